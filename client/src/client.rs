@@ -1,12 +1,22 @@
-use crate::message::Message;
-use crate::model::{ClientState, Command, InputMode, SERVER_SHUTDOWN_MESSAGE};
+use crate::config::Config;
+use crate::keymap::{Action, Keymap};
+use crate::message::{Conversation, Member, Message, Profile, Quote, Reaction, Thread};
+use crate::model::{
+    ClientState, Command, InputMode, MAX_HIDDEN_MESSAGES, PING_INTERVAL, SERVER_SHUTDOWN_MESSAGE,
+};
+use crate::notifier::{Notification, Notifiers};
+use crate::palette::{search, Entry, Palette, Target};
 use crate::request_to_json;
+use crate::theme::Theme;
+use crate::transfer::{expand_home, Download, Upload};
 use crate::ui::ui;
+use base64::{engine::general_purpose::STANDARD, Engine};
 use chrono::Local;
-use crossterm::event::{Event, EventStream, KeyCode, KeyEvent};
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyModifiers};
 use futures::{FutureExt, SinkExt};
-use serde_json::Value;
-use std::io;
+use serde_json::{json, Value};
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::UnboundedSender;
 use tokio::{net::TcpStream, sync::mpsc};
 use tokio_stream::StreamExt;
@@ -14,28 +24,97 @@ use tokio_util::codec::{Framed, LinesCodec};
 use tui::{backend::Backend, Terminal};
 
 pub(crate) struct Client {
+    pub theme: Theme,
+    pub keymap: Keymap,
+    pub notifiers: Notifiers,
     pub username: String,
     pub client_state: ClientState,
     pub input: String,
     pub input_mode: InputMode,
+    // Messages of the shown conversation
     pub messages: Vec<Message>,
+    // Number of the messages sent so far, see `Message::pending`
+    sent_messages: u64,
+    pub conversations: Vec<Conversation>,
+    // Index of the shown conversation
+    pub focused: usize,
+    // Members of the shown room are listed next to the messages
+    pub show_members: bool,
+    // Name of the room that is shown once the server confirms joining it
+    joining: Option<String>,
+    // Index of the selected message, actions apply to the last message without it
+    pub selected: Option<usize>,
     pub error_handler: Option<String>,
+    // Id of the message that is being edited in the input
+    pub editing: Option<i64>,
+    // Id and snippet of the message that is being replied to
+    pub replying_to: Option<(i64, Quote)>,
+    // Id of the message that the emoji in the input reacts to
+    pub reacting_to: Option<i64>,
+    // Thread pane, shown next to the messages when it is open
+    pub thread: Option<Thread>,
+    // Profile popup of the sender of a message
+    pub profile: Option<Profile>,
+    // Scroll offset of the full screen help, shown when it is set
+    pub help: Option<u16>,
+    pub palette: Option<Palette>,
+    pub upload: Option<Upload>,
+    pub download: Option<Download>,
+    pub latency: Option<Duration>,
+    ping: Option<(u64, Instant)>,
+    disconnect_reason: Option<String>,
+    // Set while the terminal does not have the focus
+    in_background: bool,
+    token: Option<String>,
 }
 
 impl Default for Client {
     fn default() -> Self {
         Self {
+            theme: Theme::default(),
+            keymap: Keymap::default(),
+            notifiers: Notifiers::default(),
             username: String::new(),
             client_state: ClientState::LoggingIn,
             input: String::new(),
             input_mode: InputMode::Insert,
             messages: Vec::new(),
+            sent_messages: 0,
+            conversations: Vec::new(),
+            focused: 0,
+            show_members: false,
+            joining: None,
+            selected: None,
             error_handler: None,
+            editing: None,
+            replying_to: None,
+            reacting_to: None,
+            thread: None,
+            profile: None,
+            help: None,
+            palette: None,
+            upload: None,
+            download: None,
+            latency: None,
+            ping: None,
+            disconnect_reason: None,
+            in_background: false,
+            token: None,
         }
     }
 }
 
 impl Client {
+    pub(crate) fn new(config: Config) -> Self {
+        Self {
+            theme: config.theme(),
+            keymap: config.keymap(),
+            notifiers: config.notifiers(),
+            token: config.token,
+            ..Self::default()
+        }
+    }
+
     pub(crate) async fn run_client<B: Backend>(
         mut self,
         terminal: &mut Terminal<B>,
@@ -44,36 +123,129 @@ impl Client {
         let mut event_reader = EventStream::new();
         let mut lines = Framed::new(stream, LinesCodec::new());
         let (tx, mut rx) = mpsc::unbounded_channel::<Command>();
+        let mut ping_interval = tokio::time::interval(PING_INTERVAL);
+        let mut ping_id = 0;
 
         loop {
             terminal.draw(|f| ui(f, &mut self))?;
             tokio::select! {
                 Some(command) = rx.recv() => {
                     match command {
-                        Command::SendMessage(data) => {
-                            let request = request_to_json!("SendMessage", data);
+                        Command::SendMessage(data, room_id, parent_id) => {
+                            let body = if room_id.is_none() && parent_id.is_none() {
+                                json!(data)
+                            } else {
+                                json!({ "body": data, "room_id": room_id, "parent_id": parent_id })
+                            };
+                            let request = request_to_json!("SendMessage", body);
                             self.send_request(&mut lines, &request).await.unwrap();
                         },
+                        Command::EditMessage(id, data) => {
+                            let request = request_to_json!("EditMessage", json!({ "id": id, "body": data }));
+                            self.send_request(&mut lines, &request).await.unwrap();
+                        }
+                        Command::DeleteMessage(id) => {
+                            let request = request_to_json!("DeleteMessage", json!({ "id": id }));
+                            self.send_request(&mut lines, &request).await.unwrap();
+                        }
+                        Command::GetThread(id) => {
+                            let request = request_to_json!("GetThread", json!({ "id": id }));
+                            self.send_request(&mut lines, &request).await.unwrap();
+                        }
+                        Command::AddReaction(id, emoji) => {
+                            let request = request_to_json!("AddReaction", json!({ "message_id": id, "emoji": emoji }));
+                            self.send_request(&mut lines, &request).await.unwrap();
+                        }
+                        Command::RemoveReaction(id, emoji) => {
+                            let request = request_to_json!("RemoveReaction", json!({ "message_id": id, "emoji": emoji }));
+                            self.send_request(&mut lines, &request).await.unwrap();
+                        }
+                        Command::GetProfile(username) => {
+                            let request = request_to_json!("GetProfile", username);
+                            self.send_request(&mut lines, &request).await.unwrap();
+                        }
+                        Command::DirectMessage(to, data) => {
+                            let request = request_to_json!("DirectMessage", json!({ "to": to, "body": data }));
+                            self.send_request(&mut lines, &request).await.unwrap();
+                        }
+                        Command::JoinRoom(name) => {
+                            let request = request_to_json!("JoinRoom", name);
+                            self.send_request(&mut lines, &request).await.unwrap();
+                        }
+                        Command::GetMembers(room_id) => {
+                            let request = request_to_json!("GetMembers", json!({ "room_id": room_id }));
+                            self.send_request(&mut lines, &request).await.unwrap();
+                        }
+                        Command::MarkRoomRead(room_id) => {
+                            let request = request_to_json!("MarkRead", json!({ "room_id": room_id }));
+                            self.send_request(&mut lines, &request).await.unwrap();
+                        }
+                        Command::MarkDirectRead(username) => {
+                            let request = request_to_json!("MarkRead", json!({ "username": username }));
+                            self.send_request(&mut lines, &request).await.unwrap();
+                        }
+                        Command::UploadStart(name, size, sha256) => {
+                            let request = request_to_json!("UploadStart", json!({ "name": name, "size": size, "sha256": sha256 }));
+                            self.send_request(&mut lines, &request).await.unwrap();
+                        }
+                        Command::UploadChunk(id, data) => {
+                            let request = request_to_json!("UploadChunk", json!({ "upload_id": id, "data": STANDARD.encode(data) }));
+                            self.send_request(&mut lines, &request).await.unwrap();
+                        }
+                        Command::UploadFinish(id, caption, room_id) => {
+                            let mut body = json!({ "upload_id": id, "room_id": room_id });
+                            if let Some(caption) = caption {
+                                body["body"] = json!(caption);
+                            }
+                            let request = request_to_json!("UploadFinish", body);
+                            self.send_request(&mut lines, &request).await.unwrap();
+                        }
+                        Command::Download(id, offset) => {
+                            let request = request_to_json!("Download", json!({ "attachment_id": id, "offset": offset }));
+                            self.send_request(&mut lines, &request).await.unwrap();
+                        }
                         Command::LogInUsername(username) => {
-                            let request = request_to_json!("LogInUsername", username);
+                            let login = match &self.token {
+                                Some(token) => json!({ "username": username, "token": token }),
+                                None => json!(username),
+                            };
+                            let request = request_to_json!("LogInUsername", login);
+                            self.send_request(&mut lines, &request).await.unwrap();
+                        }
+                        Command::Pong(body) => {
+                            let request = request_to_json!("Pong", body);
                             self.send_request(&mut lines, &request).await.unwrap();
                         }
                         Command::Exit => break Ok(()),
                     }
                 },
+                _ = ping_interval.tick() => {
+                    if let ClientState::LoggedIn = self.client_state {
+                        ping_id += 1;
+                        self.ping = Some((ping_id, Instant::now()));
+                        let request = request_to_json!("Ping", json!({ "id": ping_id }));
+                        self.send_request(&mut lines, &request).await.unwrap();
+                    }
+                },
                 request = lines.next() => match request {
-                    Some(Ok(received_data)) => self.handle_received_data(&received_data),
+                    Some(Ok(received_data)) => self.handle_received_data(&received_data, &tx),
                     Some(Err(e)) => {
                         self.error_handler = Some(format!("Invalid request: {e}"));
                     }
                     None => {
+                        if let Some(reason) = self.disconnect_reason.take() {
+                            break Err(io::Error::new(io::ErrorKind::ConnectionAborted, reason));
+                        }
                         self.handle_server_shutdown();
                         break Ok(())
                     }
                 },
                 result = event_reader.next().fuse() => {
-                    if let Ok(Event::Key(key)) = result.unwrap() {
-                        self.handle_input_event(key, &tx).await;
+                    match result.unwrap() {
+                        Ok(Event::Key(key)) => self.handle_input_event(key, &tx).await,
+                        Ok(Event::FocusLost) => self.in_background = true,
+                        Ok(Event::FocusGained) => self.in_background = false,
+                        _ => {}
                     }
                 },
             }
@@ -86,107 +258,1148 @@ impl Client {
             .push(Message::new(SERVER_SHUTDOWN_MESSAGE.to_string(), None, now));
     }
 
-    fn handle_received_data(&mut self, data: &str) {
-        let json_data: Value = serde_json::from_str(data).unwrap();
+    fn handle_received_data(&mut self, data: &str, tx: &UnboundedSender<Command>) {
+        let json_data: Value = match serde_json::from_str(data) {
+            Ok(json_data) => json_data,
+            Err(_) => return self.invalid_data(),
+        };
         match json_data.get("type").and_then(|v| v.as_str()) {
             Some("response") => self.handle_response(json_data),
-            Some("request_s2c") => self.handle_request(json_data),
-            _ => unreachable!(),
+            Some("request_s2c") => self.handle_request(json_data, tx),
+            _ => self.invalid_data(),
         }
     }
 
-    fn handle_request(&mut self, json_data: Value) {
+    // Data from a newer or broken server is skipped instead of ending the chat
+    fn invalid_data(&mut self) {
+        self.error_handler =
+            Some("The server sent data that this client does not understand".to_string());
+    }
+
+    fn handle_request(&mut self, json_data: Value, tx: &UnboundedSender<Command>) {
         match json_data.get("method").and_then(|v| v.as_str()) {
-            Some("SendMessage") | Some("Connection") => {
+            Some("Ping") => {
+                let body = json_data.get("body").cloned().unwrap_or_default();
+                tx.send(Command::Pong(body)).unwrap();
+            }
+            Some("Pong") => {
+                let id = json_data
+                    .get("body")
+                    .and_then(|body| body.get("id"))
+                    .and_then(|v| v.as_u64());
+                if let Some((ping_id, sent)) = self.ping {
+                    if id == Some(ping_id) {
+                        self.latency = Some(sent.elapsed());
+                        self.ping = None;
+                    }
+                }
+            }
+            Some("SendMessage") => {
                 if let ClientState::LoggedIn = self.client_state {
                     let message = Message::from_json_value(json_data);
-                    self.messages.push(message);
+                    self.add_to_thread(&message);
+                    self.receive_message(message);
+                }
+            }
+            Some("Connection") => {
+                if let ClientState::LoggedIn = self.client_state {
+                    self.receive_message(Message::from_json_value(json_data));
+                    // Someone in the member list may have come online or gone offline
+                    self.request_members(tx);
                 }
             }
-            Some("MessageRead") => unimplemented!(),
-            _ => unreachable!("Invalid data {:?}", json_data),
+            Some("DirectMessage") => {
+                if let ClientState::LoggedIn = self.client_state {
+                    self.receive_message(Message::from_direct_json_value(json_data));
+                }
+            }
+            Some("Mentioned") => {
+                if let ClientState::LoggedIn = self.client_state {
+                    // Outside of the room only the sender and the room are known
+                    match json_data["body"]["room"].as_str() {
+                        Some(room) => {
+                            let sender = json_data["body"]["sender"].as_str().unwrap_or_default();
+                            self.mentioned_outside(sender, room);
+                        }
+                        None => self.mentioned(Message::from_json_value(json_data)),
+                    }
+                }
+            }
+            Some("UnreadCounts") => self.update_unread(&json_data["body"], tx),
+            Some("Members") => {
+                let body = &json_data["body"];
+                let room_id = body["room_id"].as_i64();
+                if let Some(conversation) = self
+                    .conversations
+                    .iter_mut()
+                    .find(|conversation| !conversation.direct && conversation.room_id == room_id)
+                {
+                    conversation.members = Member::from_list(&body["members"]);
+                }
+            }
+            Some("UnreadDirectMessages") => {
+                let count = json_data["body"]["count"].as_u64().unwrap_or_default();
+                let now = Local::now().format("%d-%m-%Y %H:%M").to_string();
+                let info = format!("You have {count} unread direct messages");
+                self.show(Message::new(info, None, now));
+            }
+            Some("Thread") => {
+                self.thread = Some(Thread::from_json_value(json_data));
+            }
+            Some("MessageEdited") => {
+                let body = &json_data["body"];
+                let id = body["id"].as_i64();
+                let data = match body["data"].as_str() {
+                    Some(data) => data,
+                    None => return self.invalid_data(),
+                };
+                self.update_message(id, |message| {
+                    message.data = data.to_string();
+                    message.edited = true;
+                });
+            }
+            Some("MessageDeleted") => {
+                let id = json_data
+                    .get("body")
+                    .and_then(|body| body.get("id"))
+                    .and_then(|v| v.as_i64());
+                self.update_message(id, |message| message.deleted = true);
+            }
+            Some("Profile") => {
+                self.profile = Some(Profile::from_json_value(json_data));
+            }
+            Some("UploadStarted") => {
+                let id = json_data["body"]["upload_id"].as_i64();
+                if let Some(upload) = self.upload.as_mut() {
+                    upload.id = id;
+                }
+                self.continue_upload(tx);
+            }
+            Some("UploadProgress") => self.continue_upload(tx),
+            Some("DownloadChunk") => self.continue_download(&json_data["body"], tx),
+            Some("Reactions") => {
+                let body = &json_data["body"];
+                let id = body["message_id"].as_i64();
+                let reactions = Reaction::from_list(&body["reactions"]);
+                self.update_message(id, |message| message.reactions = reactions.clone());
+            }
+            _ => self.invalid_data(),
         }
     }
 
     fn handle_response(&mut self, json_data: Value) {
+        if let Some(reason) = json_data["message"].as_str() {
+            if self.transfer_failed(reason) {
+                return;
+            }
+        }
         match json_data.get("status_code").and_then(|v| v.as_i64()) {
             Some(200) => {
                 if let ClientState::LoggingIn = self.client_state {
                     self.client_state = ClientState::LoggedIn;
+                    // The default room, its name and id come with the unread counts
+                    self.conversations.push(Conversation::room(None, ""));
                 } else {
                     // TODO: Implement 'Delivered' icon
+                    let id = json_data
+                        .get("body")
+                        .and_then(|body| body.get("id"))
+                        .and_then(|v| v.as_i64());
+                    if let Some(message) = id.and(self.oldest_pending_message()) {
+                        message.id = id;
+                        message.pending = None;
+                        let message = message.clone();
+                        self.add_to_thread(&message);
+                    }
                 }
             }
             Some(400) => {
-                self.error_handler = json_data.get("message").map(|msg| msg.to_string());
-                // TODO: Implement new logic - push message to self.messages only if OK received
-                if let ClientState::LoggedIn = self.client_state {
-                    self.messages.pop();
+                let error = match json_data["message"].as_str() {
+                    Some("InvalidMessage") => {
+                        "Messages can't be empty, too long or have control characters"
+                    }
+                    Some("InvalidParent") => "The message you replied to is not in this room",
+                    Some("InvalidReaction") => "Reactions can only be a single emoji",
+                    Some("InvalidRoomName") => {
+                        self.joining = None;
+                        "Room names can only have lowercase letters, digits, - and _"
+                    }
+                    Some("InvalidUsername") => {
+                        "Usernames can only have letters, digits, _, . and -, choose another one"
+                    }
+                    Some("ReservedUsername") => "This username is reserved, choose another one",
+                    Some("InvalidToken") => "The token in the config must have 1 to 256 bytes",
+                    _ => "The server did not understand the request",
+                };
+                self.error_handler = Some(error.to_string());
+                // Reactions and rooms are not shown before the server accepts them
+                if !matches!(
+                    json_data["message"].as_str(),
+                    Some("InvalidReaction") | Some("InvalidRoomName")
+                ) {
+                    // TODO: Implement new logic - push message to self.messages only if OK received
+                    self.pop_pending_message();
                 }
             }
-            _ => panic!("Invalid data {:?}", json_data),
+            Some(408) => {
+                self.disconnect_reason = Some("You have not logged in in time".to_string());
+            }
+            Some(409) => {
+                self.error_handler = Some(
+                    "This username is already used by someone who is online, choose another one"
+                        .to_string(),
+                );
+            }
+            Some(429) => {
+                let retry_after = json_data
+                    .get("body")
+                    .and_then(|body| body.get("retry_after_ms"))
+                    .and_then(|v| v.as_u64())
+                    .unwrap_or_default();
+                let retry_after = retry_after as f64 / 1000.0;
+                // The server closes the connection after too many failed logins
+                if json_data["message"] == "TooManyFailedLogins" {
+                    self.disconnect_reason = Some(format!(
+                        "Too many failed logins, try again in {retry_after:.0} seconds"
+                    ));
+                    return;
+                }
+                self.error_handler = Some(format!(
+                    "You are sending messages too fast, try again in {retry_after:.1} seconds"
+                ));
+                self.pop_pending_message();
+            }
+            Some(401) => {
+                let error = match json_data["message"].as_str() {
+                    Some("TokenRequired") => {
+                        "This username is protected by a token, set it in the config"
+                    }
+                    _ => "The token in the config is not the one of this username",
+                };
+                self.error_handler = Some(error.to_string());
+            }
+            Some(403) => {
+                let error = match json_data["message"].as_str() {
+                    Some("TokenRequired") => {
+                        "Direct messages need both users to log in with a token"
+                    }
+                    _ => "You can only change your own messages",
+                };
+                self.error_handler = Some(error.to_string());
+            }
+            Some(404) => {
+                let error = match json_data["message"].as_str() {
+                    Some("UserNotFound") => "This user does not exist",
+                    Some("RoomNotFound") => {
+                        self.pop_pending_message();
+                        "You are not in this room"
+                    }
+                    _ => "This message has already been deleted",
+                };
+                self.error_handler = Some(error.to_string());
+            }
+            Some(503) => {
+                self.disconnect_reason = Some("Server is full, try again later".to_string());
+            }
+            _ => self.invalid_data(),
+        }
+    }
+
+    // Sends the next chunk, or finishes the upload after the last one
+    fn continue_upload(&mut self, tx: &UnboundedSender<Command>) {
+        let upload = match self.upload.as_mut() {
+            Some(upload) => upload,
+            None => return,
+        };
+        let id = match upload.id {
+            Some(id) => id,
+            None => return,
+        };
+        if upload.is_sent() {
+            tx.send(Command::UploadFinish(
+                id,
+                upload.caption.take(),
+                upload.room_id,
+            ))
+            .unwrap();
+            self.upload = None;
+            return;
+        }
+        match upload.next_chunk() {
+            Ok(chunk) => tx.send(Command::UploadChunk(id, chunk)).unwrap(),
+            Err(e) => {
+                self.error_handler = Some(format!("Could not upload {}: {e}", upload.name));
+                self.upload = None;
+            }
+        }
+    }
+
+    fn continue_download(&mut self, body: &Value, tx: &UnboundedSender<Command>) {
+        let download = match self.download.as_mut() {
+            Some(download) => download,
+            None => return,
+        };
+        let data = body["data"]
+            .as_str()
+            .and_then(|data| STANDARD.decode(data).ok());
+        let expected = body["attachment_id"].as_i64() == Some(download.attachment_id)
+            && body["offset"].as_u64() == Some(download.received);
+        let result = match data {
+            Some(data) if expected && (!data.is_empty() || download.size == 0) => {
+                download.write_chunk(&data)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected chunk from the server",
+            )),
+        };
+        if let Err(e) = result {
+            self.error_handler = Some(format!("Could not download {}: {e}", download.name));
+            self.download.take().unwrap().cancel();
+            return;
+        }
+        if !download.is_received() {
+            tx.send(Command::Download(download.attachment_id, download.received))
+                .unwrap();
+            return;
+        }
+
+        let download = self.download.take().unwrap();
+        let name = download.name.clone();
+        match download.finish(body["sha256"].as_str().unwrap_or_default()) {
+            Ok(path) => {
+                let now = Local::now().format("%d-%m-%Y %H:%M").to_string();
+                let info = format!("{name} has been saved to {}", path.display());
+                self.show(Message::new(info, None, now));
+            }
+            Err(e) => self.error_handler = Some(format!("Could not download {name}: {e}")),
+        }
+    }
+
+    // Errors of uploads and downloads stop them, returns `true` if the reason was one of them
+    fn transfer_failed(&mut self, reason: &str) -> bool {
+        let error = match reason {
+            "FileTooLarge" => "The file is larger than the server allows",
+            "TooManyUploads" => "Wait until your other uploads finish",
+            "StorageFull" => "The server has no space left for files",
+            "ChecksumMismatch" => "The file was damaged during the upload, try again",
+            "ChunkTooLarge" | "InvalidChunk" | "InvalidFile" | "UploadNotFound"
+            | "UploadIncomplete" | "UploadFailed" => "The server could not receive the file",
+            "AttachmentNotFound" => {
+                if let Some(download) = self.download.take() {
+                    download.cancel();
+                }
+                self.error_handler = Some("This attachment is not available".to_string());
+                return true;
+            }
+            _ => return false,
+        };
+        self.upload = None;
+        self.error_handler = Some(error.to_string());
+        true
+    }
+
+    // Updates every copy of the message, including the one in the thread pane
+    fn update_message(&mut self, id: Option<i64>, update: impl Fn(&mut Message)) {
+        let thread = self.thread.iter_mut().flat_map(|thread| {
+            std::iter::once(&mut thread.parent).chain(thread.replies.iter_mut())
+        });
+        let hidden = self
+            .conversations
+            .iter_mut()
+            .flat_map(|conversation| conversation.messages.iter_mut());
+        self.messages
+            .iter_mut()
+            .chain(thread)
+            .chain(hidden)
+            .filter(|message| message.id.is_some() && message.id == id)
+            .for_each(update);
+    }
+
+    // Messages of the other conversations wait in their own lists until they are shown
+    fn receive_message(&mut self, message: Message) {
+        let index = match self.conversation_of(&message) {
+            Some(index) => index,
+            None => return,
+        };
+        // Direct messages also notify while the terminal is in the background,
+        // mentions in rooms notify through their own event
+        let direct = message.recipient.is_some();
+        let from_other =
+            message.sender.is_some() && message.sender.as_ref() != Some(&self.username);
+        if from_other
+            && (index != self.focused || (direct && self.in_background))
+            && (direct || !message.mentions(&self.username))
+        {
+            let event = if direct {
+                Notification::DirectMessage
+            } else {
+                Notification::Message
+            };
+            let label = self.conversations[index].label();
+            self.notify(event, &label, &message);
+        }
+        if index == self.focused {
+            self.show(message);
+            return;
+        }
+        let own = message.sender.as_ref() == Some(&self.username);
+        let conversation = &mut self.conversations[index];
+        conversation.messages.push(message);
+        if conversation.messages.len() > MAX_HIDDEN_MESSAGES {
+            conversation.messages.remove(0);
+        }
+        if !own {
+            conversation.unread += 1;
+        }
+    }
+
+    // Mentions in other rooms are shown as a notice, even in rooms the user has not joined
+    fn mentioned(&mut self, message: Message) {
+        let room = self
+            .conversations
+            .iter()
+            .find(|conversation| {
+                conversation.room_id.is_some() && conversation.room_id == message.room_id
+            })
+            .map(|conversation| conversation.label())
+            .unwrap_or_else(|| "another room".to_string());
+        self.notify(Notification::Mention, &room, &message);
+        if message.room_id.is_some() && message.room_id == self.focused_room_id() {
+            return;
+        }
+        let notice = format!(
+            "{} mentioned you in {room}: {}",
+            message.sender.unwrap_or_default(),
+            message.data
+        );
+        self.show(Message::new(notice, None, message.date));
+    }
+
+    fn mentioned_outside(&mut self, sender: &str, room: &str) {
+        let room = format!("#{room}");
+        let now = Local::now().format("%d-%m-%Y %H:%M").to_string();
+        let message = Message::new(String::new(), Some(sender.to_string()), now);
+        self.notify(Notification::Mention, &room, &message);
+        let notice = format!("{sender} mentioned you in {room}, join it to read the message");
+        self.show(Message::new(notice, None, message.date));
+    }
+
+    // A notifier that fails is reported like other errors
+    fn notify(&mut self, event: Notification, conversation: &str, message: &Message) {
+        let sender = message.sender.as_deref().unwrap_or_default();
+        let title = match event {
+            Notification::DirectMessage => sender.to_string(),
+            Notification::Mention | Notification::Message => format!("{sender} in {conversation}"),
+        };
+        if let Err(e) = self
+            .notifiers
+            .notify(event, conversation, &title, &message.data)
+        {
+            self.error_handler = Some(format!("Could not send a notification: {e}"));
+        }
+    }
+
+    // Index of the conversation of the message, `None` for rooms the user is not in.
+    // Messages without a room, e.g. connections, belong to the shown conversation
+    fn conversation_of(&mut self, message: &Message) -> Option<usize> {
+        if let Some(recipient) = &message.recipient {
+            let peer = if message.sender.as_ref() == Some(&self.username) {
+                recipient.clone()
+            } else {
+                message.sender.clone().unwrap_or_default()
+            };
+            return Some(self.direct_conversation(&peer));
+        }
+        match message.room_id {
+            Some(room_id) => self
+                .conversations
+                .iter()
+                .position(|conversation| conversation.room_id == Some(room_id)),
+            None => Some(self.focused),
+        }
+    }
+
+    // The conversation is added the first time the user talks to someone
+    fn direct_conversation(&mut self, username: &str) -> usize {
+        let index = self
+            .conversations
+            .iter()
+            .position(|conversation| conversation.direct && conversation.name == username);
+        index.unwrap_or_else(|| {
+            self.conversations.push(Conversation::direct(username));
+            self.conversations.len() - 1
+        })
+    }
+
+    // The server sends the counts after logging in, joining a room and marking a
+    // conversation as read. The shown conversation never has unread messages
+    fn update_unread(&mut self, body: &Value, tx: &UnboundedSender<Command>) {
+        for room in body["rooms"].as_array().into_iter().flatten() {
+            let id = room["id"].as_i64();
+            let name = room["name"].as_str().unwrap_or_default();
+            // The first room is the default one
+            let index = self.conversations.iter().position(|conversation| {
+                !conversation.direct
+                    && (conversation.room_id == id || conversation.room_id.is_none())
+            });
+            let index = index.unwrap_or_else(|| {
+                self.conversations.push(Conversation::room(id, name));
+                self.conversations.len() - 1
+            });
+            let conversation = &mut self.conversations[index];
+            conversation.room_id = id;
+            conversation.name = name.to_string();
+            conversation.unread = room["unread"].as_u64().unwrap_or_default();
+        }
+        for conversation in self.conversations.iter_mut().filter(|c| c.direct) {
+            conversation.unread = 0;
+        }
+        for direct in body["direct"].as_array().into_iter().flatten() {
+            let index = self.direct_conversation(direct["username"].as_str().unwrap_or_default());
+            self.conversations[index].unread = direct["unread"].as_u64().unwrap_or_default();
+        }
+        if let Some(conversation) = self.conversations.get_mut(self.focused) {
+            conversation.unread = 0;
+        }
+
+        if let Some(name) = self.joining.take() {
+            let index = self
+                .conversations
+                .iter()
+                .position(|conversation| !conversation.direct && conversation.name == name);
+            if let Some(index) = index {
+                self.focus(index, tx);
+            }
+        }
+    }
+
+    // Shows another conversation, the one that is left and the shown one are marked as read.
+    // Unsent input stays with its conversation, unfinished edits and reactions are dropped
+    fn focus(&mut self, index: usize, tx: &UnboundedSender<Command>) {
+        if index == self.focused || index >= self.conversations.len() {
+            return;
+        }
+        self.mark_read(self.focused, tx);
+        if self.editing.take().is_some() || self.reacting_to.take().is_some() {
+            self.input.clear();
+        }
+        let left = &mut self.conversations[self.focused];
+        left.messages = std::mem::take(&mut self.messages);
+        left.draft = std::mem::take(&mut self.input);
+        let shown = &mut self.conversations[index];
+        self.messages = std::mem::take(&mut shown.messages);
+        self.input = std::mem::take(&mut shown.draft);
+        shown.unread = 0;
+        self.focused = index;
+        self.selected = None;
+        self.replying_to = None;
+        self.thread = None;
+        self.mark_read(index, tx);
+        self.request_members(tx);
+    }
+
+    // Alt+1..9 shows the conversation with that number in the list
+    fn focus_numbered(&mut self, key: KeyEvent, tx: &UnboundedSender<Command>) -> bool {
+        if !matches!(self.client_state, ClientState::LoggedIn) {
+            return false;
+        }
+        match (key.modifiers, key.code) {
+            (KeyModifiers::ALT, KeyCode::Char(c @ '1'..='9')) => {
+                self.focus(c as usize - '1' as usize, tx);
+                true
+            }
+            _ => false,
         }
     }
 
+    // The list wraps around at both ends
+    fn focus_next(&mut self, forward: bool, tx: &UnboundedSender<Command>) {
+        let count = self.conversations.len();
+        if count == 0 {
+            return;
+        }
+        let index = if forward {
+            (self.focused + 1) % count
+        } else {
+            (self.focused + count - 1) % count
+        };
+        self.focus(index, tx);
+    }
+
+    fn request_members(&self, tx: &UnboundedSender<Command>) {
+        if let (true, Some(room_id)) = (self.show_members, self.focused_room_id()) {
+            tx.send(Command::GetMembers(room_id)).unwrap();
+        }
+    }
+
+    fn mark_read(&self, index: usize, tx: &UnboundedSender<Command>) {
+        let command = match self.conversations.get(index) {
+            Some(conversation) if conversation.direct => {
+                Command::MarkDirectRead(conversation.name.clone())
+            }
+            Some(Conversation {
+                room_id: Some(room_id),
+                ..
+            }) => Command::MarkRoomRead(*room_id),
+            _ => return,
+        };
+        tx.send(command).unwrap();
+    }
+
+    fn focused_room_id(&self) -> Option<i64> {
+        self.conversations
+            .get(self.focused)
+            .and_then(|conversation| conversation.room_id)
+    }
+
+    // The other user when direct messages are shown
+    fn focused_peer(&self) -> Option<String> {
+        self.conversations
+            .get(self.focused)
+            .filter(|conversation| conversation.direct)
+            .map(|conversation| conversation.name.clone())
+    }
+
+    fn add_to_thread(&mut self, message: &Message) {
+        if let Some(thread) = self.thread.as_mut() {
+            if message.parent_id.is_some() && message.parent_id == thread.parent.id {
+                // Every reply in the pane quotes the same parent
+                thread.replies.push(Message {
+                    parent: None,
+                    ..message.clone()
+                });
+            }
+        }
+    }
+
+    // The shown conversation keeps as many messages as the hidden ones
+    fn show(&mut self, message: Message) {
+        self.messages.push(message);
+        if self.messages.len() > MAX_HIDDEN_MESSAGES {
+            self.messages.remove(0);
+            self.selected = self.selected.and_then(|index| index.checked_sub(1));
+        }
+    }
+
+    // Removes the last sent message if the server has not stored it
+    // The server answers the messages in the order they were sent, so an answer is
+    // for the oldest pending one, even after the user has switched the conversation
+    fn oldest_pending_message(&mut self) -> Option<&mut Message> {
+        let hidden = self
+            .conversations
+            .iter_mut()
+            .flat_map(|conversation| conversation.messages.iter_mut());
+        self.messages
+            .iter_mut()
+            .chain(hidden)
+            .filter(|message| message.pending.is_some())
+            .min_by_key(|message| message.pending)
+    }
+
+    fn pop_pending_message(&mut self) {
+        let pending = match self.oldest_pending_message() {
+            Some(message) => message.pending,
+            None => return,
+        };
+        if let Some(index) = self.messages.iter().position(|m| m.pending == pending) {
+            self.messages.remove(index);
+            self.selected = self
+                .selected
+                .map(|selected| selected - usize::from(selected > index))
+                .filter(|selected| *selected < self.messages.len());
+        }
+        for conversation in self.conversations.iter_mut() {
+            conversation
+                .messages
+                .retain(|message| message.pending != pending);
+        }
+    }
+
+    // The selected message, or the last one when nothing is selected
+    fn target_message(&self) -> Option<&Message> {
+        match self.selected {
+            Some(index) => self
+                .messages
+                .get(index)
+                .filter(|message| message.id.is_some() && !message.deleted),
+            None => self.last_message(),
+        }
+    }
+
+    // Moderators can change the selected messages of others too
+    fn changeable_message(&self) -> Option<&Message> {
+        match self.selected {
+            Some(_) => self.target_message(),
+            None => self.last_own_message(),
+        }
+    }
+
+    fn last_own_message(&self) -> Option<&Message> {
+        self.messages.iter().rev().find(|message| {
+            message.id.is_some()
+                && !message.deleted
+                && message.sender.as_ref() == Some(&self.username)
+        })
+    }
+
+    fn last_message(&self) -> Option<&Message> {
+        self.messages
+            .iter()
+            .rev()
+            .find(|message| message.id.is_some() && !message.deleted)
+    }
+
     async fn handle_input_event(&mut self, key: KeyEvent, tx: &UnboundedSender<Command>) {
         if self.error_handler.is_none() {
-            match self.input_mode {
-                InputMode::Normal => self.handle_normal_mode(key, tx).await,
-                InputMode::Insert => self.handle_insert_mode(key, tx).await,
+            if self.palette.is_some() {
+                self.handle_palette_key(key, tx);
+                return;
             }
-        } else if let KeyCode::Char('q') = key.code {
+            if self.help.is_some() {
+                self.handle_help_key(key);
+                return;
+            }
+            if self.focus_numbered(key, tx) {
+                return;
+            }
+            match self.keymap.action(key, self.input_mode) {
+                Some(action) => self.run_action(action, tx),
+                // Other keys are typed into the input, Ctrl and Alt only work through the keymap
+                None => {
+                    if let (InputMode::Insert, KeyCode::Char(c)) = (self.input_mode, key.code) {
+                        if !key
+                            .modifiers
+                            .intersects(KeyModifiers::CONTROL | KeyModifiers::ALT)
+                        {
+                            self.input.push(c);
+                        }
+                    }
+                }
+            }
+        } else if key.code == KeyCode::Char('q')
+            || self.keymap.action(key, InputMode::Normal) == Some(Action::Cancel)
+        {
             self.error_handler = None;
             self.input.clear();
         }
     }
 
-    async fn handle_normal_mode(&mut self, key: KeyEvent, tx: &UnboundedSender<Command>) {
-        match key.code {
-            KeyCode::Char('i') => {
-                self.input_mode = InputMode::Insert;
+    // The help is scrolled with the keys that move the selection and closed with its own key
+    fn handle_help_key(&mut self, key: KeyEvent) {
+        let scroll = self.help.unwrap_or_default();
+        self.help = match (self.keymap.action(key, self.input_mode), key.code) {
+            (Some(Action::SelectNext), _) | (_, KeyCode::Down) => Some(scroll.saturating_add(1)),
+            (Some(Action::SelectPrevious), _) | (_, KeyCode::Up) => Some(scroll.saturating_sub(1)),
+            (Some(Action::Help | Action::Cancel | Action::NormalMode | Action::Quit), _)
+            | (_, KeyCode::Esc | KeyCode::Char('q')) => None,
+            _ => Some(scroll),
+        };
+    }
+
+    // Every key is typed into the query, except the ones that pick an entry
+    fn handle_palette_key(&mut self, key: KeyEvent, tx: &UnboundedSender<Command>) {
+        let count = self.palette_entries().len();
+        let palette = match self.palette.as_mut() {
+            Some(palette) => palette,
+            None => return,
+        };
+        match (key.modifiers, key.code) {
+            (_, KeyCode::Esc) => self.palette = None,
+            (_, KeyCode::Enter) => {
+                let selected = palette.selected;
+                let entry = self.palette_entries().into_iter().nth(selected);
+                self.palette = None;
+                if let Some(entry) = entry {
+                    self.run_palette_entry(entry.target, tx);
+                }
             }
-            KeyCode::Char('q') => {
-                tx.send(Command::Exit).unwrap();
+            (_, KeyCode::Up) | (KeyModifiers::CONTROL, KeyCode::Char('p')) => {
+                palette.selected = palette.selected.saturating_sub(1);
+            }
+            (_, KeyCode::Down) | (KeyModifiers::CONTROL, KeyCode::Char('n')) => {
+                palette.selected = (palette.selected + 1).min(count.saturating_sub(1));
+            }
+            (_, KeyCode::Backspace) => {
+                palette.query.pop();
+                palette.selected = 0;
+            }
+            (modifiers, KeyCode::Char(c))
+                if !modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) =>
+            {
+                palette.query.push(c);
+                palette.selected = 0;
             }
             _ => {}
         }
     }
 
-    async fn handle_insert_mode(&mut self, key: KeyEvent, tx: &UnboundedSender<Command>) {
-        match key.code {
-            KeyCode::Enter => {
-                let command = if let ClientState::LoggedIn = self.client_state {
-                    Command::SendMessage(self.input.clone())
+    // Actions, conversations and online users that match the query of the palette
+    pub(crate) fn palette_entries(&self) -> Vec<Entry> {
+        let query = match &self.palette {
+            Some(palette) => palette.query.as_str(),
+            None => return Vec::new(),
+        };
+        // These only make sense as keys
+        let actions = Action::ALL
+            .into_iter()
+            .filter(|action| {
+                !matches!(
+                    action,
+                    Action::NormalMode | Action::Send | Action::DeleteBackward | Action::Palette
+                )
+            })
+            .map(|action| Entry {
+                label: action.description().to_string(),
+                detail: self
+                    .keymap
+                    .keys(action)
+                    .first()
+                    .cloned()
+                    .unwrap_or_default(),
+                target: Target::Action(action),
+            });
+        let conversations = self
+            .conversations
+            .iter()
+            .enumerate()
+            .map(|(index, conversation)| Entry {
+                label: conversation.label(),
+                detail: if conversation.direct {
+                    "direct messages"
                 } else {
-                    Command::LogInUsername(self.input.clone())
-                };
-                tx.send(command).unwrap();
+                    "room"
+                }
+                .to_string(),
+                target: Target::Conversation(index),
+            });
+        // Everyone is in the default room, so its members are all the users
+        let users =
+            self.conversations
+                .first()
+                .into_iter()
+                .flat_map(|conversation| conversation.members.iter())
+                .filter(|member| member.online && member.username != self.username)
+                .filter(|member| {
+                    !self.conversations.iter().any(|conversation| {
+                        conversation.direct && conversation.name == member.username
+                    })
+                })
+                .map(|member| Entry {
+                    label: format!("@{}", member.username),
+                    detail: "online".to_string(),
+                    target: Target::User(member.username.clone()),
+                });
+        search(query, actions.chain(conversations).chain(users).collect())
+    }
 
-                if let ClientState::LoggedIn = self.client_state {
+    fn run_palette_entry(&mut self, target: Target, tx: &UnboundedSender<Command>) {
+        match target {
+            Target::Action(action) => self.run_action(action, tx),
+            Target::Conversation(index) => self.focus(index, tx),
+            Target::User(username) => {
+                let index = self.direct_conversation(&username);
+                self.focus(index, tx);
+            }
+        }
+    }
+
+    fn run_action(&mut self, action: Action, tx: &UnboundedSender<Command>) {
+        match action {
+            Action::Cancel | Action::Profile if self.profile.is_some() => {
+                self.profile = None;
+            }
+            Action::SelectNext => {
+                if let Some(index) = self.selected {
+                    self.selected = Some((index + 1).min(self.messages.len().saturating_sub(1)));
+                }
+            }
+            Action::SelectPrevious => {
+                self.selected = match self.selected {
+                    Some(index) => Some(index.saturating_sub(1)),
+                    None => self.messages.len().checked_sub(1),
+                };
+            }
+            Action::Cancel => {
+                self.selected = None;
+            }
+            Action::Copy => {
+                if let Some(message) = self.target_message() {
+                    if let Err(e) = copy_to_clipboard(&message.data) {
+                        self.error_handler = Some(format!("Could not copy the message: {e}"));
+                    }
+                }
+            }
+            Action::Profile => {
+                if let Some(sender) = self.target_message().and_then(|m| m.sender.clone()) {
+                    tx.send(Command::GetProfile(sender)).unwrap();
+                }
+            }
+            Action::InsertMode => {
+                self.input_mode = InputMode::Insert;
+            }
+            Action::NormalMode => {
+                if self.editing.take().is_some() || self.reacting_to.take().is_some() {
+                    self.input.clear();
+                }
+                self.replying_to = None;
+                self.input_mode = InputMode::Normal;
+            }
+            Action::Members => {
+                self.show_members = !self.show_members;
+                self.request_members(tx);
+            }
+            Action::Mute => {
+                if let Some(label) = self.conversations.get(self.focused).map(|c| c.label()) {
+                    let state = if self.notifiers.toggle_mute(&label) {
+                        "off"
+                    } else {
+                        "on"
+                    };
                     let now = Local::now().format("%d-%m-%Y %H:%M").to_string();
-                    self.messages.push(Message::new(
-                        self.input.clone(),
-                        Some(self.username.clone()),
-                        now,
-                    ));
-                } else {
-                    self.username = self.input.clone();
+                    let notice = format!("Notifications of {label} are {state}");
+                    self.show(Message::new(notice, None, now));
+                }
+            }
+            Action::Quit => {
+                self.mark_read(self.focused, tx);
+                tx.send(Command::Exit).unwrap();
+            }
+            Action::Edit => {
+                if let Some(message) = self.changeable_message().cloned() {
+                    self.editing = message.id;
+                    self.input = message.data.clone();
+                    self.input_mode = InputMode::Insert;
                 }
-                self.input.clear()
             }
-            KeyCode::Char(c) => {
-                self.input.push(c);
+            Action::Delete => {
+                if let Some(id) = self.changeable_message().and_then(|message| message.id) {
+                    tx.send(Command::DeleteMessage(id)).unwrap();
+                }
             }
-            KeyCode::Backspace => {
+            Action::Reply => {
+                if let Some(message) = self.target_message() {
+                    let quote = Quote {
+                        sender: message.sender.clone().unwrap_or_default(),
+                        data: message.data.clone(),
+                    };
+                    self.replying_to = message.id.map(|id| (id, quote));
+                    self.input_mode = InputMode::Insert;
+                }
+            }
+            Action::React => {
+                if let Some(id) = self.target_message().and_then(|message| message.id) {
+                    self.reacting_to = Some(id);
+                    self.input_mode = InputMode::Insert;
+                }
+            }
+            Action::Thread if self.thread.is_some() => {
+                self.thread = None;
+            }
+            Action::Thread => {
+                // Replies open the thread of the message they reply to
+                let root = self
+                    .target_message()
+                    .and_then(|message| message.parent_id.or(message.id));
+                if let Some(id) = root {
+                    tx.send(Command::GetThread(id)).unwrap();
+                }
+            }
+            Action::NextConversation => self.focus_next(true, tx),
+            Action::PreviousConversation => self.focus_next(false, tx),
+            Action::Send => self.send_input(tx),
+            Action::DeleteBackward => {
                 self.input.pop();
             }
-            KeyCode::Esc => {
-                self.input_mode = InputMode::Normal;
+            Action::Help => {
+                self.help = Some(0);
             }
-            _ => {}
+            Action::Palette => {
+                if let ClientState::LoggedIn = self.client_state {
+                    self.palette = Some(Palette::default());
+                    if let Some(room_id) = self.conversations.first().and_then(|c| c.room_id) {
+                        tx.send(Command::GetMembers(room_id)).unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    fn send_input(&mut self, tx: &UnboundedSender<Command>) {
+        if let Some(id) = self.editing.take() {
+            tx.send(Command::EditMessage(id, self.input.clone()))
+                .unwrap();
+            self.input.clear();
+            return;
+        }
+        if let Some(id) = self.reacting_to.take() {
+            self.react(id, tx);
+            self.input.clear();
+            return;
+        }
+        if let ClientState::LoggedIn = self.client_state {
+            let input = self.input.clone();
+            if let Some(command) = input.strip_prefix('/') {
+                self.run_command(command, tx);
+                self.input.clear();
+                return;
+            }
+        }
+        // Direct messages are shown once the server has stored them
+        if let Some(peer) = self.focused_peer() {
+            tx.send(Command::DirectMessage(peer, self.input.clone()))
+                .unwrap();
+            self.input.clear();
+            return;
+        }
+        let replying_to = self.replying_to.take();
+        let command = if let ClientState::LoggedIn = self.client_state {
+            Command::SendMessage(
+                self.input.clone(),
+                self.focused_room_id(),
+                replying_to.as_ref().map(|(id, _)| *id),
+            )
+        } else {
+            Command::LogInUsername(self.input.clone())
+        };
+        tx.send(command).unwrap();
+
+        if let ClientState::LoggedIn = self.client_state {
+            let now = Local::now().format("%d-%m-%Y %H:%M").to_string();
+            let (parent_id, parent) = replying_to.unzip();
+            self.sent_messages += 1;
+            self.show(Message {
+                pending: Some(self.sent_messages),
+                room_id: self.focused_room_id(),
+                parent_id,
+                parent,
+                ..Message::new(self.input.clone(), Some(self.username.clone()), now)
+            });
+        } else {
+            self.username = self.input.clone();
+        }
+        self.input.clear()
+    }
+
+    // Commands typed into the input, e.g. `/upload ~/cat.png Look at this`
+    fn run_command(&mut self, command: &str, tx: &UnboundedSender<Command>) {
+        let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+        match name {
+            "upload" => self.start_upload(args.trim(), tx),
+            "download" => self.start_download(args.trim(), tx),
+            "msg" => self.send_direct_message(args.trim(), tx),
+            "join" => self.join_room(args.trim(), tx),
+            _ => self.error_handler = Some(format!("Unknown command /{name}")),
         }
     }
 
+    // `/upload <path> [caption]`
+    fn start_upload(&mut self, args: &str, tx: &UnboundedSender<Command>) {
+        let (path, caption) = match args.split_once(' ') {
+            Some((path, caption)) => (path, Some(caption.trim().to_string())),
+            None => (args, None),
+        };
+        if path.is_empty() {
+            self.error_handler = Some("Usage: /upload <path> [caption]".to_string());
+        } else if self.upload.is_some() {
+            self.error_handler = Some("Wait until the current upload finishes".to_string());
+        } else if self.focused_peer().is_some() {
+            self.error_handler = Some("Files can only be sent to rooms".to_string());
+        } else {
+            match Upload::open(&expand_home(path), caption) {
+                Ok(mut upload) => {
+                    upload.room_id = self.focused_room_id();
+                    let command = Command::UploadStart(
+                        upload.name.clone(),
+                        upload.size,
+                        upload.sha256.clone(),
+                    );
+                    tx.send(command).unwrap();
+                    self.upload = Some(upload);
+                }
+                Err(e) => self.error_handler = Some(format!("Could not open {path}: {e}")),
+            }
+        }
+    }
+
+    // `/msg <user> <text>`, the message is shown once the server has stored it.
+    // Without a text it shows the direct messages with the user
+    fn send_direct_message(&mut self, args: &str, tx: &UnboundedSender<Command>) {
+        match args.split_once(' ') {
+            Some((to, data)) if !data.trim().is_empty() => {
+                let to = to.trim_start_matches('@').to_string();
+                tx.send(Command::DirectMessage(to, data.trim().to_string()))
+                    .unwrap();
+            }
+            None if !args.is_empty() => {
+                let index = self.direct_conversation(args.trim_start_matches('@'));
+                self.focus(index, tx);
+            }
+            _ => self.error_handler = Some("Usage: /msg <user> [text]".to_string()),
+        }
+    }
+
+    // `/join <room>` shows the room once the server has added the user to it,
+    // the room is created if it does not exist yet
+    fn join_room(&mut self, name: &str, tx: &UnboundedSender<Command>) {
+        let name = name.trim_start_matches('#');
+        if name.is_empty() {
+            self.error_handler = Some("Usage: /join <room>".to_string());
+            return;
+        }
+        self.joining = Some(name.to_string());
+        tx.send(Command::JoinRoom(name.to_string())).unwrap();
+    }
+
+    // `/download [directory]` saves the attachment of the selected message, or of the
+    // last message with one, into the current directory by default
+    fn start_download(&mut self, dir: &str, tx: &UnboundedSender<Command>) {
+        let message = match self.selected {
+            Some(_) => self.target_message(),
+            None => self
+                .messages
+                .iter()
+                .rev()
+                .find(|message| message.attachment.is_some() && !message.deleted),
+        };
+        let attachment = match message.and_then(|message| message.attachment.clone()) {
+            Some(attachment) => attachment,
+            None => {
+                self.error_handler = Some("There is no attachment to download".to_string());
+                return;
+            }
+        };
+        if self.download.is_some() {
+            self.error_handler = Some("Wait until the current download finishes".to_string());
+            return;
+        }
+        let dir = expand_home(if dir.is_empty() { "." } else { dir });
+        match Download::create(attachment.id, &attachment.name, attachment.size, dir) {
+            Ok(download) => {
+                tx.send(Command::Download(attachment.id, 0)).unwrap();
+                self.download = Some(download);
+            }
+            Err(e) => self.error_handler = Some(format!("Could not save {}: {e}", attachment.name)),
+        }
+    }
+
+    // Reacting again with the same emoji takes the reaction back
+    fn react(&self, id: i64, tx: &UnboundedSender<Command>) {
+        let emoji = self.input.trim().to_string();
+        let reacted = self
+            .messages
+            .iter()
+            .find(|message| message.id == Some(id))
+            .and_then(|message| message.reactions.iter().find(|r| r.emoji == emoji))
+            .is_some_and(|reaction| reaction.users.contains(&self.username));
+        let command = if reacted {
+            Command::RemoveReaction(id, emoji)
+        } else {
+            Command::AddReaction(id, emoji)
+        };
+        tx.send(command).unwrap();
+    }
+
     async fn send_request(
         &self,
         lines: &mut Framed<TcpStream, LinesCodec>,
@@ -196,3 +1409,89 @@ impl Client {
         Ok(())
     }
 }
+
+// OSC 52 asks the terminal to put the text into the system clipboard,
+// so copying works over SSH as well
+fn copy_to_clipboard(text: &str) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    write!(stdout, "\x1b]52;c;{}\x07", STANDARD.encode(text))?;
+    stdout.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sent(client: &mut Client, room_id: i64, data: &str) -> Message {
+        client.sent_messages += 1;
+        Message {
+            pending: Some(client.sent_messages),
+            room_id: Some(room_id),
+            ..Message::new(
+                data.to_string(),
+                Some(client.username.clone()),
+                String::new(),
+            )
+        }
+    }
+
+    #[test]
+    fn answers_belong_to_the_oldest_pending_message() {
+        let mut client = Client {
+            username: "alice".to_string(),
+            client_state: ClientState::LoggedIn,
+            ..Client::default()
+        };
+        client.conversations = vec![
+            Conversation::room(Some(1), "general"),
+            Conversation::room(Some(2), "random"),
+        ];
+        // Sent in general, then the user switched to random and sent two more
+        let message = sent(&mut client, 1, "hello");
+        client.conversations[0].messages.push(message);
+        client.focused = 1;
+        for data in ["anyone?", "hello?"] {
+            let message = sent(&mut client, 2, data);
+            client.messages.push(message);
+        }
+
+        client.handle_response(json!({ "status_code": 200, "body": { "id": 7 } }));
+        assert_eq!(client.conversations[0].messages[0].id, Some(7));
+        assert!(client.messages.iter().all(|message| message.id.is_none()));
+
+        client.handle_response(json!({ "status_code": 404, "message": "RoomNotFound" }));
+        let shown: Vec<&str> = client.messages.iter().map(|m| m.data.as_str()).collect();
+        assert_eq!(shown, ["hello?"]);
+        client.handle_response(json!({ "status_code": 200, "body": { "id": 8 } }));
+        assert_eq!(client.messages[0].id, Some(8));
+    }
+
+    #[test]
+    fn unknown_data_shows_an_error_instead_of_panicking() {
+        let mut client = Client {
+            client_state: ClientState::LoggedIn,
+            ..Client::default()
+        };
+        client.conversations = vec![Conversation::room(Some(1), "general")];
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let unknown = [
+            "not json",
+            r#"{ "type": "gossip" }"#,
+            r#"{ "type": "request_s2c", "method": "Teleport" }"#,
+            r#"{ "type": "request_s2c", "method": "MessageEdited", "body": { "id": 1 } }"#,
+            r#"{ "type": "response", "status_code": 418 }"#,
+        ];
+        for data in unknown {
+            client.error_handler = None;
+            client.handle_received_data(data, &tx);
+            assert!(client.error_handler.is_some(), "{data}");
+        }
+
+        // Missing fields of a message are left empty
+        client.error_handler = None;
+        let data = r#"{ "type": "request_s2c", "method": "SendMessage", "body": { "room_id": 1, "date": "yesterday" } }"#;
+        client.handle_received_data(data, &tx);
+        assert!(client.error_handler.is_none());
+        assert_eq!(client.messages[0].date, "yesterday");
+    }
+}
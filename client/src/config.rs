@@ -0,0 +1,287 @@
+use crate::keymap::{Action, Key, Keymap, KeymapName};
+use crate::notifier::{NotifierName, Notifiers};
+use crate::theme::{Theme, ThemeName};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tui::style::Color;
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub theme: ThemeName,
+    // Colors that replace the ones of the theme
+    pub colors: Colors,
+    pub keymap: KeymapName,
+    // Keys that replace the ones of the keymap for these actions
+    pub keys: HashMap<Action, Vec<Key>>,
+    pub notifications: Notifications,
+    // Sent with the username, the first log in with a token claims the name on the
+    // server and nobody without the token can log in as that user afterwards
+    pub token: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Notifications {
+    // Notifiers of every event, the bell by default
+    pub direct_message: Vec<NotifierName>,
+    pub mention: Vec<NotifierName>,
+    pub message: Vec<NotifierName>,
+    // Program and arguments of the command notifier
+    pub command: Vec<String>,
+    // Conversations without notifications, like "#random" or "@bob"
+    pub muted: Vec<String>,
+}
+
+impl Default for Notifications {
+    fn default() -> Self {
+        Self {
+            direct_message: vec![NotifierName::Bell],
+            mention: vec![NotifierName::Bell],
+            message: vec![NotifierName::Bell],
+            command: Vec::new(),
+            muted: Vec::new(),
+        }
+    }
+}
+
+impl Notifications {
+    fn validate(&self) -> Result<(), String> {
+        let events = [&self.direct_message, &self.mention, &self.message];
+        if self.command.is_empty()
+            && events
+                .iter()
+                .any(|names| names.contains(&NotifierName::Command))
+        {
+            return Err(
+                "The command notifier needs a program in notifications.command".to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct Colors {
+    #[serde(with = "optional_color")]
+    pub text: Option<Color>,
+    #[serde(with = "optional_color")]
+    pub muted: Option<Color>,
+    #[serde(with = "optional_color")]
+    pub highlight: Option<Color>,
+    #[serde(with = "optional_color")]
+    pub mention: Option<Color>,
+    #[serde(with = "optional_color")]
+    pub attachment: Option<Color>,
+    #[serde(with = "optional_color")]
+    pub accent: Option<Color>,
+    #[serde(with = "optional_color")]
+    pub error: Option<Color>,
+    #[serde(with = "optional_color")]
+    pub selection: Option<Color>,
+    #[serde(with = "color_list")]
+    pub senders: Option<Vec<Color>>,
+}
+
+impl Config {
+    /// Reads `$XDG_CONFIG_HOME/socket-chat/config.toml` (or the file set by
+    /// `CLIENT_CONFIG`), missing fields and a missing file fall back to the defaults.
+    pub fn load() -> Result<Self, String> {
+        let path = match std::env::var_os("CLIENT_CONFIG") {
+            Some(path) => PathBuf::from(path),
+            None => match config_dir() {
+                Some(dir) => dir.join("socket-chat").join("config.toml"),
+                None => return Ok(Self::default()),
+            },
+        };
+        let config: Self = match std::fs::read_to_string(&path) {
+            Ok(content) => toml::from_str(&content)
+                .map_err(|e| format!("Could not parse the config file {}: {e}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => {
+                return Err(format!(
+                    "Could not read the config file {}: {e}",
+                    path.display()
+                ))
+            }
+        };
+        config.notifications.validate()?;
+        Ok(config)
+    }
+
+    // `NO_COLOR` (https://no-color.org) turns the colors off, whatever the config says
+    pub fn theme(&self) -> Theme {
+        if std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty()) {
+            return Theme::builtin(ThemeName::NoColor);
+        }
+        let theme = Theme::builtin(self.theme);
+        let colors = &self.colors;
+        Theme {
+            text: colors.text.unwrap_or(theme.text),
+            muted: colors.muted.unwrap_or(theme.muted),
+            highlight: colors.highlight.unwrap_or(theme.highlight),
+            mention: colors.mention.unwrap_or(theme.mention),
+            attachment: colors.attachment.unwrap_or(theme.attachment),
+            accent: colors.accent.unwrap_or(theme.accent),
+            error: colors.error.unwrap_or(theme.error),
+            selection: colors.selection.unwrap_or(theme.selection),
+            senders: colors.senders.clone().unwrap_or(theme.senders),
+        }
+    }
+
+    pub fn keymap(&self) -> Keymap {
+        Keymap::preset(self.keymap).with_keys(&self.keys)
+    }
+
+    pub fn notifiers(&self) -> Notifiers {
+        Notifiers::new(&self.notifications)
+    }
+}
+
+// `~/.config` when `XDG_CONFIG_HOME` is not set
+fn config_dir() -> Option<PathBuf> {
+    match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")),
+    }
+}
+
+// Colors are names like "yellow" or "light-blue", or hex codes like "#ebcb8b"
+pub fn parse_color(value: &str) -> Option<Color> {
+    if let Some(hex) = value.strip_prefix('#') {
+        let channel = |range| u8::from_str_radix(hex.get(range)?, 16).ok();
+        return match hex.len() {
+            6 => Some(Color::Rgb(channel(0..2)?, channel(2..4)?, channel(4..6)?)),
+            _ => None,
+        };
+    }
+    let color = match value.to_lowercase().replace(['-', '_', ' '], "").as_str() {
+        "reset" | "default" => Color::Reset,
+        "black" => Color::Black,
+        "red" => Color::Red,
+        "green" => Color::Green,
+        "yellow" => Color::Yellow,
+        "blue" => Color::Blue,
+        "magenta" => Color::Magenta,
+        "cyan" => Color::Cyan,
+        "gray" | "grey" => Color::Gray,
+        "darkgray" | "darkgrey" => Color::DarkGray,
+        "lightred" => Color::LightRed,
+        "lightgreen" => Color::LightGreen,
+        "lightyellow" => Color::LightYellow,
+        "lightblue" => Color::LightBlue,
+        "lightmagenta" => Color::LightMagenta,
+        "lightcyan" => Color::LightCyan,
+        "white" => Color::White,
+        _ => return None,
+    };
+    Some(color)
+}
+
+mod optional_color {
+    use serde::{de::Error, Deserialize, Deserializer};
+    use tui::style::Color;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Color>, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        match super::parse_color(&value) {
+            Some(color) => Ok(Some(color)),
+            None => Err(D::Error::custom(format!("unknown color {value:?}"))),
+        }
+    }
+}
+
+mod color_list {
+    use serde::{de::Error, Deserialize, Deserializer};
+    use tui::style::Color;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<Color>>, D::Error> {
+        let values = Vec::<String>::deserialize(deserializer)?;
+        let colors = values
+            .iter()
+            .map(|value| {
+                super::parse_color(value)
+                    .ok_or_else(|| D::Error::custom(format!("unknown color {value:?}")))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Some(colors))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colors_are_names_or_hex_codes() {
+        assert_eq!(parse_color("yellow"), Some(Color::Yellow));
+        assert_eq!(parse_color("Light-Blue"), Some(Color::LightBlue));
+        assert_eq!(parse_color("dark_grey"), Some(Color::DarkGray));
+        assert_eq!(parse_color("default"), Some(Color::Reset));
+        assert_eq!(parse_color("#ebcb8b"), Some(Color::Rgb(0xeb, 0xcb, 0x8b)));
+        assert_eq!(parse_color("#EBCB8B"), Some(Color::Rgb(0xeb, 0xcb, 0x8b)));
+        for value in [
+            "", "purple", "#fff", "#ebcb8b0", "#ebcbzz", "ebcb8b", "#ébcb8",
+        ] {
+            assert_eq!(parse_color(value), None, "{value:?} was parsed");
+        }
+    }
+
+    #[test]
+    fn unknown_colors_are_rejected_in_the_config() {
+        let config: Config = toml::from_str(
+            r##"
+            [colors]
+            mention = "#ebcb8b"
+            senders = ["red", "light-green"]
+            "##,
+        )
+        .unwrap();
+        assert_eq!(config.colors.mention, Some(Color::Rgb(0xeb, 0xcb, 0x8b)));
+        assert_eq!(
+            config.colors.senders,
+            Some(vec![Color::Red, Color::LightGreen])
+        );
+        assert_eq!(config.colors.text, None);
+
+        let error = toml::from_str::<Config>("colors.text = \"purple\"")
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("unknown color \"purple\""));
+        let error = toml::from_str::<Config>("colors.senders = [\"red\", \"#12\"]")
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("unknown color \"#12\""));
+    }
+
+    #[test]
+    fn notifications_are_read_from_the_config() {
+        let config: Config = toml::from_str(
+            r##"
+            [notifications]
+            mention = ["osc9", "command"]
+            command = ["notify-send", "{title}", "{body}"]
+            muted = ["#random", "@bob"]
+            "##,
+        )
+        .unwrap();
+        let notifications = &config.notifications;
+        assert!(notifications.direct_message == [NotifierName::Bell]);
+        assert!(notifications.mention == [NotifierName::Osc9, NotifierName::Command]);
+        assert_eq!(notifications.command, ["notify-send", "{title}", "{body}"]);
+        assert_eq!(notifications.muted, ["#random", "@bob"]);
+        assert!(notifications.validate().is_ok());
+        let notifiers = config.notifiers();
+        assert!(notifiers.is_muted("#random") && !notifiers.is_muted("#general"));
+
+        assert!(toml::from_str::<Config>("notifications.message = [\"popup\"]").is_err());
+        let config: Config = toml::from_str("notifications.message = [\"command\"]").unwrap();
+        assert!(config.notifications.validate().is_err());
+    }
+}
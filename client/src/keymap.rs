@@ -0,0 +1,453 @@
+use crate::model::InputMode;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use serde::{de::Error, Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeymapName {
+    #[default]
+    Vim,
+    Emacs,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    Quit,
+    InsertMode,
+    NormalMode,
+    Send,
+    DeleteBackward,
+    // Unselects the message and closes the profile
+    Cancel,
+    SelectPrevious,
+    SelectNext,
+    Copy,
+    Profile,
+    Edit,
+    Delete,
+    Reply,
+    React,
+    Thread,
+    Members,
+    // Turns the notifications of the shown conversation off and on
+    Mute,
+    NextConversation,
+    PreviousConversation,
+    Help,
+    // Searches actions, rooms and users
+    Palette,
+}
+
+impl Action {
+    // Keys bound to several actions run the first one in this order
+    pub const ALL: [Action; 21] = [
+        Action::Quit,
+        Action::InsertMode,
+        Action::NormalMode,
+        Action::Send,
+        Action::DeleteBackward,
+        Action::Cancel,
+        Action::SelectPrevious,
+        Action::SelectNext,
+        Action::Copy,
+        Action::Profile,
+        Action::Edit,
+        Action::Delete,
+        Action::Reply,
+        Action::React,
+        Action::Thread,
+        Action::Members,
+        Action::Mute,
+        Action::NextConversation,
+        Action::PreviousConversation,
+        Action::Help,
+        Action::Palette,
+    ];
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Action::Quit => "Exit the chat",
+            Action::InsertMode => "Start typing",
+            Action::NormalMode => "Stop typing",
+            Action::Send => "Send the message",
+            Action::DeleteBackward => "Delete the last character",
+            Action::Cancel => "Unselect the message or close the profile",
+            Action::SelectPrevious => "Select the previous message",
+            Action::SelectNext => "Select the next message",
+            Action::Copy => "Copy the message",
+            Action::Profile => "Show the profile of the sender",
+            Action::Edit => "Edit the message",
+            Action::Delete => "Delete the message",
+            Action::Reply => "Reply to the message",
+            Action::React => "React to the message",
+            Action::Thread => "Open or close the thread",
+            Action::Members => "Show or hide the members",
+            Action::Mute => "Mute or unmute the conversation",
+            Action::NextConversation => "Show the next conversation",
+            Action::PreviousConversation => "Show the previous conversation",
+            Action::Help => "Show every key and command",
+            Action::Palette => "Search actions, rooms and users",
+        }
+    }
+
+    fn available_in(self, mode: InputMode) -> bool {
+        match self {
+            Action::InsertMode => matches!(mode, InputMode::Normal),
+            Action::NormalMode | Action::Send | Action::DeleteBackward => {
+                matches!(mode, InputMode::Insert)
+            }
+            _ => true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Key {
+    code: KeyCode,
+    modifiers: KeyModifiers,
+}
+
+impl Key {
+    // Shift is part of the character itself, e.g. `+` or `K`
+    fn matches(&self, key: KeyEvent) -> bool {
+        let mut modifiers = key.modifiers;
+        if let KeyCode::Char(_) = key.code {
+            modifiers.remove(KeyModifiers::SHIFT);
+        }
+        self.code == key.code && self.modifiers == modifiers
+    }
+
+    // Characters without modifiers are typed into the input in the insert mode
+    fn is_typed(&self) -> bool {
+        matches!(self.code, KeyCode::Char(_)) && self.modifiers.is_empty()
+    }
+}
+
+// Keys are written like "q", "+", "esc", "up", "ctrl-n" or "alt-enter"
+impl FromStr for Key {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut modifiers = KeyModifiers::NONE;
+        let mut rest = value;
+        loop {
+            if let Some(key) = rest.strip_prefix("ctrl-") {
+                modifiers.insert(KeyModifiers::CONTROL);
+                rest = key;
+            } else if let Some(key) = rest.strip_prefix("alt-") {
+                modifiers.insert(KeyModifiers::ALT);
+                rest = key;
+            } else {
+                break;
+            }
+        }
+        let mut chars = rest.chars();
+        let code = match (chars.next(), chars.next()) {
+            (Some(c), None) => KeyCode::Char(c),
+            _ => match rest.to_lowercase().as_str() {
+                "esc" => KeyCode::Esc,
+                "enter" => KeyCode::Enter,
+                "backspace" => KeyCode::Backspace,
+                "delete" => KeyCode::Delete,
+                "tab" => KeyCode::Tab,
+                "space" => KeyCode::Char(' '),
+                "up" => KeyCode::Up,
+                "down" => KeyCode::Down,
+                "left" => KeyCode::Left,
+                "right" => KeyCode::Right,
+                "home" => KeyCode::Home,
+                "end" => KeyCode::End,
+                "pageup" => KeyCode::PageUp,
+                "pagedown" => KeyCode::PageDown,
+                name => match name.strip_prefix('f').and_then(|n| n.parse().ok()) {
+                    Some(n @ 1..=12) => KeyCode::F(n),
+                    _ => return Err(format!("unknown key {value:?}")),
+                },
+            },
+        };
+        Ok(Self { code, modifiers })
+    }
+}
+
+impl<'de> Deserialize<'de> for Key {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+// How keys are shown in the hints, e.g. "k", "Ctrl+N" or "Esc"
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.modifiers.contains(KeyModifiers::CONTROL) {
+            write!(f, "Ctrl+")?;
+        }
+        if self.modifiers.contains(KeyModifiers::ALT) {
+            write!(f, "Alt+")?;
+        }
+        match self.code {
+            KeyCode::Char(' ') => write!(f, "Space"),
+            KeyCode::Char(c) if !self.modifiers.is_empty() => {
+                write!(f, "{}", c.to_uppercase())
+            }
+            KeyCode::Char(c) => write!(f, "{c}"),
+            KeyCode::Esc => write!(f, "Esc"),
+            KeyCode::Enter => write!(f, "Enter"),
+            KeyCode::Backspace => write!(f, "Backspace"),
+            KeyCode::Delete => write!(f, "Del"),
+            KeyCode::Tab => write!(f, "Tab"),
+            KeyCode::Up => write!(f, "↑"),
+            KeyCode::Down => write!(f, "↓"),
+            KeyCode::Left => write!(f, "←"),
+            KeyCode::Right => write!(f, "→"),
+            KeyCode::Home => write!(f, "Home"),
+            KeyCode::End => write!(f, "End"),
+            KeyCode::PageUp => write!(f, "PageUp"),
+            KeyCode::PageDown => write!(f, "PageDown"),
+            KeyCode::F(n) => write!(f, "F{n}"),
+            code => write!(f, "{code:?}"),
+        }
+    }
+}
+
+const VIM: &[(Action, &[&str])] = &[
+    (Action::Quit, &["q"]),
+    (Action::InsertMode, &["i"]),
+    (Action::NormalMode, &["esc"]),
+    (Action::Send, &["enter"]),
+    (Action::DeleteBackward, &["backspace"]),
+    (Action::Cancel, &["esc"]),
+    (Action::SelectPrevious, &["k", "up"]),
+    (Action::SelectNext, &["j", "down"]),
+    (Action::Copy, &["y"]),
+    (Action::Profile, &["p"]),
+    (Action::Edit, &["e"]),
+    (Action::Delete, &["d"]),
+    (Action::Reply, &["r"]),
+    (Action::React, &["+"]),
+    (Action::Thread, &["t"]),
+    (Action::Members, &["m"]),
+    (Action::Mute, &["z"]),
+    (Action::NextConversation, &["ctrl-n"]),
+    (Action::PreviousConversation, &["ctrl-p"]),
+    (Action::Help, &["?", "f1"]),
+    (Action::Palette, &["ctrl-k"]),
+];
+
+// Everything works while typing, so the normal mode is hardly needed
+const EMACS: &[(Action, &[&str])] = &[
+    (Action::Quit, &["ctrl-q"]),
+    (Action::InsertMode, &["i", "enter"]),
+    (Action::NormalMode, &["esc"]),
+    (Action::Send, &["enter"]),
+    (Action::DeleteBackward, &["backspace"]),
+    (Action::Cancel, &["ctrl-g"]),
+    (Action::SelectPrevious, &["ctrl-p", "up"]),
+    (Action::SelectNext, &["ctrl-n", "down"]),
+    (Action::Copy, &["alt-w"]),
+    (Action::Profile, &["alt-u"]),
+    (Action::Edit, &["alt-e"]),
+    (Action::Delete, &["alt-d"]),
+    (Action::Reply, &["alt-r"]),
+    (Action::React, &["alt-+"]),
+    (Action::Thread, &["alt-t"]),
+    (Action::Members, &["alt-m"]),
+    (Action::Mute, &["alt-z"]),
+    (Action::NextConversation, &["alt-n"]),
+    (Action::PreviousConversation, &["alt-p"]),
+    (Action::Help, &["f1"]),
+    (Action::Palette, &["ctrl-k"]),
+];
+
+#[derive(Clone)]
+pub struct Keymap {
+    keys: HashMap<Action, Vec<Key>>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Self::preset(KeymapName::Vim)
+    }
+}
+
+impl Keymap {
+    pub fn preset(name: KeymapName) -> Self {
+        let preset = match name {
+            KeymapName::Vim => VIM,
+            KeymapName::Emacs => EMACS,
+        };
+        let keys = preset
+            .iter()
+            .map(|(action, keys)| {
+                let keys = keys.iter().map(|key| key.parse().unwrap()).collect();
+                (*action, keys)
+            })
+            .collect();
+        Self { keys }
+    }
+
+    // The keys of an action replace all of its keys from the preset
+    pub fn with_keys(mut self, keys: &HashMap<Action, Vec<Key>>) -> Self {
+        self.keys
+            .extend(keys.iter().map(|(action, keys)| (*action, keys.clone())));
+        self
+    }
+
+    pub fn action(&self, key: KeyEvent, mode: InputMode) -> Option<Action> {
+        Action::ALL.into_iter().find(|action| {
+            action.available_in(mode) && self.usable_keys(*action, mode).any(|k| k.matches(key))
+        })
+    }
+
+    // Every key of the action, for the help
+    pub fn keys(&self, action: Action) -> Vec<String> {
+        self.keys
+            .get(&action)
+            .into_iter()
+            .flatten()
+            .map(|key| key.to_string())
+            .collect()
+    }
+
+    // The first key of the action that works in the mode, for the hints
+    pub fn hint(&self, action: Action, mode: InputMode) -> Option<String> {
+        if !action.available_in(mode) {
+            return None;
+        }
+        self.usable_keys(action, mode)
+            .next()
+            .map(|key| key.to_string())
+    }
+
+    fn usable_keys(&self, action: Action, mode: InputMode) -> impl Iterator<Item = &Key> {
+        let insert = matches!(mode, InputMode::Insert);
+        self.keys
+            .get(&action)
+            .into_iter()
+            .flatten()
+            .filter(move |key| !(insert && key.is_typed()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+        KeyEvent::new(code, modifiers)
+    }
+
+    // Pairs of actions that share a key in the mode
+    fn conflicts(keymap: &Keymap, mode: InputMode) -> Vec<(Action, Action)> {
+        let mut conflicts = Vec::new();
+        for (index, first) in Action::ALL.iter().enumerate() {
+            for second in &Action::ALL[index + 1..] {
+                let shared = first.available_in(mode)
+                    && second.available_in(mode)
+                    && keymap
+                        .usable_keys(*first, mode)
+                        .any(|key| keymap.usable_keys(*second, mode).any(|other| other == key));
+                if shared {
+                    conflicts.push((*first, *second));
+                }
+            }
+        }
+        conflicts
+    }
+
+    #[test]
+    fn keys_are_parsed_and_shown() {
+        for (value, shown) in [
+            ("q", "q"),
+            ("+", "+"),
+            ("space", "Space"),
+            ("Esc", "Esc"),
+            ("up", "↑"),
+            ("pagedown", "PageDown"),
+            ("f1", "F1"),
+            ("F12", "F12"),
+            ("ctrl-n", "Ctrl+N"),
+            ("alt-enter", "Alt+Enter"),
+            ("ctrl-alt-x", "Ctrl+Alt+X"),
+            ("alt-+", "Alt++"),
+        ] {
+            assert_eq!(value.parse::<Key>().unwrap().to_string(), shown);
+        }
+        for value in ["", "f0", "f13", "ctrl-", "escape", "ctrl-shift-n"] {
+            assert!(value.parse::<Key>().is_err(), "{value:?} was parsed");
+        }
+    }
+
+    #[test]
+    fn shift_is_part_of_typed_characters() {
+        let plus: Key = "+".parse().unwrap();
+        assert!(plus.matches(key(KeyCode::Char('+'), KeyModifiers::SHIFT)));
+        let ctrl_n: Key = "ctrl-n".parse().unwrap();
+        assert!(ctrl_n.matches(key(KeyCode::Char('n'), KeyModifiers::CONTROL)));
+        assert!(!ctrl_n.matches(key(KeyCode::Char('n'), KeyModifiers::NONE)));
+        let up: Key = "up".parse().unwrap();
+        assert!(!up.matches(key(KeyCode::Up, KeyModifiers::SHIFT)));
+    }
+
+    #[test]
+    fn presets_share_keys_only_between_modes() {
+        // Esc leaves the insert mode before it would unselect the message
+        let vim = Keymap::preset(KeymapName::Vim);
+        assert_eq!(
+            conflicts(&vim, InputMode::Insert),
+            [(Action::NormalMode, Action::Cancel)]
+        );
+        assert_eq!(conflicts(&vim, InputMode::Normal), []);
+        let emacs = Keymap::preset(KeymapName::Emacs);
+        assert_eq!(conflicts(&emacs, InputMode::Insert), []);
+        assert_eq!(conflicts(&emacs, InputMode::Normal), []);
+    }
+
+    #[test]
+    fn typed_characters_run_actions_only_in_the_normal_mode() {
+        let keymap = Keymap::default();
+        let q = key(KeyCode::Char('q'), KeyModifiers::NONE);
+        assert_eq!(keymap.action(q, InputMode::Normal), Some(Action::Quit));
+        assert_eq!(keymap.action(q, InputMode::Insert), None);
+        let esc = key(KeyCode::Esc, KeyModifiers::NONE);
+        assert_eq!(
+            keymap.action(esc, InputMode::Insert),
+            Some(Action::NormalMode)
+        );
+        assert_eq!(keymap.action(esc, InputMode::Normal), Some(Action::Cancel));
+        assert_eq!(
+            keymap.hint(Action::Help, InputMode::Insert).as_deref(),
+            Some("F1")
+        );
+    }
+
+    #[test]
+    fn hints_follow_the_preset() {
+        let emacs = Keymap::preset(KeymapName::Emacs);
+        for mode in [InputMode::Normal, InputMode::Insert] {
+            assert_eq!(emacs.hint(Action::Thread, mode).as_deref(), Some("Alt+T"));
+            assert_eq!(emacs.hint(Action::Cancel, mode).as_deref(), Some("Ctrl+G"));
+        }
+        let vim = Keymap::preset(KeymapName::Vim);
+        assert_eq!(
+            vim.hint(Action::Thread, InputMode::Normal).as_deref(),
+            Some("t")
+        );
+        assert_eq!(vim.hint(Action::Thread, InputMode::Insert), None);
+    }
+
+    #[test]
+    fn configured_keys_replace_the_preset_ones() {
+        let keys = HashMap::from([(Action::Quit, vec!["ctrl-c".parse().unwrap()])]);
+        let keymap = Keymap::default().with_keys(&keys);
+        assert_eq!(keymap.keys(Action::Quit), ["Ctrl+C"]);
+        assert_eq!(keymap.keys(Action::Help), ["?", "F1"]);
+        let q = key(KeyCode::Char('q'), KeyModifiers::NONE);
+        assert_eq!(keymap.action(q, InputMode::Normal), None);
+    }
+}
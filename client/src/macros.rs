@@ -2,7 +2,10 @@
 macro_rules! request_to_json {
     ($method:expr, $body:expr) => {{
         let request = match $method {
-            "SendMessage" | "LogInUsername" =>
+            "SendMessage" | "EditMessage" | "DeleteMessage" | "GetThread" | "AddReaction"
+            | "RemoveReaction" | "GetProfile" | "UploadStart" | "UploadChunk" | "UploadFinish"
+            | "Download" | "DirectMessage" | "JoinRoom" | "GetMembers" | "MarkRead" | "LogInUsername"
+            | "Ping" | "Pong" =>
                 serde_json::json!({ "type": "request_c2s", "method": $method, "body": $body }),
             "LogInPassword" | "RegisterUsername" | "MessageRead" | "GetHistory" => unimplemented!(),
             &_ => unreachable!()
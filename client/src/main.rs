@@ -1,6 +1,7 @@
 use crate::client::Client;
+use crate::config::Config;
 use crossterm::{
-    event::{DisableMouseCapture, EnableMouseCapture},
+    event::{DisableFocusChange, DisableMouseCapture, EnableFocusChange, EnableMouseCapture},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
@@ -9,13 +10,26 @@ use tokio::net::TcpStream;
 use tui::{backend::CrosstermBackend, Terminal};
 
 mod client;
+mod config;
+mod keymap;
 mod macros;
 mod message;
+mod notifier;
+mod palette;
+mod theme;
 mod ui;
 mod model;
+mod transfer;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("[ERROR] {e}");
+            return Ok(());
+        }
+    };
     let socket = match TcpStream::connect("0.0.0.0:8080").await {
         Ok(socket) => socket,
         Err(_) => {
@@ -26,22 +40,25 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     enable_raw_mode()?;
     let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+    execute!(stdout, EnterAlternateScreen, EnableMouseCapture, EnableFocusChange)?;
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
-    let client = Client::default();
-    if let Err(e) = client.run_client(&mut terminal, socket).await {
-        eprintln!("[ERROR] {}", e);
-    }
+    let client = Client::new(config);
+    let result = client.run_client(&mut terminal, socket).await;
 
     // TODO: Handle panics
     disable_raw_mode()?;
     execute!(
         terminal.backend_mut(),
         LeaveAlternateScreen,
-        DisableMouseCapture
+        DisableMouseCapture,
+        DisableFocusChange
     )?;
     terminal.show_cursor()?;
+
+    if let Err(e) = result {
+        eprintln!("[ERROR] {}", e);
+    }
     Ok(())
 }
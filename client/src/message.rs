@@ -1,38 +1,270 @@
 use chrono::{DateTime, Local};
 use serde_json::Value;
 
+// Snippet of the message that is replied to
+#[derive(Clone)]
+pub struct Quote {
+    pub sender: String,
+    pub data: String,
+}
+
+// A file sent with a message, it is downloaded on demand
+#[derive(Clone)]
+pub struct Attachment {
+    pub id: i64,
+    pub name: String,
+    pub size: u64,
+}
+
+// Users who reacted to a message with the same emoji
+#[derive(Clone)]
+pub struct Reaction {
+    pub emoji: String,
+    pub users: Vec<String>,
+}
+
+impl Reaction {
+    pub fn from_list(value: &Value) -> Vec<Self> {
+        value
+            .as_array()
+            .map(|reactions| {
+                reactions
+                    .iter()
+                    .map(|reaction| Self {
+                        emoji: reaction["emoji"].as_str().unwrap_or_default().to_string(),
+                        users: reaction["users"]
+                            .as_array()
+                            .map(|users| {
+                                users
+                                    .iter()
+                                    .filter_map(|user| user.as_str().map(String::from))
+                                    .collect()
+                            })
+                            .unwrap_or_default(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
 #[derive(Clone)]
 pub struct Message {
+    // `None` until the server has stored the message
+    pub id: Option<i64>,
+    // Own messages the server has not answered yet are numbered in the order they were sent
+    pub pending: Option<u64>,
+    // `None` for messages that are not sent to a room
+    pub room_id: Option<i64>,
+    pub parent_id: Option<i64>,
+    pub parent: Option<Quote>,
     pub data: String,
     pub sender: Option<String>,
+    // Set for direct messages, which are only seen by the sender and the recipient
+    pub recipient: Option<String>,
     pub date: String,
+    pub edited: bool,
+    pub deleted: bool,
+    pub reactions: Vec<Reaction>,
+    pub attachment: Option<Attachment>,
 }
 
 impl Message {
     pub fn new(data: String, sender: Option<String>, date: String) -> Self {
-        Self { data, sender, date }
+        Self {
+            id: None,
+            pending: None,
+            room_id: None,
+            parent_id: None,
+            parent: None,
+            data,
+            sender,
+            recipient: None,
+            date,
+            edited: false,
+            deleted: false,
+            reactions: Vec::new(),
+            attachment: None,
+        }
     }
 
     pub fn from_json_value(value: Value) -> Self {
-        let body = value.get("body").unwrap();
-        let date = body
-            .get("date")
-            .map(|v| {
-                let date = v.as_str().unwrap();
-                let local_date = DateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S %z")
-                    .unwrap()
-                    .with_timezone(&Local);
-                local_date.format("%d-%m-%Y %H:%M").to_string()
+        Self::from_body(&value["body"])
+    }
+
+    // `@alice` mentions alice, the letter case and punctuation after the name don't matter
+    pub fn mentions(&self, username: &str) -> bool {
+        let username = username.to_lowercase();
+        self.data
+            .split_whitespace()
+            .filter_map(|word| word.strip_prefix('@'))
+            .any(|word| {
+                let trimmed = word.trim_end_matches(|c: char| c.is_ascii_punctuation());
+                word.to_lowercase() == username || trimmed.to_lowercase() == username
+            })
+    }
+
+    // Direct messages are numbered apart from the messages of the room, so they are
+    // kept without an id and can't be edited, replied to or reacted to
+    pub fn from_direct_json_value(value: Value) -> Self {
+        let body = &value["body"];
+        Self {
+            id: None,
+            recipient: body["recipient"].as_str().map(String::from),
+            ..Self::from_body(body)
+        }
+    }
+
+    pub fn from_body(body: &Value) -> Self {
+        let date = body["date"].as_str().map(local_date).unwrap_or_default();
+        let sender = body["sender"].as_str().map(String::from);
+        let data = body["data"].as_str().unwrap_or_default().to_string();
+        let parent = body.get("parent").map(|parent| Quote {
+            sender: parent["sender"].as_str().unwrap_or_default().to_string(),
+            data: parent["data"].as_str().unwrap_or_default().to_string(),
+        });
+        Self {
+            id: body.get("id").and_then(|v| v.as_i64()),
+            room_id: body.get("room_id").and_then(|v| v.as_i64()),
+            parent_id: body.get("parent_id").and_then(|v| v.as_i64()),
+            parent,
+            edited: body
+                .get("edited")
+                .and_then(|v| v.as_bool())
+                .unwrap_or_default(),
+            deleted: body
+                .get("deleted")
+                .and_then(|v| v.as_bool())
+                .unwrap_or_default(),
+            reactions: Reaction::from_list(&body["reactions"]),
+            attachment: body.get("attachment").map(|attachment| Attachment {
+                id: attachment["id"].as_i64().unwrap_or_default(),
+                name: attachment["name"].as_str().unwrap_or_default().to_string(),
+                size: attachment["size"].as_u64().unwrap_or_default(),
+            }),
+            ..Self::new(data, sender, date)
+        }
+    }
+}
+
+// Converts a date sent by the server to the local time zone, a date in another
+// format is shown as it is
+fn local_date(date: &str) -> String {
+    match DateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S %z") {
+        Ok(date) => date
+            .with_timezone(&Local)
+            .format("%d-%m-%Y %H:%M")
+            .to_string(),
+        Err(_) => date.to_string(),
+    }
+}
+
+// A room the user has joined, or the direct messages with another user
+pub struct Conversation {
+    // `None` for direct messages, and for the default room until the server
+    // has sent its id
+    pub room_id: Option<i64>,
+    // The name of the room, or the username of the other user
+    pub name: String,
+    pub direct: bool,
+    pub unread: u64,
+    // Messages that were received while another conversation was shown
+    pub messages: Vec<Message>,
+    // Input that was not sent before another conversation was shown
+    pub draft: String,
+    // Members of the room, fetched while the member list is shown
+    pub members: Vec<Member>,
+}
+
+impl Conversation {
+    pub fn room(room_id: Option<i64>, name: &str) -> Self {
+        Self {
+            room_id,
+            name: name.to_string(),
+            direct: false,
+            unread: 0,
+            messages: Vec::new(),
+            draft: String::new(),
+            members: Vec::new(),
+        }
+    }
+
+    pub fn direct(username: &str) -> Self {
+        Self {
+            direct: true,
+            ..Self::room(None, username)
+        }
+    }
+
+    pub fn label(&self) -> String {
+        if self.direct {
+            format!("@{}", self.name)
+        } else {
+            format!("#{}", self.name)
+        }
+    }
+}
+
+pub struct Member {
+    pub username: String,
+    pub online: bool,
+}
+
+impl Member {
+    pub fn from_list(value: &Value) -> Vec<Self> {
+        value
+            .as_array()
+            .map(|members| {
+                members
+                    .iter()
+                    .map(|member| Self {
+                        username: member["username"].as_str().unwrap_or_default().to_string(),
+                        online: member["online"].as_bool().unwrap_or_default(),
+                    })
+                    .collect()
             })
-            .unwrap();
-        let sender = body
-            .get("sender")
-            .map(|v| v.as_str().unwrap().to_string());
-        let data = body
-            .get("data")
-            .and_then(|v| v.as_str())
-            .unwrap()
-            .to_string();
-        Self { data, sender, date }
+            .unwrap_or_default()
+    }
+}
+
+// What is known about the sender of a message
+pub struct Profile {
+    pub username: String,
+    pub online: bool,
+    pub messages: i64,
+    pub first_seen: Option<String>,
+    pub last_seen: Option<String>,
+}
+
+impl Profile {
+    pub fn from_json_value(value: Value) -> Self {
+        let body = &value["body"];
+        Self {
+            username: body["username"].as_str().unwrap_or_default().to_string(),
+            online: body["online"].as_bool().unwrap_or_default(),
+            messages: body["messages"].as_i64().unwrap_or_default(),
+            first_seen: body["first_seen"].as_str().map(local_date),
+            last_seen: body["last_seen"].as_str().map(local_date),
+        }
+    }
+}
+
+// A message with all of its replies
+pub struct Thread {
+    pub parent: Message,
+    pub replies: Vec<Message>,
+}
+
+impl Thread {
+    pub fn from_json_value(value: Value) -> Self {
+        let body = &value["body"];
+        let replies = body["replies"]
+            .as_array()
+            .map(|replies| replies.iter().map(Message::from_body).collect())
+            .unwrap_or_default();
+        Self {
+            parent: Message::from_body(&body["parent"]),
+            replies,
+        }
     }
 }
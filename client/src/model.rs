@@ -1,4 +1,10 @@
+use serde_json::Value;
+use std::time::Duration;
+
 pub const SERVER_SHUTDOWN_MESSAGE: &str = "Server is shutting down, app will be closed in 10 seconds";
+pub const PING_INTERVAL: Duration = Duration::from_secs(5);
+// Messages kept for each conversation, the shown one scrolls through them
+pub const MAX_HIDDEN_MESSAGES: usize = 100;
 
 #[derive(Clone, Copy)]
 pub(crate) enum ClientState {
@@ -15,6 +21,29 @@ pub(crate) enum InputMode {
 #[derive(Debug)]
 pub(crate) enum Command {
     Exit,
-    SendMessage(String),
+    // The text, the room and the id of the message it replies to
+    SendMessage(String, Option<i64>, Option<i64>),
+    EditMessage(i64, String),
+    DeleteMessage(i64),
+    GetThread(i64),
+    // The id of the message and the emoji
+    AddReaction(i64, String),
+    RemoveReaction(i64, String),
+    GetProfile(String),
+    // The recipient and the text
+    DirectMessage(String, String),
+    JoinRoom(String),
+    GetMembers(i64),
+    MarkRoomRead(i64),
+    // The sender of the direct messages
+    MarkDirectRead(String),
+    // The name, size and SHA-256 checksum of the file
+    UploadStart(String, u64, String),
+    UploadChunk(i64, Vec<u8>),
+    // The id of the upload, the caption and the room
+    UploadFinish(i64, Option<String>, Option<i64>),
+    // The id of the attachment and the offset of the next chunk
+    Download(i64, u64),
     LogInUsername(String),
+    Pong(Value),
 }
\ No newline at end of file
@@ -0,0 +1,210 @@
+use crate::config::Notifications;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifierName {
+    Bell,
+    Osc9,
+    Osc777,
+    Command,
+}
+
+#[derive(Clone, Copy)]
+pub enum Notification {
+    DirectMessage,
+    Mention,
+    // Messages in the rooms that are not shown
+    Message,
+}
+
+pub trait Notifier {
+    fn notify(&self, title: &str, body: &str) -> io::Result<()>;
+}
+
+pub struct Bell;
+
+impl Notifier for Bell {
+    fn notify(&self, _title: &str, _body: &str) -> io::Result<()> {
+        let mut stdout = io::stdout();
+        stdout.write_all(b"\x07")?;
+        stdout.flush()
+    }
+}
+
+// Desktop notification of iTerm2, WezTerm, Windows Terminal and others
+pub struct Osc9;
+
+impl Notifier for Osc9 {
+    fn notify(&self, title: &str, body: &str) -> io::Result<()> {
+        let mut stdout = io::stdout();
+        write!(stdout, "\x1b]9;{}: {}\x07", escape(title), escape(body))?;
+        stdout.flush()
+    }
+}
+
+// Desktop notification of rxvt-unicode, foot, Ghostty and others
+pub struct Osc777;
+
+impl Notifier for Osc777 {
+    fn notify(&self, title: &str, body: &str) -> io::Result<()> {
+        let mut stdout = io::stdout();
+        let title = escape(title).replace(';', ",");
+        write!(stdout, "\x1b]777;notify;{title};{}\x07", escape(body))?;
+        stdout.flush()
+    }
+}
+
+// Runs a program like `notify-send`, `{title}` and `{body}` in its arguments are replaced
+pub struct ExternalCommand {
+    program: String,
+    args: Vec<String>,
+}
+
+impl Notifier for ExternalCommand {
+    fn notify(&self, title: &str, body: &str) -> io::Result<()> {
+        let mut child = Command::new(&self.program)
+            .args(
+                self.args
+                    .iter()
+                    .map(|arg| arg.replace("{title}", title).replace("{body}", body)),
+            )
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        // Waiting for it keeps finished commands from piling up as zombies
+        std::thread::spawn(move || child.wait());
+        Ok(())
+    }
+}
+
+// Messages are written by other users, so they must not end the escape sequence
+fn escape(text: &str) -> String {
+    text.chars().filter(|c| !c.is_control()).collect()
+}
+
+pub struct Notifiers {
+    direct_message: Vec<Box<dyn Notifier>>,
+    mention: Vec<Box<dyn Notifier>>,
+    message: Vec<Box<dyn Notifier>>,
+    // Labels of the conversations without notifications, e.g. "#random" or "@bob"
+    muted: HashSet<String>,
+}
+
+impl Default for Notifiers {
+    fn default() -> Self {
+        Self::new(&Notifications::default())
+    }
+}
+
+impl Notifiers {
+    pub fn new(config: &Notifications) -> Self {
+        let build = |names: &[NotifierName]| -> Vec<Box<dyn Notifier>> {
+            names
+                .iter()
+                .map(|name| -> Box<dyn Notifier> {
+                    match name {
+                        NotifierName::Bell => Box::new(Bell),
+                        NotifierName::Osc9 => Box::new(Osc9),
+                        NotifierName::Osc777 => Box::new(Osc777),
+                        NotifierName::Command => Box::new(ExternalCommand {
+                            program: config.command.first().cloned().unwrap_or_default(),
+                            args: config.command.iter().skip(1).cloned().collect(),
+                        }),
+                    }
+                })
+                .collect()
+        };
+        Self {
+            direct_message: build(&config.direct_message),
+            mention: build(&config.mention),
+            message: build(&config.message),
+            muted: config.muted.iter().cloned().collect(),
+        }
+    }
+
+    // Nothing is sent for muted conversations, the first error stops the others
+    pub fn notify(
+        &self,
+        event: Notification,
+        conversation: &str,
+        title: &str,
+        body: &str,
+    ) -> io::Result<()> {
+        if self.is_muted(conversation) {
+            return Ok(());
+        }
+        let notifiers = match event {
+            Notification::DirectMessage => &self.direct_message,
+            Notification::Mention => &self.mention,
+            Notification::Message => &self.message,
+        };
+        notifiers
+            .iter()
+            .try_for_each(|notifier| notifier.notify(title, body))
+    }
+
+    pub fn is_muted(&self, conversation: &str) -> bool {
+        self.muted.contains(conversation)
+    }
+
+    // Returns whether the conversation is muted now
+    pub fn toggle_mute(&mut self, conversation: &str) -> bool {
+        if self.muted.remove(conversation) {
+            false
+        } else {
+            self.muted.insert(conversation.to_string());
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A program that can't be started, so a notification fails instead of showing up
+    fn failing_notifiers(muted: &[&str]) -> Notifiers {
+        Notifiers::new(&Notifications {
+            direct_message: vec![NotifierName::Command],
+            mention: Vec::new(),
+            message: vec![NotifierName::Command],
+            command: vec!["/nonexistent/notifier".to_string(), "{title}".to_string()],
+            muted: muted.iter().map(|label| label.to_string()).collect(),
+        })
+    }
+
+    #[test]
+    fn muted_conversations_are_not_notified() {
+        let notifiers = failing_notifiers(&["#random"]);
+        assert!(notifiers.is_muted("#random"));
+        assert!(!notifiers.is_muted("#general"));
+        let notify = |conversation| notifiers.notify(Notification::Message, conversation, "", "");
+        assert!(notify("#random").is_ok());
+        assert!(notify("#general").is_err());
+        assert!(notifiers
+            .notify(Notification::Mention, "#general", "", "")
+            .is_ok());
+    }
+
+    #[test]
+    fn mute_toggles() {
+        let mut notifiers = failing_notifiers(&[]);
+        assert!(notifiers.toggle_mute("@bob"));
+        assert!(notifiers.is_muted("@bob"));
+        assert!(notifiers
+            .notify(Notification::DirectMessage, "@bob", "bob", "hi")
+            .is_ok());
+        assert!(!notifiers.toggle_mute("@bob"));
+        assert!(!notifiers.is_muted("@bob"));
+    }
+
+    #[test]
+    fn escape_sequences_are_removed_from_messages() {
+        assert_eq!(escape("hi\x07\x1b]9;evil\x1b\\ there"), "hi]9;evil\\ there");
+    }
+}
@@ -0,0 +1,103 @@
+use crate::keymap::Action;
+
+#[derive(Default)]
+pub struct Palette {
+    pub query: String,
+    // Index of the selected entry among the matching ones
+    pub selected: usize,
+}
+
+pub enum Target {
+    Action(Action),
+    // Index of the conversation in the list
+    Conversation(usize),
+    // Online user without a direct conversation yet
+    User(String),
+}
+
+pub struct Entry {
+    pub label: String,
+    // Key of the action or kind of the conversation, shown after the label
+    pub detail: String,
+    pub target: Target,
+}
+
+// Keeps the entries whose label contains the characters of the query in the same order,
+// the best matches first
+pub fn search(query: &str, entries: Vec<Entry>) -> Vec<Entry> {
+    let mut matches: Vec<(i32, Entry)> = entries
+        .into_iter()
+        .filter_map(|entry| score(query, &entry.label).map(|score| (score, entry)))
+        .collect();
+    matches.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+    matches.into_iter().map(|(_, entry)| entry).collect()
+}
+
+// Characters at the start of a word or right after the previous match score higher
+fn score(query: &str, text: &str) -> Option<i32> {
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let mut score = 0;
+    let mut start = 0;
+    let mut previous = None;
+    for c in query.to_lowercase().chars().filter(|c| !c.is_whitespace()) {
+        let index = start + text[start..].iter().position(|&t| t == c)?;
+        score += 1;
+        if index == 0 || !text[index - 1].is_alphanumeric() {
+            score += 2;
+        }
+        if previous == Some(index.wrapping_sub(1)) {
+            score += 3;
+        }
+        previous = Some(index);
+        start = index + 1;
+    }
+    Some(score)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(query: &str, labels: &[&str]) -> Vec<String> {
+        let entries = labels
+            .iter()
+            .map(|label| Entry {
+                label: label.to_string(),
+                detail: String::new(),
+                target: Target::User(label.to_string()),
+            })
+            .collect();
+        search(query, entries)
+            .into_iter()
+            .map(|entry| entry.label)
+            .collect()
+    }
+
+    #[test]
+    fn characters_must_appear_in_order() {
+        assert_eq!(score("abc", "xaxbxc"), Some(3));
+        assert_eq!(score("cba", "xaxbxc"), None);
+        assert_eq!(score("abcd", "abc"), None);
+        assert_eq!(score("", "anything"), Some(0));
+    }
+
+    #[test]
+    fn word_starts_and_runs_score_higher() {
+        assert_eq!(score("abc", "abc"), Some(11));
+        assert_eq!(score("abc", "a-b-c"), Some(9));
+        assert_eq!(score("abc", "xabc"), Some(9));
+        assert_eq!(score("A B", "abc"), score("ab", "abc"));
+    }
+
+    #[test]
+    fn search_keeps_the_best_matches_first() {
+        let entries = ["#random", "@mauro", "Mute or unmute", "#music"];
+        assert_eq!(
+            labels("mu", &entries),
+            ["Mute or unmute", "#music", "@mauro"]
+        );
+        // Equal scores keep the order of the entries
+        assert_eq!(labels("", &entries), entries);
+        assert!(labels("xyz", &entries).is_empty());
+    }
+}
@@ -0,0 +1,129 @@
+use serde::Deserialize;
+use tui::style::{Color, Modifier, Style};
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ThemeName {
+    #[default]
+    Dark,
+    Light,
+    HighContrast,
+    NoColor,
+}
+
+#[derive(Clone)]
+pub struct Theme {
+    // Messages and dates
+    pub text: Color,
+    // Quotes, tombstones and everything else that is less important
+    pub muted: Color,
+    // Unread conversations, the user's own reactions and the input while typing
+    pub highlight: Color,
+    // Messages that mention the user
+    pub mention: Color,
+    pub attachment: Color,
+    // Online statuses, the latency and other good news
+    pub accent: Color,
+    pub error: Color,
+    // Background of the selected message and of the shown conversation
+    pub selection: Color,
+    // Every sender keeps one of these colors, it is picked by the username
+    pub senders: Vec<Color>,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::builtin(ThemeName::Dark)
+    }
+}
+
+impl Theme {
+    pub fn builtin(name: ThemeName) -> Self {
+        match name {
+            ThemeName::Dark => Self {
+                text: Color::Rgb(216, 222, 233),
+                muted: Color::DarkGray,
+                highlight: Color::Yellow,
+                mention: Color::Rgb(235, 203, 139),
+                attachment: Color::Rgb(136, 192, 208),
+                accent: Color::Green,
+                error: Color::Red,
+                selection: Color::Rgb(59, 66, 82),
+                senders: vec![
+                    Color::Rgb(129, 161, 193),
+                    Color::Rgb(163, 190, 140),
+                    Color::Rgb(180, 142, 173),
+                    Color::Rgb(208, 135, 112),
+                    Color::Rgb(143, 188, 187),
+                    Color::Rgb(94, 129, 172),
+                ],
+            },
+            ThemeName::Light => Self {
+                text: Color::Rgb(46, 52, 64),
+                muted: Color::Rgb(128, 128, 128),
+                highlight: Color::Rgb(176, 112, 0),
+                mention: Color::Rgb(191, 97, 106),
+                attachment: Color::Rgb(0, 119, 153),
+                accent: Color::Rgb(40, 140, 60),
+                error: Color::Rgb(191, 0, 0),
+                selection: Color::Rgb(216, 222, 233),
+                senders: vec![
+                    Color::Rgb(61, 100, 160),
+                    Color::Rgb(60, 120, 50),
+                    Color::Rgb(140, 70, 140),
+                    Color::Rgb(170, 80, 40),
+                    Color::Rgb(30, 120, 120),
+                    Color::Rgb(120, 100, 20),
+                ],
+            },
+            ThemeName::HighContrast => Self {
+                text: Color::White,
+                muted: Color::Gray,
+                highlight: Color::LightYellow,
+                mention: Color::LightMagenta,
+                attachment: Color::LightCyan,
+                accent: Color::LightGreen,
+                error: Color::LightRed,
+                selection: Color::Blue,
+                senders: vec![
+                    Color::LightCyan,
+                    Color::LightGreen,
+                    Color::LightYellow,
+                    Color::LightBlue,
+                    Color::LightMagenta,
+                ],
+            },
+            // Only bold, italic and reversed text are left to tell things apart
+            ThemeName::NoColor => Self {
+                text: Color::Reset,
+                muted: Color::Reset,
+                highlight: Color::Reset,
+                mention: Color::Reset,
+                attachment: Color::Reset,
+                accent: Color::Reset,
+                error: Color::Reset,
+                selection: Color::Reset,
+                senders: vec![Color::Reset],
+            },
+        }
+    }
+
+    // FNV-1a, so a user has the same color in every session and for everyone
+    pub fn sender(&self, username: &str) -> Color {
+        let hash = username.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+        });
+        match self.senders.len() {
+            0 => self.text,
+            len => self.senders[(hash % len as u64) as usize],
+        }
+    }
+
+    // The selection is reversed when there is no color for its background
+    pub fn selection_style(&self) -> Style {
+        match self.selection {
+            Color::Reset => Style::default().add_modifier(Modifier::REVERSED),
+            color => Style::default().bg(color),
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// Raw bytes in one chunk, the server accepts up to 64 KiB by default
+const CHUNK_SIZE: u64 = 32 * 1024;
+
+// A file that is being sent to the server, one chunk after each acknowledgement
+pub struct Upload {
+    // `None` until the server has accepted the upload
+    pub id: Option<i64>,
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+    pub caption: Option<String>,
+    // The room the file is sent to, the default one if it is `None`
+    pub room_id: Option<i64>,
+    pub sent: u64,
+    file: File,
+}
+
+impl Upload {
+    pub fn open(path: &Path, caption: Option<String>) -> io::Result<Self> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file name"))?
+            .to_string();
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        let mut hasher = Sha256::new();
+        io::copy(&mut file, &mut hasher)?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(Self {
+            id: None,
+            name,
+            size,
+            sha256: format!("{:x}", hasher.finalize()),
+            caption,
+            room_id: None,
+            sent: 0,
+            file,
+        })
+    }
+
+    pub fn is_sent(&self) -> bool {
+        self.sent >= self.size
+    }
+
+    pub fn next_chunk(&mut self) -> io::Result<Vec<u8>> {
+        let mut chunk = Vec::new();
+        (&mut self.file)
+            .take(CHUNK_SIZE.min(self.size - self.sent))
+            .read_to_end(&mut chunk)?;
+        if chunk.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the file has changed during the upload",
+            ));
+        }
+        self.sent += chunk.len() as u64;
+        Ok(chunk)
+    }
+}
+
+// An attachment that is being saved, it is written next to the target as
+// `.<name>.part` until the checksum has been checked
+pub struct Download {
+    pub attachment_id: i64,
+    pub name: String,
+    pub size: u64,
+    pub received: u64,
+    dir: PathBuf,
+    file: File,
+    hasher: Sha256,
+}
+
+impl Download {
+    pub fn create(attachment_id: i64, name: &str, size: u64, dir: PathBuf) -> io::Result<Self> {
+        // The name comes from another user, so it can't point outside of the directory
+        let name = Path::new(name)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("attachment")
+            .to_string();
+        fs::create_dir_all(&dir)?;
+        let file = File::create(dir.join(format!(".{name}.part")))?;
+        Ok(Self {
+            attachment_id,
+            name,
+            size,
+            received: 0,
+            dir,
+            file,
+            hasher: Sha256::new(),
+        })
+    }
+
+    pub fn is_received(&self) -> bool {
+        self.received >= self.size
+    }
+
+    pub fn write_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)?;
+        self.hasher.update(data);
+        self.received += data.len() as u64;
+        Ok(())
+    }
+
+    // Returns where the file has been saved, existing files are not overwritten
+    pub fn finish(self, sha256: &str) -> io::Result<PathBuf> {
+        let part_path = self.dir.join(format!(".{}.part", self.name));
+        if format!("{:x}", self.hasher.finalize()) != sha256 {
+            fs::remove_file(part_path)?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the checksum does not match",
+            ));
+        }
+        let mut path = self.dir.join(&self.name);
+        let mut copy = 1;
+        while path.exists() {
+            path = self.dir.join(format!("{copy}-{}", self.name));
+            copy += 1;
+        }
+        fs::rename(part_path, &path)?;
+        Ok(path)
+    }
+
+    pub fn cancel(self) {
+        fs::remove_file(self.dir.join(format!(".{}.part", self.name))).ok();
+    }
+}
+
+// Percentage of a transfer for the status line
+pub fn progress(done: u64, size: u64) -> u64 {
+    (done * 100).checked_div(size).unwrap_or(100)
+}
+
+// "~/file" is expanded like in the shell
+pub fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => Path::new(&home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
@@ -1,52 +1,83 @@
 use crate::{
     client::Client,
-    message::Message,
+    keymap::Action,
+    message::{Member, Message, Profile, Quote, Reaction, Thread},
     model::{ClientState, InputMode},
+    theme::Theme,
+    transfer::progress,
 };
 use tui::{
     backend::Backend,
     layout::{Constraint, Direction, Layout, Rect},
-    style::{Color, Modifier, Style},
+    style::{Modifier, Style},
     text::{Span, Spans},
-    widgets::{Block, BorderType, Borders, Clear, List, ListItem, Paragraph, Wrap},
+    widgets::{Block, BorderType, Borders, Clear, List, ListItem, ListState, Paragraph, Wrap},
     Frame,
 };
 use unicode_width::UnicodeWidthStr;
 
-const MIN_WIDTH: u16 = 80;
-const MIN_HEIGHT: u16 = 24;
+const MIN_WIDTH: u16 = 40;
+const MIN_HEIGHT: u16 = 12;
+// Smaller terminals get the compact layout
+const COMPACT_WIDTH: u16 = 80;
+const COMPACT_HEIGHT: u16 = 24;
+// Longer quotes of the replied message are cut
+const QUOTE_LENGTH: usize = 40;
+const SIDEBAR_WIDTH: u16 = 20;
+const COMMANDS: [(&str, &str); 5] = [
+    ("/upload <path> [caption]", "Send a file"),
+    (
+        "/download [directory]",
+        "Save the attachment of the message",
+    ),
+    ("/msg <user> <text>", "Send a direct message"),
+    ("/msg <user>", "Show the direct messages with the user"),
+    (
+        "/join <room>",
+        "Join a room, it is created if it does not exist",
+    ),
+];
 
 pub(crate) fn ui<B: Backend>(f: &mut Frame<B>, client: &mut Client) {
     let (w, h) = (f.size().width, f.size().height);
     if w < MIN_WIDTH || h < MIN_HEIGHT {
-        too_small_screen(f, w, h);
+        too_small_screen(f, w, h, &client.theme);
     } else {
         if let ClientState::LoggingIn = client.client_state {
             log_screen(f, client);
         } else {
             chat_screen(f, client);
         }
+        if client.help.is_some() {
+            help_screen(f, client);
+        }
+        if client.palette.is_some() {
+            palette_block(f, client);
+        }
+        if let Some(profile) = &client.profile {
+            profile_block(f, profile, client);
+        }
         if client.error_handler.is_some() {
             error_block(f, client);
         }
     }
 }
 
-fn too_small_screen<B: Backend>(f: &mut Frame<B>, w: u16, h: u16) {
+fn too_small_screen<B: Backend>(f: &mut Frame<B>, w: u16, h: u16, theme: &Theme) {
     let text = vec![
         Spans::from("Terminal size is too small:"),
         Spans::from(vec![
             Span::raw("Width = "),
-            Span::styled(format!("{}", w), Style::default().fg(Color::Green)),
+            Span::styled(format!("{}", w), Style::default().fg(theme.accent)),
             Span::raw(", height = "),
-            Span::styled(format!("{}", h), Style::default().fg(Color::Green)),
+            Span::styled(format!("{}", h), Style::default().fg(theme.accent)),
         ]),
         Spans::from("Needed for current user interface:"),
         Spans::from(vec![
             Span::raw("Width = "),
-            Span::styled(format!("{}", MIN_WIDTH), Style::default().fg(Color::Green)),
+            Span::styled(format!("{}", MIN_WIDTH), Style::default().fg(theme.accent)),
             Span::raw(", height = "),
-            Span::styled(format!("{}", MIN_HEIGHT), Style::default().fg(Color::Green)),
+            Span::styled(format!("{}", MIN_HEIGHT), Style::default().fg(theme.accent)),
         ]),
     ];
 
@@ -61,73 +92,450 @@ fn too_small_screen<B: Backend>(f: &mut Frame<B>, w: u16, h: u16) {
 
 fn log_screen<B: Backend>(f: &mut Frame<B>, client: &mut Client) {
     let block = input_block(client);
-    let area = centered_rect(50, 20, f.size());
+    let size = f.size();
+    let area = if is_compact(size) {
+        Rect::new(size.x, size.height.saturating_sub(3) / 2, size.width, 3)
+    } else {
+        centered_rect(50, 20, size)
+    };
     f.render_widget(Clear, area);
     f.render_widget(block, area);
-    set_cursor(f, client, area);
+    set_cursor(f, client, area.x + 1, area.y + 1);
 }
 
+fn is_compact(area: Rect) -> bool {
+    area.width < COMPACT_WIDTH || area.height < COMPACT_HEIGHT
+}
+
+// Conversations on the left, the shown one in the middle and its members on the right
 fn chat_screen<B: Backend>(f: &mut Frame<B>, client: &mut Client) {
+    if is_compact(f.size()) {
+        compact_chat_screen(f, client);
+        return;
+    }
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(4), Constraint::Length(1)].as_ref())
+        .split(f.size());
+    let members = client
+        .conversations
+        .get(client.focused)
+        .filter(|conversation| client.show_members && !conversation.direct)
+        .map(|conversation| &conversation.members);
+    let mut columns = vec![Constraint::Length(SIDEBAR_WIDTH), Constraint::Min(30)];
+    if members.is_some() {
+        columns.push(Constraint::Length(SIDEBAR_WIDTH));
+    }
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints(columns)
+        .split(rows[0]);
+
+    let mut state = ListState::default();
+    state.select(Some(client.focused));
+    f.render_stateful_widget(conversation_list(client), columns[0], &mut state);
+    if let Some(members) = members {
+        f.render_widget(member_list(members, &client.theme), columns[2]);
+    }
+
     let chunks = Layout::default()
         .direction(Direction::Vertical)
-        .margin(0)
-        .constraints([Constraint::Percentage(90), Constraint::Percentage(10)].as_ref())
+        .constraints([Constraint::Min(3), Constraint::Length(3)].as_ref())
+        .split(columns[1]);
+    let help_message = help_message(client);
+
+    let messages_area = if let Some(thread) = &client.thread {
+        let panes = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(60), Constraint::Percentage(40)].as_ref())
+            .split(chunks[0]);
+        let thread = thread_block(thread, client, false);
+        f.render_widget(thread, panes[1]);
+        panes[0]
+    } else {
+        chunks[0]
+    };
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .border_type(BorderType::Rounded)
+        .title(help_message);
+    render_messages(f, client, messages_area, block, false);
+
+    let input = input_block(client);
+    f.render_widget(input, chunks[1]);
+    set_cursor(f, client, chunks[1].x + 1, chunks[1].y + 1);
+
+    let status = status_line(client);
+    f.render_widget(status, rows[1]);
+}
+
+// Without the sidebars and borders the chat still fits into a 40×12 pane,
+// the thread is shown instead of the messages
+fn compact_chat_screen<B: Backend>(f: &mut Frame<B>, client: &mut Client) {
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(
+            [
+                Constraint::Length(1),
+                Constraint::Min(1),
+                Constraint::Length(1),
+                Constraint::Length(1),
+            ]
+            .as_ref(),
+        )
         .split(f.size());
 
-    let help_message = help_message(&client.input_mode);
+    f.render_widget(conversation_bar(client), rows[0]);
+    match &client.thread {
+        Some(thread) => {
+            let thread = thread_block(thread, client, true);
+            f.render_widget(thread, rows[1]);
+        }
+        None => render_messages(f, client, rows[1], Block::default(), true),
+    }
+    input_line(f, client, rows[2]);
+    f.render_widget(status_line(client), rows[3]);
+}
+
+fn render_messages<B: Backend>(
+    f: &mut Frame<B>,
+    client: &Client,
+    area: Rect,
+    block: Block,
+    compact: bool,
+) {
+    // Only the latest messages that fit are shown, or the ones from the selected message
+    // on. Replies take one more line for the quote and reactions one more under the message
+    let messages_limit = block.inner(area).height as usize;
+    let mut start = client.messages.len();
+    let mut height = 0;
+    while start > 0 && height + message_height(&client.messages[start - 1]) <= messages_limit {
+        start -= 1;
+        height += message_height(&client.messages[start]);
+    }
+    if let Some(selected) = client.selected {
+        start = start.min(selected);
+    }
+
+    let items = message_block(
+        &client.messages[start..],
+        &client.username,
+        &client.theme,
+        compact,
+    );
+    let messages = List::new(items)
+        .block(block)
+        .highlight_style(client.theme.selection_style());
+    let mut state = ListState::default();
+    state.select(client.selected.map(|index| index - start));
+
+    f.render_stateful_widget(messages, area, &mut state);
+}
+
+// The shown conversation and the ones with unread messages in a single line
+fn conversation_bar(client: &Client) -> Paragraph<'_> {
+    let spans: Vec<Span> = client
+        .conversations
+        .iter()
+        .enumerate()
+        .map(|(index, conversation)| {
+            let (text, style) = if index == client.focused {
+                let style = client.theme.selection_style().add_modifier(Modifier::BOLD);
+                (conversation.label(), style)
+            } else if conversation.unread > 0 {
+                // Muted conversations count their messages without standing out
+                let style = if client.notifiers.is_muted(&conversation.label()) {
+                    Style::default().fg(client.theme.muted)
+                } else {
+                    Style::default()
+                        .add_modifier(Modifier::BOLD)
+                        .fg(client.theme.highlight)
+                };
+                (
+                    format!("{} ({})", conversation.label(), conversation.unread),
+                    style,
+                )
+            } else {
+                (
+                    conversation.label(),
+                    Style::default().fg(client.theme.muted),
+                )
+            };
+            Span::styled(format!("{text} "), style)
+        })
+        .collect();
+    Paragraph::new(Spans::from(spans))
+}
+
+// The input after a short prompt that tells what Enter does
+fn input_line<B: Backend>(f: &mut Frame<B>, client: &mut Client, area: Rect) {
+    let prompt = match &client.replying_to {
+        _ if client.editing.is_some() => "edit> ".to_string(),
+        _ if client.reacting_to.is_some() => "react> ".to_string(),
+        Some((_, quote)) => format!("↳ {}> ", quote.sender),
+        None => "> ".to_string(),
+    };
+    let style = match client.input_mode {
+        InputMode::Insert if client.error_handler.is_none() => {
+            Style::default().fg(client.theme.highlight)
+        }
+        _ => Style::default(),
+    };
+    let x = area.x + prompt.width() as u16;
+    let line = Spans::from(vec![
+        Span::styled(prompt, Style::default().fg(client.theme.muted)),
+        Span::styled(client.input.clone(), style),
+    ]);
+    f.render_widget(Paragraph::new(line), area);
+    set_cursor(f, client, x, area.y);
+}
+
+// Rooms and direct messages numbered for Alt+1..9, the ones with unread messages are highlighted
+fn conversation_list(client: &Client) -> List<'_> {
+    let items: Vec<ListItem> = client
+        .conversations
+        .iter()
+        .enumerate()
+        .map(|(index, conversation)| {
+            let number = match index {
+                0..=8 => format!("{} ", index + 1),
+                _ => "  ".to_string(),
+            };
+            let (text, style) = if index == client.focused {
+                let style = Style::default().add_modifier(Modifier::BOLD);
+                (conversation.label(), style)
+            } else if conversation.unread > 0 {
+                let style = if client.notifiers.is_muted(&conversation.label()) {
+                    Style::default().fg(client.theme.muted)
+                } else {
+                    Style::default()
+                        .add_modifier(Modifier::BOLD)
+                        .fg(client.theme.highlight)
+                };
+                (
+                    format!("{} ({})", conversation.label(), conversation.unread),
+                    style,
+                )
+            } else {
+                (
+                    conversation.label(),
+                    Style::default().fg(client.theme.muted),
+                )
+            };
+            ListItem::new(Spans::from(vec![
+                Span::styled(number, Style::default().fg(client.theme.muted)),
+                Span::styled(text, style),
+            ]))
+        })
+        .collect();
+    List::new(items)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_type(BorderType::Rounded)
+                .title(conversation_keys(client)),
+        )
+        .highlight_style(client.theme.selection_style())
+}
 
-    let messages = client.messages.clone();
-    let messages = List::new(message_block(&messages)).block(
+// Keys of the next and the previous conversation, e.g. " Ctrl+N/Ctrl+P "
+fn conversation_keys(client: &Client) -> Vec<Span<'static>> {
+    let actions = [Action::NextConversation, Action::PreviousConversation];
+    let keys: Vec<String> = actions
+        .iter()
+        .filter_map(|action| client.keymap.hint(*action, client.input_mode))
+        .collect();
+    if keys.is_empty() {
+        return Vec::new();
+    }
+    vec![
+        Span::raw(" "),
+        Span::styled(
+            keys.join("/"),
+            Style::default().add_modifier(Modifier::BOLD),
+        ),
+        Span::raw(" "),
+    ]
+}
+
+// Online members first, both groups sorted by name like the server sends them
+fn member_list<'a>(members: &'a [Member], theme: &Theme) -> List<'a> {
+    let (online, offline): (Vec<&Member>, Vec<&Member>) =
+        members.iter().partition(|member| member.online);
+    let items: Vec<ListItem> = online
+        .into_iter()
+        .chain(offline)
+        .map(|member| {
+            let (dot, color) = if member.online {
+                ("● ", theme.accent)
+            } else {
+                ("○ ", theme.muted)
+            };
+            ListItem::new(Spans::from(vec![
+                Span::styled(dot, Style::default().fg(color)),
+                Span::styled(
+                    member.username.as_str(),
+                    Style::default().fg(theme.sender(&member.username)),
+                ),
+            ]))
+        })
+        .collect();
+    List::new(items).block(
         Block::default()
             .borders(Borders::ALL)
             .border_type(BorderType::Rounded)
-            .title(help_message),
-    );
+            .title(format!(" Members ({}) ", members.len())),
+    )
+}
 
-    let messages_limit = chunks[0].height - 2;
-    if client.messages.len() > messages_limit as usize {
-        client.messages.remove(0);
+fn thread_block<'a>(thread: &'a Thread, client: &Client, compact: bool) -> List<'a> {
+    let messages = std::iter::once(&thread.parent)
+        .chain(thread.replies.iter())
+        .map(|message| format_message(message, &client.username, &client.theme, compact))
+        .collect::<Vec<_>>();
+    if compact {
+        return List::new(messages);
     }
-    f.render_widget(messages, chunks[0]);
-
-    let input = input_block(client);
-    f.render_widget(input, chunks[1]);
-    set_cursor(f, client, chunks[1]);
+    let close = client.keymap.hint(Action::Thread, client.input_mode);
+    let mut title = vec![Span::raw(" Thread")];
+    title.extend(key_hint(", press ", close, " to close"));
+    title.push(Span::raw(" "));
+    List::new(messages).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded)
+            .title(title),
+    )
 }
 
-fn help_message(input_mode: &InputMode) -> Vec<Span> {
-    match input_mode {
-        InputMode::Normal => vec![
-            Span::raw(" Press "),
-            Span::styled("q", Style::default().add_modifier(Modifier::BOLD)),
-            Span::raw(" to exit, "),
-            Span::styled("i", Style::default().add_modifier(Modifier::BOLD)),
-            Span::raw(" to enter the insert mode"),
-        ],
-        InputMode::Insert => vec![
-            Span::raw(" Press "),
-            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
-            Span::raw(" to enter the normal mode, "),
-            Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
-            Span::raw(" to send a message"),
+// The key between the texts, or nothing when the action has no key in the mode
+fn key_hint(before: &'static str, key: Option<String>, after: &'static str) -> Vec<Span<'static>> {
+    match key {
+        Some(key) => vec![
+            Span::raw(before),
+            Span::styled(key, Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(after),
         ],
+        None => Vec::new(),
     }
 }
 
-fn message_block(messages: &[Message]) -> Vec<ListItem> {
-    messages.iter().map(format_message).collect()
+fn status_line(client: &Client) -> Paragraph<'_> {
+    let latency = client
+        .latency
+        .map(|latency| format!("{} ms", latency.as_millis()))
+        .unwrap_or_else(|| "...".to_string());
+    let mut spans = vec![
+        Span::styled(
+            format!(" {} ", client.username),
+            Style::default().add_modifier(Modifier::BOLD),
+        ),
+        Span::raw("| Latency: "),
+        Span::styled(latency, Style::default().fg(client.theme.accent)),
+    ];
+    if let Some(upload) = &client.upload {
+        spans.push(Span::raw(format!(
+            " | Uploading {} {}%",
+            upload.name,
+            progress(upload.sent, upload.size)
+        )));
+    }
+    if let Some(download) = &client.download {
+        spans.push(Span::raw(format!(
+            " | Downloading {} {}%",
+            download.name,
+            progress(download.received, download.size)
+        )));
+    }
+    Paragraph::new(Spans::from(spans))
 }
 
-fn input_block(client: &mut Client) -> Paragraph {
-    let needed_input = if let ClientState::LoggedIn = client.client_state {
-        "message"
-    } else {
-        "username"
+// Hints for the current mode, with the keys of the active keymap
+fn help_message(client: &Client) -> Vec<Span<'static>> {
+    let mode = client.input_mode;
+    let (prefix, hints): (&str, &[(&[Action], &str)]) = match mode {
+        InputMode::Normal if client.selected.is_some() => (
+            " ",
+            &[
+                (&[Action::SelectNext, Action::SelectPrevious], "move"),
+                (&[Action::Copy], "copy"),
+                (&[Action::Reply], "reply"),
+                (&[Action::React], "react"),
+                (&[Action::Edit, Action::Delete], "edit/delete"),
+                (&[Action::Profile], "profile"),
+                (&[Action::Cancel], "unselect"),
+            ],
+        ),
+        InputMode::Normal => (
+            " Press ",
+            &[
+                (&[Action::Quit], "to exit"),
+                (&[Action::Help], "for help"),
+                (&[Action::InsertMode], "to insert"),
+                (&[Action::SelectPrevious], "to select a message"),
+                (&[Action::Thread], "for threads"),
+                (&[Action::Members], "for members"),
+            ],
+        ),
+        InputMode::Insert => (
+            " Press ",
+            &[
+                (&[Action::NormalMode], "to enter the normal mode"),
+                (&[Action::Send], "to send a message"),
+                (&[Action::Quit], "to exit"),
+                (&[Action::Help], "for help"),
+            ],
+        ),
+    };
+    let mut spans = vec![Span::raw(prefix)];
+    for (actions, text) in hints {
+        // Actions without a key in this mode are left out
+        let keys: Option<Vec<String>> = actions
+            .iter()
+            .map(|action| client.keymap.hint(*action, mode))
+            .collect();
+        let keys = match keys {
+            Some(keys) => keys.join("/"),
+            None => continue,
+        };
+        if spans.len() > 1 {
+            spans.push(Span::raw(", "));
+        }
+        spans.push(Span::styled(
+            keys,
+            Style::default().add_modifier(Modifier::BOLD),
+        ));
+        spans.push(Span::raw(format!(" {text}")));
+    }
+    spans
+}
+
+fn message_block<'a>(
+    messages: &'a [Message],
+    username: &str,
+    theme: &Theme,
+    compact: bool,
+) -> Vec<ListItem<'a>> {
+    messages
+        .iter()
+        .map(|message| format_message(message, username, theme, compact))
+        .collect()
+}
+
+fn input_block(client: &mut Client) -> Paragraph<'_> {
+    let title = match (&client.client_state, &client.replying_to) {
+        (ClientState::LoggedIn, _) if client.editing.is_some() => " Edit the message".to_string(),
+        (ClientState::LoggedIn, _) if client.reacting_to.is_some() => {
+            " React with an emoji".to_string()
+        }
+        (ClientState::LoggedIn, Some((_, quote))) => format!(" Reply to {}", quote.sender),
+        (ClientState::LoggedIn, None) => " Enter the message".to_string(),
+        (ClientState::LoggingIn, _) => " Enter the username".to_string(),
     };
     Paragraph::new(client.input.as_ref())
         .style(match client.input_mode {
             InputMode::Insert if client.error_handler.is_none() => {
-                Style::default().fg(Color::Yellow)
+                Style::default().fg(client.theme.highlight)
             }
             _ => Style::default(),
         })
@@ -135,63 +543,334 @@ fn input_block(client: &mut Client) -> Paragraph {
             Block::default()
                 .borders(Borders::ALL)
                 .border_type(BorderType::Rounded)
-                .title(format!(" Enter the {}", needed_input)),
+                .title(title),
         )
 }
 
+fn profile_block<B: Backend>(f: &mut Frame<B>, profile: &Profile, client: &Client) {
+    let theme = &client.theme;
+    let (status, color) = if profile.online {
+        ("online", theme.accent)
+    } else {
+        ("offline", theme.muted)
+    };
+    let last_seen = match (&profile.last_seen, profile.online) {
+        (_, true) => "now",
+        (Some(last_seen), false) => last_seen.as_str(),
+        (None, false) => "never",
+    };
+    let text = vec![
+        Spans::from(vec![
+            Span::raw("Status: "),
+            Span::styled(status, Style::default().fg(color)),
+        ]),
+        Spans::from(format!("Messages: {}", profile.messages)),
+        Spans::from(format!(
+            "First seen: {}",
+            profile.first_seen.as_deref().unwrap_or("never")
+        )),
+        Spans::from(format!("Last seen: {}", last_seen)),
+    ];
+    let close = client.keymap.hint(Action::Cancel, client.input_mode);
+    let mut title = vec![Span::styled(
+        format!(" {}", profile.username),
+        Style::default().add_modifier(Modifier::BOLD),
+    )];
+    title.extend(key_hint(" Press ", close, " to close"));
+    let block = Paragraph::new(text).block(
+        Block::default()
+            .title(title)
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded),
+    );
+    let area = popup_rect(35, 30, f.size());
+    f.render_widget(Clear, area);
+    f.render_widget(block, area);
+}
+
+// Every key of the keymap and every command, over the whole screen
+fn help_screen<B: Backend>(f: &mut Frame<B>, client: &mut Client) {
+    let bold = Style::default().add_modifier(Modifier::BOLD);
+    let keys: Vec<(String, &str)> = Action::ALL
+        .into_iter()
+        .map(|action| (client.keymap.keys(action).join(", "), action.description()))
+        .filter(|(keys, _)| !keys.is_empty())
+        .chain([(
+            "Alt+1..9".to_string(),
+            "Show the conversation with that number",
+        )])
+        .collect();
+    let width = keys
+        .iter()
+        .map(|(keys, _)| keys.width())
+        .max()
+        .unwrap_or_default()
+        + 2;
+    let mut lines = vec![Spans::from(Span::styled("Keys", bold))];
+    for (keys, description) in keys {
+        lines.push(Spans::from(vec![
+            Span::styled(
+                format!("{keys:<width$}"),
+                Style::default().fg(client.theme.highlight),
+            ),
+            Span::raw(description),
+        ]));
+    }
+    lines.push(Spans::from(Span::styled(
+        "Keys without Ctrl or Alt only work in the normal mode",
+        Style::default().fg(client.theme.muted),
+    )));
+    lines.push(Spans::default());
+    lines.push(Spans::from(Span::styled("Commands", bold)));
+    let width = COMMANDS
+        .iter()
+        .map(|(command, _)| command.width())
+        .max()
+        .unwrap_or_default()
+        + 2;
+    for (command, description) in COMMANDS {
+        lines.push(Spans::from(vec![
+            Span::styled(
+                format!("{command:<width$}"),
+                Style::default().fg(client.theme.highlight),
+            ),
+            Span::raw(description),
+        ]));
+    }
+
+    let block = Block::default()
+        .title(vec![
+            Span::styled(" Help", bold),
+            Span::raw(", press "),
+            Span::styled("↑/↓", bold),
+            Span::raw(" to scroll, "),
+            Span::styled("Esc", bold),
+            Span::raw(" to close "),
+        ])
+        .borders(Borders::ALL)
+        .border_type(BorderType::Rounded);
+    let area = f.size();
+    let max_scroll = lines
+        .len()
+        .saturating_sub(block.inner(area).height as usize) as u16;
+    let scroll = client.help.unwrap_or_default().min(max_scroll);
+    client.help = Some(scroll);
+    f.render_widget(Clear, area);
+    f.render_widget(Paragraph::new(lines).block(block).scroll((scroll, 0)), area);
+}
+
+fn palette_block<B: Backend>(f: &mut Frame<B>, client: &mut Client) {
+    let entries = client.palette_entries();
+    let palette = match &client.palette {
+        Some(palette) => palette,
+        None => return,
+    };
+    let muted = Style::default().fg(client.theme.muted);
+    let block = Block::default()
+        .title(" Search actions, rooms and users ")
+        .borders(Borders::ALL)
+        .border_type(BorderType::Rounded);
+    let area = popup_rect(50, 50, f.size());
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(1), Constraint::Min(0)])
+        .split(block.inner(area));
+    let items: Vec<ListItem> = entries
+        .iter()
+        .map(|entry| {
+            ListItem::new(Spans::from(vec![
+                Span::raw(entry.label.as_str()),
+                Span::styled(format!("  {}", entry.detail), muted),
+            ]))
+        })
+        .collect();
+    let mut state = ListState::default();
+    if !entries.is_empty() {
+        state.select(Some(palette.selected.min(entries.len() - 1)));
+    }
+    f.render_widget(Clear, area);
+    f.render_widget(block, area);
+    f.render_widget(
+        Paragraph::new(Spans::from(vec![
+            Span::styled("> ", muted),
+            Span::raw(palette.query.as_str()),
+        ])),
+        rows[0],
+    );
+    f.render_stateful_widget(
+        List::new(items).highlight_style(client.theme.selection_style()),
+        rows[1],
+        &mut state,
+    );
+    if client.error_handler.is_none() {
+        f.set_cursor(rows[0].x + 2 + palette.query.width() as u16, rows[0].y);
+    }
+}
+
 fn error_block<B: Backend>(f: &mut Frame<B>, client: &mut Client) {
     let error_message = client.error_handler.as_ref().unwrap();
+    // The popup takes every key, so the key of the normal mode closes it in both modes
+    let close = client
+        .keymap
+        .hint(Action::Cancel, InputMode::Normal)
+        .unwrap_or_else(|| "q".to_string());
+    let mut title = vec![Span::styled(
+        " Error!",
+        Style::default()
+            .fg(client.theme.error)
+            .add_modifier(Modifier::BOLD),
+    )];
+    title.extend(key_hint(" Press ", Some(close), " to continue"));
     let block = Paragraph::new(error_message.as_ref())
         .block(
             Block::default()
-                .title(vec![
-                    Span::styled(
-                        " Error!",
-                        Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
-                    ),
-                    Span::raw(" Press "),
-                    Span::styled("q", Style::default().add_modifier(Modifier::BOLD)),
-                    Span::raw(" to continue"),
-                ])
+                .title(title)
                 .borders(Borders::ALL)
                 .border_type(BorderType::Rounded),
         )
         .wrap(Wrap { trim: true });
-    let area = centered_rect(35, 10, f.size());
+    let area = popup_rect(35, 10, f.size());
+    f.render_widget(Clear, area);
     f.render_widget(block, area);
 }
 
-fn set_cursor<B: Backend>(f: &mut Frame<B>, client: &mut Client, area: Rect) {
+// The input starts at `x`
+fn set_cursor<B: Backend>(f: &mut Frame<B>, client: &mut Client, x: u16, y: u16) {
     if let InputMode::Insert = client.input_mode {
-        if client.error_handler.is_none() {
-            f.set_cursor(area.x + client.input.width() as u16 + 1, area.y + 1);
+        if client.error_handler.is_none() && client.help.is_none() {
+            f.set_cursor(x + client.input.width() as u16, y);
         }
     }
 }
 
-fn format_message(message: &Message) -> ListItem {
-    let date = Span::styled(
-        format!("[{}] ", message.date),
+fn message_height(message: &Message) -> usize {
+    1 + message.parent.is_some() as usize + has_reactions(message) as usize
+}
+
+// Reactions of deleted messages are not shown
+fn has_reactions(message: &Message) -> bool {
+    !message.deleted && !message.reactions.is_empty()
+}
+
+// Counts like "👍 2  🎉 1", the user's own reactions are highlighted
+fn format_reactions<'a>(reactions: &'a [Reaction], username: &str, theme: &Theme) -> Spans<'a> {
+    let mut spans = vec![Span::raw("  ")];
+    for reaction in reactions {
+        let style = if reaction.users.iter().any(|user| user == username) {
+            Style::default()
+                .add_modifier(Modifier::BOLD)
+                .fg(theme.highlight)
+        } else {
+            Style::default().fg(theme.muted)
+        };
+        spans.push(Span::styled(
+            format!("{} {}", reaction.emoji, reaction.users.len()),
+            style,
+        ));
+        spans.push(Span::raw("  "));
+    }
+    Spans::from(spans)
+}
+
+fn format_quote<'a>(quote: &'a Quote, theme: &Theme) -> Spans<'a> {
+    let mut data: String = quote.data.chars().take(QUOTE_LENGTH).collect();
+    if quote.data.chars().count() > QUOTE_LENGTH {
+        data.push('…');
+    } else if quote.data.is_empty() {
+        data.push_str("message deleted");
+    }
+    Spans::from(Span::styled(
+        format!("  ┌ [{}] {}", quote.sender, data),
         Style::default()
-            .add_modifier(Modifier::BOLD)
-            .fg(Color::Rgb(216, 222, 233)),
+            .add_modifier(Modifier::ITALIC)
+            .fg(theme.muted),
+    ))
+}
+
+// Messages of others that mention the user are highlighted, compact ones only show the time
+fn format_message<'a>(
+    message: &'a Message,
+    username: &str,
+    theme: &Theme,
+    compact: bool,
+) -> ListItem<'a> {
+    let mut lines: Vec<Spans<'_>> = message
+        .parent
+        .iter()
+        .map(|quote| format_quote(quote, theme))
+        .collect();
+    let date = match message.date.split_once(' ') {
+        Some((_, time)) if compact => time,
+        _ => &message.date,
+    };
+    let date = Span::styled(
+        format!("[{date}] "),
+        Style::default().add_modifier(Modifier::BOLD).fg(theme.text),
     );
-    let sender = message.sender.clone();
+    let sender = match (&message.sender, &message.recipient) {
+        (Some(sender), Some(recipient)) => Some(format!("[{sender} → {recipient}] ")),
+        (Some(sender), None) => Some(format!("[{}] ", sender)),
+        _ => None,
+    };
     let sender = sender
         .map(|sender| {
+            let color = theme.sender(message.sender.as_deref().unwrap_or_default());
             Span::styled(
-                format!("[{}] ", sender),
-                Style::default()
-                    .add_modifier(Modifier::BOLD)
-                    .fg(Color::Rgb(129, 161, 193)),
+                sender,
+                Style::default().add_modifier(Modifier::BOLD).fg(color),
             )
         })
         .unwrap_or_else(|| Span::raw(""));
-    let data = Span::styled(
-        &message.data,
-        Style::default().fg(Color::Rgb(216, 222, 233)),
-    );
-    ListItem::new(vec![Spans::from(vec![date, sender, data])])
+    if message.deleted {
+        let tombstone = Span::styled(
+            "message deleted",
+            Style::default()
+                .add_modifier(Modifier::ITALIC)
+                .fg(theme.muted),
+        );
+        lines.push(Spans::from(vec![date, sender, tombstone]));
+        return ListItem::new(lines);
+    }
+    let data_style = if message.sender.as_deref() != Some(username) && message.mentions(username) {
+        Style::default()
+            .add_modifier(Modifier::BOLD)
+            .fg(theme.mention)
+    } else {
+        Style::default().fg(theme.text)
+    };
+    let data = Span::styled(&message.data, data_style);
+    let mut spans = vec![date, sender, data];
+    if let Some(attachment) = &message.attachment {
+        spans.push(Span::styled(
+            format!(" 📎 {} ({})", attachment.name, format_size(attachment.size)),
+            Style::default().fg(theme.attachment),
+        ));
+    }
+    if message.edited {
+        spans.push(Span::styled(" (edited)", Style::default().fg(theme.muted)));
+    }
+    lines.push(Spans::from(spans));
+    if has_reactions(message) {
+        lines.push(format_reactions(&message.reactions, username, theme));
+    }
+    ListItem::new(lines)
+}
+
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{bytes} B"),
+        1024..=1_048_575 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / 1_048_576.0),
+    }
+}
+
+// Popups take most of a compact screen
+fn popup_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
+    if is_compact(r) {
+        centered_rect(90, 80, r)
+    } else {
+        centered_rect(percent_x, percent_y, r)
+    }
 }
 
 fn centered_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
@@ -0,0 +1,158 @@
+//! Connects thousands of simulated clients to a running server and measures how
+//! fast messages are fanned out to all of them.
+//!
+//! cargo run --release --example load_test -- [connections] [senders] [messages]
+
+use futures::{SinkExt, StreamExt};
+use serde_json::json;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::sync::{mpsc, Barrier};
+use tokio_util::codec::{Framed, LinesCodec};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+const SERVER_ADDRESS: &str = "127.0.0.1:8080";
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+struct Report {
+    received: usize,
+    expected: usize,
+    finished: Option<Instant>,
+}
+
+fn arg(n: usize, default: usize) -> usize {
+    std::env::args()
+        .nth(n)
+        .and_then(|arg| arg.parse().ok())
+        .unwrap_or(default)
+}
+
+async fn connect(id: usize) -> Result<Framed<TcpStream, LinesCodec>> {
+    // Every client gets its own loopback address so that the per-IP limits of
+    // the server are not hit (works on Linux, where all of 127.0.0.0/8 is local)
+    let ip = Ipv4Addr::new(127, 0, (id / 250) as u8, (id % 250 + 1) as u8);
+    let socket = TcpSocket::new_v4()?;
+    socket.bind(SocketAddr::from((ip, 0)))?;
+    let stream = socket.connect(SERVER_ADDRESS.parse()?).await?;
+
+    let mut lines = Framed::new(stream, LinesCodec::new());
+    let request =
+        json!({ "type": "request_c2s", "method": "LogInUsername", "body": format!("load{id}") });
+    lines.send(request.to_string()).await?;
+    match lines.next().await {
+        Some(Ok(response)) if response.contains("\"status_code\":200") => Ok(lines),
+        Some(Ok(response)) => Err(format!("load{id} could not log in: {response}").into()),
+        _ => Err(format!("load{id} was disconnected while logging in").into()),
+    }
+}
+
+async fn simulate_client(
+    mut lines: Framed<TcpStream, LinesCodec>,
+    messages: usize,
+    expected: usize,
+    start: Arc<Barrier>,
+) -> Report {
+    start.wait().await;
+    for i in 0..messages {
+        let request = json!({ "type": "request_c2s", "method": "SendMessage", "body": format!("message {i}") });
+        if lines.send(request.to_string()).await.is_err() {
+            break;
+        }
+    }
+
+    let mut received = 0;
+    let deadline = tokio::time::sleep(TIMEOUT);
+    tokio::pin!(deadline);
+    while received < expected {
+        tokio::select! {
+            line = lines.next() => match line {
+                // Parsing every line would make the load test itself the bottleneck
+                Some(Ok(line)) => {
+                    if line.contains(r#""method":"SendMessage""#) {
+                        received += 1;
+                    }
+                }
+                _ => break,
+            },
+            _ = &mut deadline => break,
+        }
+    }
+
+    Report {
+        received,
+        expected,
+        finished: (received == expected).then(Instant::now),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let connections = arg(1, 2000);
+    let senders = arg(2, 20).min(connections);
+    let messages = arg(3, 5);
+
+    println!("Connecting {connections} clients...");
+    let connect_start = Instant::now();
+    let mut handles = Vec::with_capacity(connections);
+    for id in 0..connections {
+        handles.push(tokio::spawn(connect(id)));
+    }
+    let mut clients = Vec::with_capacity(connections);
+    for handle in handles {
+        clients.push(handle.await??);
+    }
+    println!("Connected in {:.2?}", connect_start.elapsed());
+
+    let start = Arc::new(Barrier::new(connections + 1));
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    for (id, lines) in clients.into_iter().enumerate() {
+        let (messages, expected) = if id < senders {
+            (messages, (senders - 1) * messages)
+        } else {
+            (0, senders * messages)
+        };
+        let start = Arc::clone(&start);
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            let report = simulate_client(lines, messages, expected, start).await;
+            tx.send(report).ok();
+        });
+    }
+    drop(tx);
+
+    start.wait().await;
+    let fan_out_start = Instant::now();
+    let mut latencies = Vec::with_capacity(connections);
+    let (mut received, mut expected) = (0, 0);
+    while let Some(report) = rx.recv().await {
+        received += report.received;
+        expected += report.expected;
+        if let Some(finished) = report.finished {
+            latencies.push(finished.duration_since(fan_out_start));
+        }
+    }
+    let elapsed = fan_out_start.elapsed();
+    latencies.sort();
+
+    println!(
+        "Delivered {received}/{expected} messages in {elapsed:.2?} ({:.0} messages/s)",
+        received as f64 / elapsed.as_secs_f64()
+    );
+    if !latencies.is_empty() {
+        let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+        println!(
+            "Time until a client received everything: p50 {:.2?}, p99 {:.2?}, max {:.2?}",
+            percentile(50),
+            percentile(99),
+            percentile(100)
+        );
+    }
+    println!(
+        "{}/{connections} clients received all messages",
+        latencies.len()
+    );
+    Ok(())
+}
@@ -1,4 +1,4 @@
 create table if not exists users (
   id bigserial primary key,
   username text unique not null
-);
\ No newline at end of file
+);
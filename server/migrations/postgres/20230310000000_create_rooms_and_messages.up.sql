@@ -0,0 +1,14 @@
+create table if not exists rooms (
+  id bigserial primary key,
+  name text unique not null
+);
+
+create table if not exists messages (
+  id bigserial primary key,
+  room_id bigint not null references rooms(id) on delete cascade,
+  sender_id bigint not null references users(id) on delete cascade,
+  body text not null,
+  created_at timestamptz not null default now()
+);
+
+create index if not exists messages_room_id_idx on messages(room_id, id);
@@ -0,0 +1,7 @@
+create table if not exists sessions (
+  id bigserial primary key,
+  user_id bigint not null references users(id) on delete cascade,
+  address text not null,
+  connected_at timestamptz not null default now(),
+  disconnected_at timestamptz
+);
@@ -0,0 +1,3 @@
+drop table if exists message_edits;
+alter table messages drop column if exists deleted_at;
+alter table messages drop column if exists edited_at;
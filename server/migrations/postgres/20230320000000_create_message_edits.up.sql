@@ -0,0 +1,12 @@
+alter table messages add column if not exists edited_at timestamptz;
+alter table messages add column if not exists deleted_at timestamptz;
+
+create table if not exists message_edits (
+  id bigserial primary key,
+  message_id bigint not null references messages(id) on delete cascade,
+  editor_id bigint not null references users(id) on delete cascade,
+  previous_body text not null,
+  edited_at timestamptz not null default now()
+);
+
+create index if not exists message_edits_message_id_idx on message_edits(message_id, id);
@@ -0,0 +1,3 @@
+alter table messages add column if not exists parent_id bigint references messages(id) on delete set null;
+
+create index if not exists messages_parent_id_idx on messages(parent_id, id);
@@ -0,0 +1,2 @@
+alter table messages drop column if exists attachment_id;
+drop table if exists attachments;
@@ -0,0 +1,10 @@
+create table if not exists attachments (
+  id bigserial primary key,
+  uploader_id bigint not null references users(id) on delete cascade,
+  name text not null,
+  size bigint not null,
+  sha256 text not null,
+  created_at timestamptz not null default now()
+);
+
+alter table messages add column if not exists attachment_id bigint references attachments(id) on delete set null;
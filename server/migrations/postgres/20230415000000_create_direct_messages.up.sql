@@ -0,0 +1,11 @@
+create table if not exists direct_messages (
+  id bigserial primary key,
+  sender_id bigint not null references users(id) on delete cascade,
+  recipient_id bigint not null references users(id) on delete cascade,
+  body text not null,
+  created_at timestamptz not null default now(),
+  delivered_at timestamptz
+);
+
+create index if not exists direct_messages_undelivered_idx on direct_messages(recipient_id, id)
+  where delivered_at is null;
@@ -0,0 +1,2 @@
+alter table users drop column token_sha256;
+alter table users drop column normalized_username;
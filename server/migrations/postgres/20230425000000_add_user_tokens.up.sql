@@ -0,0 +1,12 @@
+alter table users add column normalized_username text;
+alter table users add column token_sha256 text;
+
+-- Names that only differ in case belong to the oldest user, the newer ones can't
+-- be logged into anymore
+update users set normalized_username = lower(username)
+where id = (select min(id) from users u where lower(u.username) = lower(users.username));
+update users set normalized_username = lower(username) || '#' || id
+where normalized_username is null;
+
+alter table users alter column normalized_username set not null;
+create unique index if not exists users_normalized_username_idx on users(normalized_username);
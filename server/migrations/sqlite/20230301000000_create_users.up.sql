@@ -0,0 +1,4 @@
+create table if not exists users (
+  id integer primary key,
+  username text unique not null
+);
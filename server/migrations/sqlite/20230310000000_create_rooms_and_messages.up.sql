@@ -0,0 +1,14 @@
+create table if not exists rooms (
+  id integer primary key,
+  name text unique not null
+);
+
+create table if not exists messages (
+  id integer primary key,
+  room_id integer not null references rooms(id) on delete cascade,
+  sender_id integer not null references users(id) on delete cascade,
+  body text not null,
+  created_at text not null
+);
+
+create index if not exists messages_room_id_idx on messages(room_id, id);
@@ -0,0 +1,7 @@
+create table if not exists sessions (
+  id integer primary key,
+  user_id integer not null references users(id) on delete cascade,
+  address text not null,
+  connected_at text not null,
+  disconnected_at text
+);
@@ -0,0 +1,3 @@
+drop table if exists message_edits;
+alter table messages drop column deleted_at;
+alter table messages drop column edited_at;
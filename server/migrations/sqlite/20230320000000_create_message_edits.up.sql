@@ -0,0 +1,12 @@
+alter table messages add column edited_at text;
+alter table messages add column deleted_at text;
+
+create table if not exists message_edits (
+  id integer primary key,
+  message_id integer not null references messages(id) on delete cascade,
+  editor_id integer not null references users(id) on delete cascade,
+  previous_body text not null,
+  edited_at text not null
+);
+
+create index if not exists message_edits_message_id_idx on message_edits(message_id, id);
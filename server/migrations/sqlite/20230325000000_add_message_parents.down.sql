@@ -0,0 +1,2 @@
+drop index if exists messages_parent_id_idx;
+alter table messages drop column parent_id;
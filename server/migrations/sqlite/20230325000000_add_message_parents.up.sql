@@ -0,0 +1,3 @@
+alter table messages add column parent_id integer references messages(id) on delete set null;
+
+create index if not exists messages_parent_id_idx on messages(parent_id, id);
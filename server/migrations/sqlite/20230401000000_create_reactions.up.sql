@@ -0,0 +1,7 @@
+create table if not exists reactions (
+  message_id integer not null references messages(id) on delete cascade,
+  user_id integer not null references users(id) on delete cascade,
+  emoji text not null,
+  created_at text not null,
+  primary key (message_id, user_id, emoji)
+);
@@ -0,0 +1,10 @@
+create table if not exists attachments (
+  id integer primary key,
+  uploader_id integer not null references users(id) on delete cascade,
+  name text not null,
+  size integer not null,
+  sha256 text not null,
+  created_at text not null
+);
+
+alter table messages add column attachment_id integer references attachments(id) on delete set null;
@@ -0,0 +1,2 @@
+drop index if exists direct_messages_undelivered_idx;
+drop table if exists direct_messages;
@@ -0,0 +1,11 @@
+create table if not exists direct_messages (
+  id integer primary key,
+  sender_id integer not null references users(id) on delete cascade,
+  recipient_id integer not null references users(id) on delete cascade,
+  body text not null,
+  created_at text not null,
+  delivered_at text
+);
+
+create index if not exists direct_messages_undelivered_idx on direct_messages(recipient_id, id)
+  where delivered_at is null;
@@ -0,0 +1,2 @@
+drop table if exists direct_message_reads;
+drop table if exists room_members;
@@ -0,0 +1,13 @@
+create table if not exists room_members (
+  room_id integer not null references rooms(id) on delete cascade,
+  user_id integer not null references users(id) on delete cascade,
+  last_read_id integer not null default 0,
+  primary key (room_id, user_id)
+);
+
+create table if not exists direct_message_reads (
+  user_id integer not null references users(id) on delete cascade,
+  peer_id integer not null references users(id) on delete cascade,
+  last_read_id integer not null default 0,
+  primary key (user_id, peer_id)
+);
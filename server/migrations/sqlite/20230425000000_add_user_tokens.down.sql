@@ -0,0 +1,3 @@
+drop index if exists users_normalized_username_idx;
+alter table users drop column token_sha256;
+alter table users drop column normalized_username;
@@ -1,21 +1,26 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
-use tokio::sync::{mpsc, Mutex};
+use crate::config::SlowConsumerPolicy;
+use crate::db::User;
+use crate::queue;
+use crate::registry::Registry;
+use std::net::SocketAddr;
 
 pub struct Client {
-    pub username: String,
+    pub user: User,
     pub addr: SocketAddr,
-    pub rx: mpsc::UnboundedReceiver<String>,
+    pub rx: queue::Receiver,
 }
 
 impl Client {
-    pub async fn new(
-        clients: &Arc<Mutex<HashMap<SocketAddr, mpsc::UnboundedSender<String>>>>,
-        username: String,
+    pub fn new(
+        clients: &Registry,
+        user: User,
         addr: SocketAddr,
+        capacity: usize,
+        policy: SlowConsumerPolicy,
     ) -> Self {
-        let (tx, rx) = mpsc::unbounded_channel();
-        clients.lock().await.insert(addr, tx);
+        let (tx, rx) = queue::bounded(capacity, policy);
+        clients.insert(addr, tx);
 
-        Self { username, addr, rx }
+        Self { user, addr, rx }
     }
 }
@@ -1,42 +1,454 @@
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::LazyLock;
+use std::time::Duration;
+use regex::Regex;
+use serde::Deserialize;
+use unicode_segmentation::UnicodeSegmentation;
 use crate::Result;
 
-#[derive(Clone)]
+const CONFIG_PATH: &str = "config.toml";
+
+// Invisible characters like U+202E, which shows the text after it reversed, or
+// joiners that make two names look the same
+static FORMAT_CHARS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\p{Cf}").unwrap());
+// Messages keep the joiners and tags that emoji sequences and some scripts need
+static HIDDEN_MESSAGE_CHARS: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"[\p{Cf}--[\x{200C}\x{200D}\x{E0020}-\x{E007F}]]").unwrap());
+
+#[derive(Clone, Deserialize)]
+pub struct Moderator {
+    pub username: String,
+    // SHA-256 checksum of the token in hex, e.g. from `printf %s <token> | sha256sum`
+    pub token_sha256: String,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct RateLimit {
+    #[serde(with = "positive")]
+    pub messages_per_second: f64,
+    #[serde(with = "positive")]
+    pub messages_burst: f64,
+    #[serde(with = "positive")]
+    pub bytes_per_second: f64,
+    #[serde(with = "positive")]
+    pub bytes_burst: f64,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    DropOldest,
+    DropNewest,
+    Disconnect,
+}
+
+// `database` connects to `DATABASE_URL`, `memory` keeps everything in memory
+// until the server stops
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageKind {
+    Database,
+    Memory,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateLoginPolicy {
+    Reject,
+    TakeOver,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub server_address: String,
+    pub storage: StorageKind,
+    #[serde(with = "positive")]
+    pub max_connections: usize,
+    #[serde(with = "positive")]
+    pub max_connections_per_ip: usize,
     pub min_username_len: usize,
     pub max_username_len: usize,
+    #[serde(with = "pattern")]
+    pub username_pattern: Regex,
+    pub reserved_usernames: Vec<String>,
+    // Moderators can edit and delete the messages of other users, their names
+    // are reserved for logins with their token
+    pub moderators: Vec<Moderator>,
     pub min_message_len: usize,
     pub max_message_len: usize,
+    #[serde(with = "optional_pattern")]
+    pub message_pattern: Option<Regex>,
+    // Uploaded files are kept in this directory, named by their SHA-256 checksum
+    pub upload_dir: PathBuf,
+    pub max_upload_size: u64,
+    // Stored files and uploads in progress together, uploads that don't fit are rejected
+    pub max_upload_dir_size: u64,
+    // Decoded bytes in one `UploadChunk` or `DownloadChunk`
+    pub max_chunk_size: usize,
+    pub max_uploads_per_client: usize,
+    pub duplicate_login_policy: DuplicateLoginPolicy,
+    pub user_rate_limit: RateLimit,
+    pub ip_rate_limit: RateLimit,
+    // Uploaded and downloaded chunks wait for these limits instead of being rejected
+    pub user_transfer_limit: RateLimit,
+    pub ip_transfer_limit: RateLimit,
+    pub max_rate_limit_violations: usize,
+    // Logins with a wrong token from one IP, after that its logins are refused
+    // until one of them is forgiven
+    #[serde(with = "positive")]
+    pub max_failed_logins: usize,
+    #[serde(with = "seconds")]
+    pub failed_login_decay: Duration,
+    // One violation of the rate limit is forgiven after this many seconds
+    #[serde(with = "seconds")]
+    pub violation_decay: Duration,
+    // Without room for one message every policy would drop everything
+    #[serde(with = "positive")]
+    pub client_queue_capacity: usize,
+    pub slow_consumer_policy: SlowConsumerPolicy,
+    #[serde(with = "seconds")]
+    pub stats_interval: Duration,
+    #[serde(with = "seconds")]
+    pub heartbeat_interval: Duration,
+    pub max_missed_heartbeats: u32,
+    #[serde(with = "seconds")]
+    pub login_timeout: Duration,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             server_address: "0.0.0.0:8080".to_string(),
-            // TODO: Add regex for username and message to avoid invalid data
+            storage: StorageKind::Database,
+            max_connections: 1024,
+            max_connections_per_ip: 16,
             min_username_len: 1,
             max_username_len: 20,
+            username_pattern: Regex::new(r"^[\p{L}\p{M}\p{N}_.-]+$").unwrap(),
+            reserved_usernames: ["server", "admin", "administrator", "moderator", "system", "root"]
+                .map(String::from)
+                .to_vec(),
+            moderators: Vec::new(),
             min_message_len: 1,
             max_message_len: 256,
+            message_pattern: None,
+            upload_dir: PathBuf::from("uploads"),
+            max_upload_size: 10 * 1024 * 1024,
+            max_upload_dir_size: 1024 * 1024 * 1024,
+            max_chunk_size: 64 * 1024,
+            max_uploads_per_client: 2,
+            duplicate_login_policy: DuplicateLoginPolicy::Reject,
+            user_rate_limit: RateLimit {
+                messages_per_second: 2.0,
+                messages_burst: 5.0,
+                bytes_per_second: 512.0,
+                bytes_burst: 2048.0,
+            },
+            // Several users may share the same IP (e.g. behind NAT)
+            ip_rate_limit: RateLimit {
+                messages_per_second: 10.0,
+                messages_burst: 20.0,
+                bytes_per_second: 4096.0,
+                bytes_burst: 16384.0,
+            },
+            // A burst fits a few chunks of `max_chunk_size` in base64
+            user_transfer_limit: RateLimit {
+                messages_per_second: 20.0,
+                messages_burst: 40.0,
+                bytes_per_second: 1024.0 * 1024.0,
+                bytes_burst: 2.0 * 1024.0 * 1024.0,
+            },
+            ip_transfer_limit: RateLimit {
+                messages_per_second: 50.0,
+                messages_burst: 100.0,
+                bytes_per_second: 4.0 * 1024.0 * 1024.0,
+                bytes_burst: 8.0 * 1024.0 * 1024.0,
+            },
+            max_rate_limit_violations: 10,
+            max_failed_logins: 5,
+            failed_login_decay: Duration::from_secs(60),
+            violation_decay: Duration::from_secs(10),
+            client_queue_capacity: 256,
+            slow_consumer_policy: SlowConsumerPolicy::DropOldest,
+            stats_interval: Duration::from_secs(60),
+            heartbeat_interval: Duration::from_secs(15),
+            max_missed_heartbeats: 3,
+            login_timeout: Duration::from_secs(60),
         }
     }
 }
 
 impl Config {
+    /// Reads the config from `CONFIG_PATH` (or the file set by `SERVER_CONFIG`),
+    /// missing fields and a missing file fall back to the defaults.
+    pub fn load() -> Result<Self> {
+        let path = std::env::var("SERVER_CONFIG").unwrap_or_else(|_| CONFIG_PATH.to_string());
+        match std::fs::read_to_string(&path) {
+            Ok(content) => toml::from_str(&content)
+                .map_err(|e| format!("Could not parse the config file {path}: {e}").into()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(format!("Could not read the config file {path}: {e}").into()),
+        }
+    }
+
+    // Lengths are counted in graphemes, so "é" is one character whether it is
+    // one code point or two
     pub fn is_valid_username(&self, username: Option<&str>, client_addr: SocketAddr) -> Result<bool> {
         if let Some(username) = username {
-            Ok((self.min_username_len..=self.max_username_len).contains(&username.len()))
+            let len = username.graphemes(true).count();
+            Ok((self.min_username_len..=self.max_username_len).contains(&len)
+                && !FORMAT_CHARS.is_match(username)
+                && self.username_pattern.is_match(username))
         } else {
             Err(format!("Invalid request from {}", client_addr).into())
         }
     }
 
+    // The names of moderators are reserved too, unless the checksum of the token
+    // the user logs in with is the one of the moderator
+    pub fn is_reserved_username(&self, username: &str, token_sha256: Option<&str>) -> bool {
+        let username = username.to_lowercase();
+        self.reserved_usernames
+            .iter()
+            .any(|reserved| reserved.to_lowercase() == username)
+            || self.moderators.iter().any(|moderator| {
+                moderator.username.to_lowercase() == username
+                    && token_sha256.is_none_or(|token_sha256| {
+                        !moderator.token_sha256.eq_ignore_ascii_case(token_sha256)
+                    })
+            })
+    }
+
+    // Only the token of a moderator logs in with the name, see `is_reserved_username`
+    pub fn is_moderator(&self, username: &str) -> bool {
+        let username = username.to_lowercase();
+        self.moderators
+            .iter()
+            .any(|moderator| moderator.username.to_lowercase() == username)
+    }
+
+    // Control characters are never allowed: escape sequences would be
+    // interpreted by the terminals of the other users
     pub fn is_valid_message(&self, message: Option<&str>, client_addr: SocketAddr) -> Result<bool> {
         if let Some(message) = message {
-            Ok((self.min_message_len..=self.max_message_len).contains(&message.trim().len()))
+            let message = message.trim();
+            let len = message.graphemes(true).count();
+            Ok((self.min_message_len..=self.max_message_len).contains(&len)
+                && !message.chars().any(char::is_control)
+                && !HIDDEN_MESSAGE_CHARS.is_match(message)
+                && self
+                    .message_pattern
+                    .as_ref()
+                    .is_none_or(|pattern| pattern.is_match(message)))
         } else {
             Err(format!("Invalid request from {}", client_addr).into())
         }
     }
+
+    // A reaction is a single emoji (which may be several code points),
+    // letters and digits are not accepted
+    pub fn is_valid_reaction(&self, emoji: Option<&str>, client_addr: SocketAddr) -> Result<bool> {
+        if let Some(emoji) = emoji {
+            Ok(emoji.graphemes(true).count() == 1
+                && !emoji
+                    .chars()
+                    .any(|c| c.is_alphanumeric() || c.is_whitespace() || c.is_control()))
+        } else {
+            Err(format!("Invalid request from {}", client_addr).into())
+        }
+    }
+}
+
+// Intervals and timeouts must be positive, a zero interval would make tokio panic
+mod seconds {
+    use serde::{de::Error, Deserialize, Deserializer};
+    use std::time::Duration;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let seconds = f64::deserialize(deserializer)?;
+        match Duration::try_from_secs_f64(seconds) {
+            Ok(duration) if !duration.is_zero() => Ok(duration),
+            _ => Err(D::Error::custom(format!(
+                "expected a positive number of seconds, found {seconds}"
+            ))),
+        }
+    }
+}
+
+// Zero would stall the server or divide by zero, e.g. in the wait time of a rate limit
+mod positive {
+    use serde::{de::Error, Deserialize, Deserializer};
+    use std::fmt::Display;
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: Deserialize<'de> + Default + PartialOrd + Display,
+    {
+        let value = T::deserialize(deserializer)?;
+        if value > T::default() {
+            Ok(value)
+        } else {
+            Err(D::Error::custom(format!(
+                "expected a positive number, found {value}"
+            )))
+        }
+    }
+}
+
+mod pattern {
+    use regex::Regex;
+    use serde::{de::Error, Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Regex, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Regex::new(&pattern).map_err(D::Error::custom)
+    }
+}
+
+mod optional_pattern {
+    use regex::Regex;
+    use serde::Deserializer;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Regex>, D::Error> {
+        super::pattern::deserialize(deserializer).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:1".parse().unwrap()
+    }
+
+    #[test]
+    fn usernames_are_letters_digits_and_some_punctuation() {
+        let config = Config::default();
+        let valid = |name: &str| config.is_valid_username(Some(name), addr()).unwrap();
+        assert!(valid("alice"));
+        assert!(valid("Zoë_1.b-c"));
+        assert!(valid("日本"));
+        assert!(!valid(""));
+        assert!(!valid("alice bob"));
+        assert!(!valid("alice!"));
+        assert!(!valid(&"a".repeat(21)));
+        assert!(config.is_valid_username(None, addr()).is_err());
+    }
+
+    #[test]
+    fn usernames_reject_invisible_characters_even_if_the_pattern_allows_them() {
+        let config = Config {
+            username_pattern: Regex::new(".+").unwrap(),
+            ..Config::default()
+        };
+        let valid = |name: &str| config.is_valid_username(Some(name), addr()).unwrap();
+        assert!(valid("alice"));
+        assert!(!valid("al\u{202E}ice"));
+        assert!(!valid("al\u{200D}ice"));
+        assert!(!valid("\u{FEFF}alice"));
+    }
+
+    #[test]
+    fn messages_reject_control_and_bidi_characters() {
+        let config = Config::default();
+        let valid = |message: &str| config.is_valid_message(Some(message), addr()).unwrap();
+        assert!(valid(" hello "));
+        // A family emoji and a flag are joined by ZWJ and tag characters
+        assert!(valid("\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}"));
+        assert!(valid(
+            "\u{1F3F4}\u{E0067}\u{E0062}\u{E0073}\u{E0063}\u{E0074}\u{E007F}"
+        ));
+        assert!(!valid("   "));
+        assert!(!valid("a\nb"));
+        assert!(!valid("gnp.\u{202E}exe"));
+        assert!(!valid("a\u{2066}b\u{2069}"));
+        assert!(!valid(&"a".repeat(257)));
+    }
+
+    #[test]
+    fn moderator_names_need_the_token_of_the_moderator() {
+        let config = Config {
+            moderators: vec![Moderator {
+                username: "Carol".to_string(),
+                token_sha256: "ABC123".to_string(),
+            }],
+            ..Config::default()
+        };
+        assert!(config.is_reserved_username("carol", None));
+        assert!(config.is_reserved_username("CAROL", Some("def456")));
+        assert!(!config.is_reserved_username("carol", Some("abc123")));
+        assert!(config.is_reserved_username("Admin", Some("abc123")));
+        assert!(!config.is_reserved_username("alice", None));
+        assert!(config.is_moderator("carol"));
+        assert!(!config.is_moderator("alice"));
+    }
+
+    #[test]
+    fn reactions_are_a_single_emoji() {
+        let config = Config::default();
+        let valid = |emoji: &str| config.is_valid_reaction(Some(emoji), addr()).unwrap();
+        assert!(valid("👍"));
+        assert!(valid("\u{1F44D}\u{1F3FD}"));
+        assert!(!valid("👍👍"));
+        assert!(!valid("a"));
+        assert!(!valid(" "));
+    }
+
+    #[test]
+    fn durations_are_read_in_seconds() {
+        let config: Config =
+            toml::from_str("heartbeat_interval = 0.5\nlogin_timeout = 30").unwrap();
+        assert_eq!(config.heartbeat_interval, Duration::from_millis(500));
+        assert_eq!(config.login_timeout, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn durations_must_be_positive() {
+        for value in ["0", "0.0", "-1", "nan", "inf", "1e300"] {
+            let content = format!("stats_interval = {value}");
+            assert!(
+                toml::from_str::<Config>(&content).is_err(),
+                "stats_interval = {value} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn connection_limits_must_be_positive() {
+        assert!(toml::from_str::<Config>("max_connections = 0").is_err());
+        assert!(toml::from_str::<Config>("max_connections_per_ip = 0").is_err());
+        let config: Config = toml::from_str("max_connections_per_ip = 1").unwrap();
+        assert_eq!(config.max_connections_per_ip, 1);
+    }
+
+    #[test]
+    fn client_queues_must_hold_a_message() {
+        let config: Config = toml::from_str("client_queue_capacity = 1").unwrap();
+        assert_eq!(config.client_queue_capacity, 1);
+        assert!(toml::from_str::<Config>("client_queue_capacity = 0").is_err());
+    }
+
+    #[test]
+    fn rate_limits_must_be_positive() {
+        let limit = |messages_per_second| {
+            format!(
+                "ip_rate_limit = {{ messages_per_second = {messages_per_second}, \
+                 messages_burst = 20, bytes_per_second = 4096, bytes_burst = 16384 }}"
+            )
+        };
+        let config: Config = toml::from_str(&limit("0.5")).unwrap();
+        assert_eq!(config.ip_rate_limit.messages_per_second, 0.5);
+        for value in ["0", "-1", "nan"] {
+            assert!(
+                toml::from_str::<Config>(&limit(value)).is_err(),
+                "messages_per_second = {value} was accepted"
+            );
+        }
+    }
 }
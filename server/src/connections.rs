@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct Connections {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+pub struct ConnectionLimiter {
+    max_connections: usize,
+    max_connections_per_ip: usize,
+    connections: Mutex<Connections>,
+}
+
+// Holds a connection slot until the connection is closed
+pub struct ConnectionGuard {
+    limiter: Arc<ConnectionLimiter>,
+    ip: IpAddr,
+}
+
+#[derive(Debug)]
+pub enum Rejection {
+    ServerFull(usize),
+    TooManyFromIp(usize),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ServerFull(max) => write!(f, "the server is full ({max} connections)"),
+            Self::TooManyFromIp(max) => write!(f, "too many connections from this IP ({max})"),
+        }
+    }
+}
+
+impl Rejection {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::ServerFull(_) => "MaxConnections",
+            Self::TooManyFromIp(_) => "MaxConnectionsPerIp",
+        }
+    }
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_connections: usize, max_connections_per_ip: usize) -> Self {
+        Self {
+            max_connections,
+            max_connections_per_ip,
+            connections: Mutex::new(Connections::default()),
+        }
+    }
+
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Result<ConnectionGuard, Rejection> {
+        let mut connections = self.connections.lock().unwrap();
+        if connections.total >= self.max_connections {
+            return Err(Rejection::ServerFull(self.max_connections));
+        }
+        // Checked before the entry is added, so rejected IPs don't leave one behind
+        let from_ip = connections.per_ip.get(&ip).copied().unwrap_or_default();
+        if from_ip >= self.max_connections_per_ip {
+            return Err(Rejection::TooManyFromIp(self.max_connections_per_ip));
+        }
+        *connections.per_ip.entry(ip).or_default() += 1;
+        connections.total += 1;
+
+        Ok(ConnectionGuard {
+            limiter: Arc::clone(self),
+            ip,
+        })
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut connections = self.limiter.connections.lock().unwrap();
+        connections.total -= 1;
+        if let Some(from_ip) = connections.per_ip.get_mut(&self.ip) {
+            *from_ip -= 1;
+            if *from_ip == 0 {
+                connections.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([127, 0, 0, last])
+    }
+
+    #[test]
+    fn the_server_takes_at_most_max_connections() {
+        let limiter = Arc::new(ConnectionLimiter::new(2, 2));
+        let _first = limiter.acquire(ip(1)).unwrap();
+        let _second = limiter.acquire(ip(2)).unwrap();
+        let rejection = limiter.acquire(ip(3)).err().unwrap();
+        assert!(matches!(rejection, Rejection::ServerFull(2)));
+        assert_eq!(rejection.reason(), "MaxConnections");
+    }
+
+    #[test]
+    fn each_ip_takes_at_most_max_connections_per_ip() {
+        let limiter = Arc::new(ConnectionLimiter::new(10, 2));
+        let _first = limiter.acquire(ip(1)).unwrap();
+        let _second = limiter.acquire(ip(1)).unwrap();
+        let rejection = limiter.acquire(ip(1)).err().unwrap();
+        assert!(matches!(rejection, Rejection::TooManyFromIp(2)));
+        assert_eq!(rejection.reason(), "MaxConnectionsPerIp");
+        assert!(limiter.acquire(ip(2)).is_ok());
+    }
+
+    #[test]
+    fn closed_connections_release_their_slot() {
+        let limiter = Arc::new(ConnectionLimiter::new(1, 1));
+        let first = limiter.acquire(ip(1)).unwrap();
+        assert!(limiter.acquire(ip(2)).is_err());
+        drop(first);
+        let second = limiter.acquire(ip(2)).unwrap();
+        drop(second);
+        let connections = limiter.connections.lock().unwrap();
+        assert_eq!(connections.total, 0);
+        assert!(connections.per_ip.is_empty());
+    }
+
+    #[test]
+    fn rejected_ips_leave_no_entry() {
+        let limiter = Arc::new(ConnectionLimiter::new(10, 0));
+        assert!(limiter.acquire(ip(1)).is_err());
+        assert!(limiter.connections.lock().unwrap().per_ip.is_empty());
+    }
+}
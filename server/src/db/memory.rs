@@ -0,0 +1,491 @@
+use super::{
+    Attachment, DirectMessage, Message, MigrationStatus, Profile, Reaction, Storage, Unread, User,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::migrate::MigrateError;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+#[derive(Default)]
+struct Tables {
+    // Users by their normalized name, with their id, username and the checksum of their token
+    users: HashMap<String, (i64, String, Option<String>)>,
+    rooms: HashMap<String, i64>,
+    messages: Vec<Message>,
+    attachments: Vec<Attachment>,
+    // Previous bodies with the ids of the message and of the editor
+    message_edits: Vec<(i64, i64, String)>,
+    // Direct messages with the id of the recipient and whether they have been delivered
+    direct_messages: Vec<(DirectMessage, i64, bool)>,
+    // Room and user ids of the members with the id of the last message they have read
+    room_members: Vec<(i64, i64, i64)>,
+    // The last direct message each user has read, by the ids of the user and of the sender
+    direct_message_reads: HashMap<(i64, i64), i64>,
+    // Message ids with the username and the emoji of the reaction
+    reactions: Vec<(i64, String, String)>,
+    // Usernames with the time each session was opened and closed, `None` while it is open
+    sessions: Vec<(String, DateTime<Utc>, Option<DateTime<Utc>>)>,
+}
+
+impl Tables {
+    fn last_message_id(&self, room_id: i64) -> i64 {
+        self.messages
+            .iter()
+            .filter(|message| message.room_id == room_id)
+            .map(|message| message.id)
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn message_mut(&mut self, id: i64) -> Option<&mut Message> {
+        let index = usize::try_from(id - 1).ok()?;
+        self.messages.get_mut(index)
+    }
+}
+
+// Keeps everything in the process memory, nothing survives a restart.
+// Ids start at 1 and follow the insertion order like in the SQL backends
+#[derive(Default)]
+pub struct MemoryStorage {
+    tables: Mutex<Tables>,
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    // There is no schema, so there is nothing to migrate
+    async fn migrate(&self) -> Result<(), MigrateError> {
+        Ok(())
+    }
+
+    async fn undo_last_migration(&self) -> Result<Option<i64>, MigrateError> {
+        Ok(None)
+    }
+
+    async fn migration_status(&self) -> Result<Vec<MigrationStatus>, MigrateError> {
+        Ok(Vec::new())
+    }
+
+    async fn add_user(&self, username: &str) -> Result<User, sqlx::Error> {
+        let mut tables = self.tables.lock().unwrap();
+        let next_id = tables.users.len() as i64 + 1;
+        let (id, username, token_sha256) = tables
+            .users
+            .entry(super::normalize(username))
+            .or_insert_with(|| (next_id, username.to_string(), None));
+        Ok(User {
+            id: *id,
+            username: username.clone(),
+            claimed: token_sha256.is_some(),
+        })
+    }
+
+    async fn get_user(&self, username: &str) -> Result<Option<User>, sqlx::Error> {
+        let tables = self.tables.lock().unwrap();
+        Ok(tables
+            .users
+            .get(&super::normalize(username))
+            .map(|(id, username, token_sha256)| User {
+                id: *id,
+                username: username.clone(),
+                claimed: token_sha256.is_some(),
+            }))
+    }
+
+    async fn claim_user(&self, user: &User, token_sha256: &str) -> Result<bool, sqlx::Error> {
+        let mut tables = self.tables.lock().unwrap();
+        let (.., stored) = tables
+            .users
+            .values_mut()
+            .find(|(id, ..)| *id == user.id)
+            .ok_or(sqlx::Error::RowNotFound)?;
+        Ok(stored.get_or_insert_with(|| token_sha256.to_string()) == token_sha256)
+    }
+
+    async fn token_matches(&self, user: &User, token_sha256: &str) -> Result<bool, sqlx::Error> {
+        let tables = self.tables.lock().unwrap();
+        Ok(tables
+            .users
+            .values()
+            .any(|(id, _, stored)| *id == user.id && stored.as_deref() == Some(token_sha256)))
+    }
+
+    async fn add_room(&self, name: &str) -> Result<i64, sqlx::Error> {
+        let mut tables = self.tables.lock().unwrap();
+        let next_id = tables.rooms.len() as i64 + 1;
+        Ok(*tables.rooms.entry(name.to_string()).or_insert(next_id))
+    }
+
+    async fn room_name(&self, room_id: i64) -> Result<Option<String>, sqlx::Error> {
+        let tables = self.tables.lock().unwrap();
+        Ok(tables
+            .rooms
+            .iter()
+            .find(|(_, id)| **id == room_id)
+            .map(|(name, _)| name.clone()))
+    }
+
+    async fn join_room(&self, room_id: i64, user: &User) -> Result<(), sqlx::Error> {
+        let mut tables = self.tables.lock().unwrap();
+        let joined = tables
+            .room_members
+            .iter()
+            .any(|(room, member, _)| *room == room_id && *member == user.id);
+        if !joined {
+            let last_read_id = tables.last_message_id(room_id);
+            tables.room_members.push((room_id, user.id, last_read_id));
+        }
+        Ok(())
+    }
+
+    async fn is_member(&self, room_id: i64, user: &User) -> Result<bool, sqlx::Error> {
+        let tables = self.tables.lock().unwrap();
+        Ok(tables
+            .room_members
+            .iter()
+            .any(|(room, member, _)| *room == room_id && *member == user.id))
+    }
+
+    async fn room_members(&self, room_id: i64) -> Result<Vec<String>, sqlx::Error> {
+        let tables = self.tables.lock().unwrap();
+        let mut members: Vec<String> = tables
+            .users
+            .values()
+            .filter(|(id, ..)| {
+                tables
+                    .room_members
+                    .iter()
+                    .any(|(room, member, _)| *room == room_id && member == id)
+            })
+            .map(|(_, username, _)| username.clone())
+            .collect();
+        members.sort();
+        Ok(members)
+    }
+
+    async fn mark_read(&self, room_id: i64, user: &User) -> Result<(), sqlx::Error> {
+        let mut tables = self.tables.lock().unwrap();
+        let last_id = tables.last_message_id(room_id);
+        for (room, member, last_read_id) in tables.room_members.iter_mut() {
+            if *room == room_id && *member == user.id {
+                *last_read_id = last_id;
+            }
+        }
+        Ok(())
+    }
+
+    async fn mark_direct_read(&self, user: &User, peer: &User) -> Result<(), sqlx::Error> {
+        let mut tables = self.tables.lock().unwrap();
+        let last_id = tables
+            .direct_messages
+            .iter()
+            .filter(|(message, recipient_id, _)| {
+                *recipient_id == user.id && message.sender == peer.username
+            })
+            .map(|(message, ..)| message.id)
+            .max()
+            .unwrap_or_default();
+        tables
+            .direct_message_reads
+            .insert((user.id, peer.id), last_id);
+        Ok(())
+    }
+
+    async fn unread(&self, user: &User) -> Result<Vec<Unread>, sqlx::Error> {
+        let tables = self.tables.lock().unwrap();
+        let mut unread: Vec<Unread> = tables
+            .room_members
+            .iter()
+            .filter(|(_, member, _)| *member == user.id)
+            .map(|(room_id, _, last_read_id)| Unread {
+                room_id: Some(*room_id),
+                name: tables
+                    .rooms
+                    .iter()
+                    .find(|(_, id)| *id == room_id)
+                    .map(|(name, _)| name.clone())
+                    .unwrap_or_default(),
+                count: tables
+                    .messages
+                    .iter()
+                    .filter(|message| {
+                        message.room_id == *room_id
+                            && message.id > *last_read_id
+                            && message.sender != user.username
+                            && message.deleted_at.is_none()
+                    })
+                    .count() as i64,
+            })
+            .collect();
+        unread.sort_by_key(|unread| unread.room_id);
+
+        let mut direct: Vec<Unread> = Vec::new();
+        for (message, recipient_id, _) in &tables.direct_messages {
+            let sender_id = tables
+                .users
+                .get(&super::normalize(&message.sender))
+                .map(|(id, ..)| *id);
+            let last_read_id = sender_id
+                .and_then(|sender_id| tables.direct_message_reads.get(&(user.id, sender_id)))
+                .copied()
+                .unwrap_or_default();
+            if *recipient_id != user.id || message.id <= last_read_id {
+                continue;
+            }
+            match direct
+                .iter_mut()
+                .find(|unread| unread.name == message.sender)
+            {
+                Some(unread) => unread.count += 1,
+                None => direct.push(Unread {
+                    room_id: None,
+                    name: message.sender.clone(),
+                    count: 1,
+                }),
+            }
+        }
+        direct.sort_by(|a, b| a.name.cmp(&b.name));
+        unread.extend(direct);
+        Ok(unread)
+    }
+
+    async fn add_message(
+        &self,
+        room_id: i64,
+        sender: &User,
+        body: &str,
+        parent_id: Option<i64>,
+        attachment: Option<&Attachment>,
+    ) -> Result<Message, sqlx::Error> {
+        let mut tables = self.tables.lock().unwrap();
+        let message = Message {
+            id: tables.messages.len() as i64 + 1,
+            room_id,
+            parent_id,
+            sender: sender.username.clone(),
+            body: body.to_string(),
+            created_at: Utc::now(),
+            edited_at: None,
+            deleted_at: None,
+            attachment_id: attachment.map(|attachment| attachment.id),
+            attachment_name: attachment.map(|attachment| attachment.name.clone()),
+            attachment_size: attachment.map(|attachment| attachment.size),
+        };
+        tables.messages.push(message.clone());
+        Ok(message)
+    }
+
+    async fn get_message(&self, id: i64) -> Result<Option<Message>, sqlx::Error> {
+        let mut tables = self.tables.lock().unwrap();
+        Ok(tables.message_mut(id).map(|message| message.clone()))
+    }
+
+    async fn replies(&self, parent_id: i64) -> Result<Vec<Message>, sqlx::Error> {
+        let tables = self.tables.lock().unwrap();
+        Ok(tables
+            .messages
+            .iter()
+            .filter(|message| message.parent_id == Some(parent_id))
+            .cloned()
+            .collect())
+    }
+
+    async fn edit_message(
+        &self,
+        id: i64,
+        editor: &User,
+        body: &str,
+    ) -> Result<Message, sqlx::Error> {
+        let mut tables = self.tables.lock().unwrap();
+        let message = tables.message_mut(id).ok_or(sqlx::Error::RowNotFound)?;
+        let previous_body = std::mem::replace(&mut message.body, body.to_string());
+        message.edited_at = Some(Utc::now());
+        let message = message.clone();
+        tables.message_edits.push((id, editor.id, previous_body));
+        Ok(message)
+    }
+
+    async fn delete_message(&self, id: i64) -> Result<(), sqlx::Error> {
+        let mut tables = self.tables.lock().unwrap();
+        if let Some(message) = tables.message_mut(id) {
+            message.deleted_at = Some(Utc::now());
+        }
+        Ok(())
+    }
+
+    async fn add_reaction(
+        &self,
+        message_id: i64,
+        user: &User,
+        emoji: &str,
+    ) -> Result<(), sqlx::Error> {
+        let mut tables = self.tables.lock().unwrap();
+        let reaction = (message_id, user.username.clone(), emoji.to_string());
+        if !tables.reactions.contains(&reaction) {
+            tables.reactions.push(reaction);
+        }
+        Ok(())
+    }
+
+    async fn remove_reaction(
+        &self,
+        message_id: i64,
+        user: &User,
+        emoji: &str,
+    ) -> Result<(), sqlx::Error> {
+        let mut tables = self.tables.lock().unwrap();
+        tables.reactions.retain(|(id, username, reaction)| {
+            !(*id == message_id && *username == user.username && reaction == emoji)
+        });
+        Ok(())
+    }
+
+    async fn reactions(&self, message_id: i64) -> Result<Vec<Reaction>, sqlx::Error> {
+        let tables = self.tables.lock().unwrap();
+        let rows = tables
+            .reactions
+            .iter()
+            .filter(|(id, _, _)| *id == message_id)
+            .map(|(_, username, emoji)| (emoji.clone(), username.clone()))
+            .collect();
+        Ok(super::group_reactions(rows))
+    }
+
+    async fn add_attachment(
+        &self,
+        _uploader: &User,
+        name: &str,
+        size: i64,
+        sha256: &str,
+    ) -> Result<Attachment, sqlx::Error> {
+        let mut tables = self.tables.lock().unwrap();
+        let attachment = Attachment {
+            id: tables.attachments.len() as i64 + 1,
+            name: name.to_string(),
+            size,
+            sha256: sha256.to_string(),
+        };
+        tables.attachments.push(attachment.clone());
+        Ok(attachment)
+    }
+
+    async fn get_attachment(&self, id: i64) -> Result<Option<Attachment>, sqlx::Error> {
+        let tables = self.tables.lock().unwrap();
+        let index = usize::try_from(id - 1).ok();
+        Ok(index.and_then(|index| tables.attachments.get(index).cloned()))
+    }
+
+    async fn attachment_room(&self, attachment_id: i64) -> Result<Option<i64>, sqlx::Error> {
+        let tables = self.tables.lock().unwrap();
+        Ok(tables
+            .messages
+            .iter()
+            .find(|message| message.attachment_id == Some(attachment_id))
+            .map(|message| message.room_id))
+    }
+
+    async fn add_direct_message(
+        &self,
+        sender: &User,
+        recipient: &User,
+        body: &str,
+    ) -> Result<DirectMessage, sqlx::Error> {
+        let mut tables = self.tables.lock().unwrap();
+        let message = DirectMessage {
+            id: tables.direct_messages.len() as i64 + 1,
+            sender: sender.username.clone(),
+            recipient: recipient.username.clone(),
+            body: body.to_string(),
+            created_at: Utc::now(),
+        };
+        tables
+            .direct_messages
+            .push((message.clone(), recipient.id, false));
+        Ok(message)
+    }
+
+    async fn undelivered_direct_messages(
+        &self,
+        recipient: &User,
+    ) -> Result<Vec<DirectMessage>, sqlx::Error> {
+        let tables = self.tables.lock().unwrap();
+        Ok(tables
+            .direct_messages
+            .iter()
+            .filter(|(_, recipient_id, delivered)| *recipient_id == recipient.id && !delivered)
+            .map(|(message, ..)| message.clone())
+            .collect())
+    }
+
+    async fn mark_delivered(&self, recipient: &User, last_id: i64) -> Result<(), sqlx::Error> {
+        let mut tables = self.tables.lock().unwrap();
+        for (message, recipient_id, delivered) in tables.direct_messages.iter_mut() {
+            if *recipient_id == recipient.id && message.id <= last_id {
+                *delivered = true;
+            }
+        }
+        Ok(())
+    }
+
+    async fn profile(&self, username: &str) -> Result<Option<Profile>, sqlx::Error> {
+        let tables = self.tables.lock().unwrap();
+        let Some((_, username, _)) = tables.users.get(&super::normalize(username)) else {
+            return Ok(None);
+        };
+        let sessions = tables.sessions.iter().filter(|(user, ..)| user == username);
+        Ok(Some(Profile {
+            username: username.to_string(),
+            messages: tables
+                .messages
+                .iter()
+                .filter(|message| message.sender == *username && message.deleted_at.is_none())
+                .count() as i64,
+            first_seen: sessions
+                .clone()
+                .map(|(_, connected_at, _)| *connected_at)
+                .min(),
+            last_seen: sessions
+                .filter_map(|(.., disconnected_at)| *disconnected_at)
+                .max(),
+        }))
+    }
+
+    async fn open_session(&self, user: &User, _address: &str) -> Result<i64, sqlx::Error> {
+        let mut tables = self.tables.lock().unwrap();
+        tables
+            .sessions
+            .push((user.username.clone(), Utc::now(), None));
+        Ok(tables.sessions.len() as i64)
+    }
+
+    async fn close_stale_sessions(&self) -> Result<u64, sqlx::Error> {
+        let mut tables = self.tables.lock().unwrap();
+        let now = Utc::now();
+        let mut closed = 0;
+        for (.., disconnected_at) in tables.sessions.iter_mut().filter(|(.., at)| at.is_none()) {
+            *disconnected_at = Some(now);
+            closed += 1;
+        }
+        Ok(closed)
+    }
+
+    async fn close_session(&self, session_id: i64) -> Result<(), sqlx::Error> {
+        let mut tables = self.tables.lock().unwrap();
+        let (.., disconnected_at) = usize::try_from(session_id - 1)
+            .ok()
+            .and_then(|index| tables.sessions.get_mut(index))
+            .ok_or(sqlx::Error::RowNotFound)?;
+        *disconnected_at = Some(Utc::now());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryStorage;
+
+    crate::db::suite::storage_tests! {
+        #[tokio::test]
+        async fn () { MemoryStorage::default() }
+    }
+}
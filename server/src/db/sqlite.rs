@@ -0,0 +1,671 @@
+use super::{
+    Attachment, DirectMessage, Message, MigrationStatus, Profile, Reaction, Storage, Unread, User,
+};
+use async_trait::async_trait;
+use chrono::Utc;
+use sqlx::{
+    migrate::{MigrateError, Migrator},
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    SqlitePool,
+};
+use std::{collections::HashSet, str::FromStr};
+
+// Selects rows in the shape of `Message`
+const SELECT_MESSAGES: &str =
+    "select m.id, m.room_id, m.parent_id, u.username as sender, m.body, m.created_at,
+            m.edited_at, m.deleted_at, m.attachment_id, a.name as attachment_name,
+            a.size as attachment_size
+     from messages m join users u on u.id = m.sender_id
+     left join attachments a on a.id = m.attachment_id";
+
+const SELECT_DIRECT_MESSAGES: &str =
+    "select d.id, s.username as sender, r.username as recipient, d.body, d.created_at
+     from direct_messages d
+     join users s on s.id = d.sender_id
+     join users r on r.id = d.recipient_id";
+
+// Unread counts in the shape of `Unread`
+const UNREAD_ROOMS: &str = "select rm.room_id, r.name, count(m.id) as count
+     from room_members rm
+     join rooms r on r.id = rm.room_id
+     left join messages m on m.room_id = rm.room_id and m.id > rm.last_read_id
+          and m.sender_id <> rm.user_id and m.deleted_at is null
+     where rm.user_id = ?
+     group by rm.room_id, r.name
+     order by rm.room_id";
+
+const UNREAD_DIRECT_MESSAGES: &str = "select null as room_id, s.username as name, count(*) as count
+     from direct_messages d
+     join users s on s.id = d.sender_id
+     left join direct_message_reads dr on dr.user_id = d.recipient_id and dr.peer_id = d.sender_id
+     where d.recipient_id = ? and d.id > coalesce(dr.last_read_id, 0)
+     group by s.username
+     order by s.username";
+
+const PROFILE: &str = "select u.username,
+            (select count(*) from messages m
+             where m.sender_id = u.id and m.deleted_at is null) as messages,
+            (select min(s.connected_at) from sessions s where s.user_id = u.id) as first_seen,
+            (select max(s.disconnected_at) from sessions s where s.user_id = u.id) as last_seen
+     from users u where u.normalized_username = ?";
+
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+// Adds `normalized_username`, but lower() of SQLite only changes ASCII letters
+const USER_TOKENS_MIGRATION: i64 = 20230425000000;
+
+pub struct SqliteStorage {
+    pool: SqlitePool,
+}
+
+impl SqliteStorage {
+    pub async fn connect(url: &str) -> Result<Self, sqlx::Error> {
+        let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+        // Every connection to `sqlite::memory:` opens its own empty database,
+        // so the pool has to keep a single connection alive
+        let pool = if url.contains(":memory:") {
+            SqlitePoolOptions::new()
+                .max_connections(1)
+                .min_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None)
+        } else {
+            SqlitePoolOptions::new()
+        };
+        Ok(Self::new(pool.connect_with(options).await?))
+    }
+
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+
+    // Normalizes the usernames again the same way as `add_user` and `get_user` do.
+    // Names that only differ in case still belong to the oldest user
+    async fn normalize_usernames(&self) -> Result<(), sqlx::Error> {
+        let users: Vec<(i64, String, String)> =
+            sqlx::query_as("select id, username, normalized_username from users order by id")
+                .fetch_all(&self.pool)
+                .await?;
+        let mut taken = HashSet::new();
+        let changed: Vec<(i64, String)> = users
+            .into_iter()
+            .filter_map(|(id, username, normalized)| {
+                let name = super::normalize(&username);
+                let name = if taken.insert(name.clone()) {
+                    name
+                } else {
+                    format!("{name}#{id}")
+                };
+                (name != normalized).then_some((id, name))
+            })
+            .collect();
+
+        // Usernames can't have a '#', so the placeholders don't clash with any name
+        let mut transaction = self.pool.begin().await?;
+        for (id, _) in &changed {
+            sqlx::query("update users set normalized_username = '#' || id where id = ?")
+                .bind(id)
+                .execute(&mut transaction)
+                .await?;
+        }
+        for (id, name) in &changed {
+            sqlx::query("update users set normalized_username = ? where id = ?")
+                .bind(name)
+                .bind(id)
+                .execute(&mut transaction)
+                .await?;
+        }
+        transaction.commit().await
+    }
+
+    async fn is_applied(&self, version: i64) -> Result<bool, MigrateError> {
+        let status = super::migration_status(&MIGRATOR, &self.pool).await?;
+        Ok(status.iter().any(|m| m.version == version && m.applied))
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn migrate(&self) -> Result<(), MigrateError> {
+        let normalized = self.is_applied(USER_TOKENS_MIGRATION).await?;
+        MIGRATOR.run(&self.pool).await?;
+        if !normalized {
+            self.normalize_usernames().await?;
+        }
+        Ok(())
+    }
+
+    async fn undo_last_migration(&self) -> Result<Option<i64>, MigrateError> {
+        let Some((latest, target)) = super::undo_target(self.migration_status().await?) else {
+            return Ok(None);
+        };
+        MIGRATOR.undo(&self.pool, target).await?;
+        Ok(Some(latest))
+    }
+
+    async fn migration_status(&self) -> Result<Vec<MigrationStatus>, MigrateError> {
+        super::migration_status(&MIGRATOR, &self.pool).await
+    }
+
+    async fn add_user(&self, username: &str) -> Result<User, sqlx::Error> {
+        sqlx::query_as(
+            "insert into users(username, normalized_username) values (?, ?)
+             on conflict (normalized_username)
+             do update set normalized_username = excluded.normalized_username
+             returning id, username, token_sha256 is not null as claimed",
+        )
+        .bind(username)
+        .bind(super::normalize(username))
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    async fn get_user(&self, username: &str) -> Result<Option<User>, sqlx::Error> {
+        sqlx::query_as(
+            "select id, username, token_sha256 is not null as claimed
+             from users where normalized_username = ?",
+        )
+        .bind(super::normalize(username))
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn claim_user(&self, user: &User, token_sha256: &str) -> Result<bool, sqlx::Error> {
+        let stored: String = sqlx::query_scalar(
+            "update users set token_sha256 = coalesce(token_sha256, ?)
+             where id = ? returning token_sha256",
+        )
+        .bind(token_sha256)
+        .bind(user.id)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(stored == token_sha256)
+    }
+
+    async fn token_matches(&self, user: &User, token_sha256: &str) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar("select exists(select 1 from users where id = ? and token_sha256 = ?)")
+            .bind(user.id)
+            .bind(token_sha256)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn add_room(&self, name: &str) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar(
+            "insert into rooms(name) values (?)
+             on conflict (name) do update set name = excluded.name
+             returning id",
+        )
+        .bind(name)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    async fn room_name(&self, room_id: i64) -> Result<Option<String>, sqlx::Error> {
+        sqlx::query_scalar("select name from rooms where id = ?")
+            .bind(room_id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    async fn join_room(&self, room_id: i64, user: &User) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "insert into room_members(room_id, user_id, last_read_id)
+             values (?, ?, (select coalesce(max(id), 0) from messages where room_id = ?))
+             on conflict (room_id, user_id) do nothing",
+        )
+        .bind(room_id)
+        .bind(user.id)
+        .bind(room_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn is_member(&self, room_id: i64, user: &User) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar(
+            "select exists(select 1 from room_members where room_id = ? and user_id = ?)",
+        )
+        .bind(room_id)
+        .bind(user.id)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    async fn room_members(&self, room_id: i64) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar(
+            "select users.username from room_members
+            join users on users.id = room_members.user_id
+            where room_members.room_id = ?
+            order by users.username",
+        )
+        .bind(room_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn mark_read(&self, room_id: i64, user: &User) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "update room_members
+             set last_read_id = (select coalesce(max(id), 0) from messages where room_id = ?)
+             where room_id = ? and user_id = ?",
+        )
+        .bind(room_id)
+        .bind(room_id)
+        .bind(user.id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn mark_direct_read(&self, user: &User, peer: &User) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "insert into direct_message_reads(user_id, peer_id, last_read_id)
+             values (?, ?, (select coalesce(max(id), 0) from direct_messages
+                             where recipient_id = ? and sender_id = ?))
+             on conflict (user_id, peer_id) do update set last_read_id = excluded.last_read_id",
+        )
+        .bind(user.id)
+        .bind(peer.id)
+        .bind(user.id)
+        .bind(peer.id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn unread(&self, user: &User) -> Result<Vec<Unread>, sqlx::Error> {
+        let mut unread: Vec<Unread> = sqlx::query_as(UNREAD_ROOMS)
+            .bind(user.id)
+            .fetch_all(&self.pool)
+            .await?;
+        let direct: Vec<Unread> = sqlx::query_as(UNREAD_DIRECT_MESSAGES)
+            .bind(user.id)
+            .fetch_all(&self.pool)
+            .await?;
+        unread.extend(direct);
+        Ok(unread)
+    }
+
+    async fn add_message(
+        &self,
+        room_id: i64,
+        sender: &User,
+        body: &str,
+        parent_id: Option<i64>,
+        attachment: Option<&Attachment>,
+    ) -> Result<Message, sqlx::Error> {
+        let created_at = Utc::now();
+        let id = sqlx::query_scalar(
+            "insert into messages(room_id, sender_id, body, created_at, parent_id, attachment_id)
+             values (?, ?, ?, ?, ?, ?)
+             returning id",
+        )
+        .bind(room_id)
+        .bind(sender.id)
+        .bind(body)
+        .bind(created_at)
+        .bind(parent_id)
+        .bind(attachment.map(|attachment| attachment.id))
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(Message {
+            id,
+            room_id,
+            parent_id,
+            sender: sender.username.clone(),
+            body: body.to_string(),
+            created_at,
+            edited_at: None,
+            deleted_at: None,
+            attachment_id: attachment.map(|attachment| attachment.id),
+            attachment_name: attachment.map(|attachment| attachment.name.clone()),
+            attachment_size: attachment.map(|attachment| attachment.size),
+        })
+    }
+
+    async fn get_message(&self, id: i64) -> Result<Option<Message>, sqlx::Error> {
+        sqlx::query_as(&format!("{SELECT_MESSAGES} where m.id = ?"))
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    async fn replies(&self, parent_id: i64) -> Result<Vec<Message>, sqlx::Error> {
+        sqlx::query_as(&format!(
+            "{SELECT_MESSAGES} where m.parent_id = ? order by m.id"
+        ))
+        .bind(parent_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn edit_message(
+        &self,
+        id: i64,
+        editor: &User,
+        body: &str,
+    ) -> Result<Message, sqlx::Error> {
+        let now = Utc::now();
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "insert into message_edits(message_id, editor_id, previous_body, edited_at)
+             select id, ?, body, ? from messages where id = ?",
+        )
+        .bind(editor.id)
+        .bind(now)
+        .bind(id)
+        .execute(&mut tx)
+        .await?;
+        sqlx::query("update messages set body = ?, edited_at = ? where id = ?")
+            .bind(body)
+            .bind(now)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        let message = sqlx::query_as(&format!("{SELECT_MESSAGES} where m.id = ?"))
+            .bind(id)
+            .fetch_one(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(message)
+    }
+
+    async fn delete_message(&self, id: i64) -> Result<(), sqlx::Error> {
+        sqlx::query("update messages set deleted_at = ? where id = ?")
+            .bind(Utc::now())
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn add_reaction(
+        &self,
+        message_id: i64,
+        user: &User,
+        emoji: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "insert into reactions(message_id, user_id, emoji, created_at) values (?, ?, ?, ?)
+             on conflict do nothing",
+        )
+        .bind(message_id)
+        .bind(user.id)
+        .bind(emoji)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_reaction(
+        &self,
+        message_id: i64,
+        user: &User,
+        emoji: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("delete from reactions where message_id = ? and user_id = ? and emoji = ?")
+            .bind(message_id)
+            .bind(user.id)
+            .bind(emoji)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    // The row ids follow the insertion order, unlike the text timestamps
+    async fn reactions(&self, message_id: i64) -> Result<Vec<Reaction>, sqlx::Error> {
+        let rows = sqlx::query_as(
+            "select r.emoji, u.username from reactions r join users u on u.id = r.user_id
+             where r.message_id = ?
+             order by r.rowid",
+        )
+        .bind(message_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(super::group_reactions(rows))
+    }
+
+    async fn add_attachment(
+        &self,
+        uploader: &User,
+        name: &str,
+        size: i64,
+        sha256: &str,
+    ) -> Result<Attachment, sqlx::Error> {
+        sqlx::query_as(
+            "insert into attachments(uploader_id, name, size, sha256, created_at)
+             values (?, ?, ?, ?, ?)
+             returning id, name, size, sha256",
+        )
+        .bind(uploader.id)
+        .bind(name)
+        .bind(size)
+        .bind(sha256)
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    async fn get_attachment(&self, id: i64) -> Result<Option<Attachment>, sqlx::Error> {
+        sqlx::query_as("select id, name, size, sha256 from attachments where id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    async fn attachment_room(&self, attachment_id: i64) -> Result<Option<i64>, sqlx::Error> {
+        sqlx::query_scalar("select room_id from messages where attachment_id = ? limit 1")
+            .bind(attachment_id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    async fn add_direct_message(
+        &self,
+        sender: &User,
+        recipient: &User,
+        body: &str,
+    ) -> Result<DirectMessage, sqlx::Error> {
+        let created_at = Utc::now();
+        let id = sqlx::query_scalar(
+            "insert into direct_messages(sender_id, recipient_id, body, created_at)
+             values (?, ?, ?, ?)
+             returning id",
+        )
+        .bind(sender.id)
+        .bind(recipient.id)
+        .bind(body)
+        .bind(created_at)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(DirectMessage {
+            id,
+            sender: sender.username.clone(),
+            recipient: recipient.username.clone(),
+            body: body.to_string(),
+            created_at,
+        })
+    }
+
+    async fn undelivered_direct_messages(
+        &self,
+        recipient: &User,
+    ) -> Result<Vec<DirectMessage>, sqlx::Error> {
+        sqlx::query_as(&format!(
+            "{SELECT_DIRECT_MESSAGES} where d.recipient_id = ? and d.delivered_at is null
+             order by d.id"
+        ))
+        .bind(recipient.id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn mark_delivered(&self, recipient: &User, last_id: i64) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "update direct_messages set delivered_at = ?
+             where recipient_id = ? and id <= ? and delivered_at is null",
+        )
+        .bind(Utc::now())
+        .bind(recipient.id)
+        .bind(last_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn profile(&self, username: &str) -> Result<Option<Profile>, sqlx::Error> {
+        sqlx::query_as(PROFILE)
+            .bind(super::normalize(username))
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    async fn open_session(&self, user: &User, address: &str) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar(
+            "insert into sessions(user_id, address, connected_at) values (?, ?, ?) returning id",
+        )
+        .bind(user.id)
+        .bind(address)
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    async fn close_stale_sessions(&self) -> Result<u64, sqlx::Error> {
+        let result =
+            sqlx::query("update sessions set disconnected_at = ? where disconnected_at is null")
+                .bind(Utc::now())
+                .execute(&self.pool)
+                .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn close_session(&self, session_id: i64) -> Result<(), sqlx::Error> {
+        sqlx::query("update sessions set disconnected_at = ? where id = ?")
+            .bind(Utc::now())
+            .bind(session_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+
+    async fn memory_storage() -> SqliteStorage {
+        let db = SqliteStorage::connect("sqlite::memory:").await.unwrap();
+        db.migrate().await.unwrap();
+        db
+    }
+
+    crate::db::suite::storage_tests! {
+        #[tokio::test]
+        async fn () { memory_storage().await }
+    }
+
+    #[tokio::test]
+    async fn edits_are_kept_in_the_history() -> sqlx::Result<()> {
+        let db = memory_storage().await;
+        let room_id = db.add_room("general").await?;
+        let alice = db.add_user("alice").await?;
+        let message = db.add_message(room_id, &alice, "helo", None, None).await?;
+
+        db.edit_message(message.id, &alice, "hello").await?;
+        db.edit_message(message.id, &alice, "hello!").await?;
+
+        let history: Vec<String> = sqlx::query_scalar(
+            "select previous_body from message_edits where message_id = ? order by id",
+        )
+        .bind(message.id)
+        .fetch_all(&db.pool)
+        .await?;
+        assert_eq!(history, ["helo", "hello"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sessions_are_closed_on_disconnect() -> sqlx::Result<()> {
+        let db = memory_storage().await;
+        let alice = db.add_user("alice").await?;
+
+        let session_id = db.open_session(&alice, "127.0.0.1:1234").await?;
+        let open: Option<DateTime<Utc>> =
+            sqlx::query_scalar("select disconnected_at from sessions where id = ?")
+                .bind(session_id)
+                .fetch_one(&db.pool)
+                .await?;
+        assert!(open.is_none());
+
+        db.close_session(session_id).await?;
+        let closed: Option<DateTime<Utc>> =
+            sqlx::query_scalar("select disconnected_at from sessions where id = ?")
+                .bind(session_id)
+                .fetch_one(&db.pool)
+                .await?;
+        assert!(closed.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn migrations_can_be_reverted() -> Result<(), MigrateError> {
+        let db = memory_storage().await;
+        assert!(db.migration_status().await?.iter().all(|m| m.applied));
+
+        let latest = MIGRATOR.iter().map(|m| m.version).max();
+        assert_eq!(db.undo_last_migration().await?, latest);
+        let status = db.migration_status().await?;
+        assert!(!status.last().unwrap().applied);
+        assert!(status[..status.len() - 1].iter().all(|m| m.applied));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn migrated_usernames_are_normalized_beyond_ascii() -> Result<(), MigrateError> {
+        let db = memory_storage().await;
+        while db.is_applied(USER_TOKENS_MIGRATION).await? {
+            db.undo_last_migration().await?;
+        }
+        for username in ["Émile", "émile", "ÖDE", "bob"] {
+            sqlx::query("insert into users(username) values (?)")
+                .bind(username)
+                .execute(&db.pool)
+                .await?;
+        }
+        db.migrate().await?;
+
+        let emile = db.get_user("ÉMILE").await?.unwrap();
+        assert_eq!((emile.id, emile.username.as_str()), (1, "Émile"));
+        assert_eq!(db.get_user("öde").await?.unwrap().id, 3);
+        assert_eq!(db.get_user("Bob").await?.unwrap().id, 4);
+        let normalized: Vec<String> =
+            sqlx::query_scalar("select normalized_username from users order by id")
+                .fetch_all(&db.pool)
+                .await?;
+        assert_eq!(normalized, ["émile", "émile#2", "öde", "bob"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_database_is_created() -> sqlx::Result<()> {
+        let path = std::env::temp_dir().join(format!("socket-chat-{}.db", std::process::id()));
+        let url = format!("sqlite://{}", path.display());
+
+        let db = SqliteStorage::connect(&url).await?;
+        db.migrate().await?;
+        let alice = db.add_user("alice").await?;
+        db.pool.close().await;
+
+        let db = SqliteStorage::connect(&url).await?;
+        assert_eq!(db.add_user("alice").await?.id, alice.id);
+        db.pool.close().await;
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,304 @@
+// Checks that every storage backend has to pass, each one runs them on its own
+// empty storage through `storage_tests!`
+use super::{Storage, Unread};
+
+// Declares a test for every check of the suite. `$storage` builds the empty
+// storage from the arguments of the test, e.g. the pool of `#[sqlx::test]`
+macro_rules! storage_tests {
+    ($(#[$attr:meta])* async fn ($($arg:ident: $ty:ty),*) $storage:block) => {
+        $crate::db::suite::storage_tests!(
+            @tests [$(#[$attr])*] ($($arg: $ty),*) $storage;
+            add_user_returns_the_registered_user,
+            usernames_ignore_case_and_tokens_claim_them,
+            add_room_is_idempotent,
+            add_message_stores_the_message,
+            edits_change_the_message,
+            replies_are_listed_in_order,
+            reactions_are_grouped_by_emoji,
+            deleted_messages_are_kept,
+            messages_reference_their_attachments,
+            profiles_count_messages_and_sessions,
+            direct_messages_wait_until_delivered,
+            unread_counts_follow_read_markers,
+            stale_sessions_are_closed
+        );
+    };
+    (@tests $attrs:tt $args:tt $storage:block; $($name:ident),*) => {
+        $($crate::db::suite::storage_tests!(@test $attrs $args $storage; $name);)*
+    };
+    (@test [$($attr:tt)*] $args:tt $storage:block; $name:ident) => {
+        $($attr)*
+        async fn $name $args -> sqlx::Result<()> {
+            let db = $storage;
+            $crate::db::suite::$name(&db).await
+        }
+    };
+}
+pub(super) use storage_tests;
+
+pub async fn add_user_returns_the_registered_user(db: &dyn Storage) -> sqlx::Result<()> {
+    let alice = db.add_user("alice").await?;
+    let bob = db.add_user("bob").await?;
+    let alice_again = db.add_user("alice").await?;
+
+    assert_eq!(alice.username, "alice");
+    assert_ne!(alice.id, bob.id);
+    assert_eq!(alice.id, alice_again.id);
+    Ok(())
+}
+
+pub async fn usernames_ignore_case_and_tokens_claim_them(db: &dyn Storage) -> sqlx::Result<()> {
+    let alice = db.add_user("Alice").await?;
+    let alice_again = db.add_user("ALICE").await?;
+    assert_eq!(alice_again.id, alice.id);
+    assert_eq!(alice_again.username, "Alice");
+    assert_eq!(
+        db.get_user("alice").await?.map(|user| user.id),
+        Some(alice.id)
+    );
+    assert!(!alice.claimed);
+
+    assert!(db.claim_user(&alice, "checksum").await?);
+    assert!(db.claim_user(&alice, "checksum").await?);
+    assert!(!db.claim_user(&alice, "other").await?);
+    assert!(db.token_matches(&alice, "checksum").await?);
+    assert!(!db.token_matches(&alice, "other").await?);
+    assert!(db.get_user("alice").await?.unwrap().claimed);
+    assert!(db.add_user("alice").await?.claimed);
+    assert_eq!(db.profile("aLiCe").await?.unwrap().username, "Alice");
+    Ok(())
+}
+
+pub async fn add_room_is_idempotent(db: &dyn Storage) -> sqlx::Result<()> {
+    let general = db.add_room("general").await?;
+    let random = db.add_room("random").await?;
+
+    assert_eq!(db.add_room("general").await?, general);
+    assert_ne!(general, random);
+    assert_eq!(db.room_name(random).await?.as_deref(), Some("random"));
+    assert_eq!(db.room_name(random + 1).await?, None);
+    Ok(())
+}
+
+pub async fn add_message_stores_the_message(db: &dyn Storage) -> sqlx::Result<()> {
+    let room_id = db.add_room("general").await?;
+    let alice = db.add_user("alice").await?;
+
+    let first = db.add_message(room_id, &alice, "hello", None, None).await?;
+    let second = db.add_message(room_id, &alice, "world", None, None).await?;
+
+    assert!(first.id < second.id);
+    assert_eq!(second.sender, "alice");
+    for message in [first, second] {
+        let stored = db.get_message(message.id).await?.unwrap();
+        assert_eq!(stored.room_id, room_id);
+        assert_eq!(stored.body, message.body);
+        assert_eq!(stored.created_at, message.created_at);
+        assert!(stored.edited_at.is_none());
+    }
+    Ok(())
+}
+
+pub async fn edits_change_the_message(db: &dyn Storage) -> sqlx::Result<()> {
+    let room_id = db.add_room("general").await?;
+    let alice = db.add_user("alice").await?;
+    let message = db.add_message(room_id, &alice, "helo", None, None).await?;
+
+    db.edit_message(message.id, &alice, "hello").await?;
+    let edited = db.edit_message(message.id, &alice, "hello!").await?;
+
+    assert_eq!(edited.body, "hello!");
+    assert_eq!(edited.sender, "alice");
+    assert!(edited.edited_at.is_some());
+    let stored = db.get_message(message.id).await?.unwrap();
+    assert_eq!(stored.body, "hello!");
+    assert_eq!(stored.created_at, message.created_at);
+    Ok(())
+}
+
+pub async fn replies_are_listed_in_order(db: &dyn Storage) -> sqlx::Result<()> {
+    let room_id = db.add_room("general").await?;
+    let alice = db.add_user("alice").await?;
+    let bob = db.add_user("bob").await?;
+    let parent = db
+        .add_message(room_id, &alice, "question", None, None)
+        .await?;
+    db.add_message(room_id, &bob, "unrelated", None, None)
+        .await?;
+
+    let first = db
+        .add_message(room_id, &bob, "answer", Some(parent.id), None)
+        .await?;
+    db.add_message(room_id, &alice, "thanks", Some(parent.id), None)
+        .await?;
+
+    assert_eq!(first.parent_id, Some(parent.id));
+    let replies = db.replies(parent.id).await?;
+    let bodies: Vec<&str> = replies.iter().map(|m| m.body.as_str()).collect();
+    assert_eq!(bodies, ["answer", "thanks"]);
+    assert_eq!(replies[0].sender, "bob");
+    assert_eq!(replies[0].room_id, room_id);
+    assert!(db.replies(first.id).await?.is_empty());
+    Ok(())
+}
+
+pub async fn reactions_are_grouped_by_emoji(db: &dyn Storage) -> sqlx::Result<()> {
+    let room_id = db.add_room("general").await?;
+    let alice = db.add_user("alice").await?;
+    let bob = db.add_user("bob").await?;
+    let message = db.add_message(room_id, &alice, "hello", None, None).await?;
+
+    db.add_reaction(message.id, &bob, "🎉").await?;
+    db.add_reaction(message.id, &alice, "👍").await?;
+    db.add_reaction(message.id, &bob, "👍").await?;
+    db.add_reaction(message.id, &bob, "👍").await?;
+    db.add_reaction(message.id, &alice, "🎉").await?;
+    db.remove_reaction(message.id, &bob, "🎉").await?;
+
+    let reactions = db.reactions(message.id).await?;
+    let emojis: Vec<&str> = reactions.iter().map(|r| r.emoji.as_str()).collect();
+    assert_eq!(emojis, ["👍", "🎉"]);
+    assert_eq!(reactions[0].usernames, ["alice", "bob"]);
+    assert_eq!(reactions[1].usernames, ["alice"]);
+    Ok(())
+}
+
+pub async fn deleted_messages_are_kept(db: &dyn Storage) -> sqlx::Result<()> {
+    let room_id = db.add_room("general").await?;
+    let alice = db.add_user("alice").await?;
+    let message = db.add_message(room_id, &alice, "hello", None, None).await?;
+
+    db.delete_message(message.id).await?;
+
+    let deleted = db.get_message(message.id).await?.unwrap();
+    assert!(deleted.deleted_at.is_some());
+    assert!(db.get_message(message.id + 1).await?.is_none());
+    Ok(())
+}
+
+pub async fn messages_reference_their_attachments(db: &dyn Storage) -> sqlx::Result<()> {
+    let room_id = db.add_room("general").await?;
+    let alice = db.add_user("alice").await?;
+    let sha256 = "0".repeat(64);
+    let attachment = db.add_attachment(&alice, "cat.png", 1024, &sha256).await?;
+    let message = db
+        .add_message(room_id, &alice, "", None, Some(&attachment))
+        .await?;
+
+    let stored = db.get_message(message.id).await?.unwrap();
+    assert_eq!(stored.attachment_id, Some(attachment.id));
+    assert_eq!(stored.attachment_name.as_deref(), Some("cat.png"));
+    assert_eq!(stored.attachment_size, Some(1024));
+    let stored = db.get_attachment(attachment.id).await?.unwrap();
+    assert_eq!(stored.sha256, sha256);
+    assert!(db.get_attachment(attachment.id + 1).await?.is_none());
+    assert_eq!(db.attachment_room(attachment.id).await?, Some(room_id));
+    assert_eq!(db.attachment_room(attachment.id + 1).await?, None);
+    Ok(())
+}
+
+pub async fn profiles_count_messages_and_sessions(db: &dyn Storage) -> sqlx::Result<()> {
+    let room_id = db.add_room("general").await?;
+    let alice = db.add_user("alice").await?;
+    let session_id = db.open_session(&alice, "127.0.0.1:1234").await?;
+    db.add_message(room_id, &alice, "hello", None, None).await?;
+    let deleted = db.add_message(room_id, &alice, "oops", None, None).await?;
+    db.delete_message(deleted.id).await?;
+
+    let profile = db.profile("alice").await?.unwrap();
+    assert_eq!(profile.messages, 1);
+    assert!(profile.first_seen.is_some());
+    assert!(profile.last_seen.is_none());
+
+    db.close_session(session_id).await?;
+    let profile = db.profile("alice").await?.unwrap();
+    assert!(profile.last_seen >= profile.first_seen);
+    assert!(db.profile("bob").await?.is_none());
+    Ok(())
+}
+
+pub async fn direct_messages_wait_until_delivered(db: &dyn Storage) -> sqlx::Result<()> {
+    let alice = db.add_user("alice").await?;
+    let bob = db.add_user("bob").await?;
+    let first = db.add_direct_message(&alice, &bob, "hi").await?;
+    let second = db
+        .add_direct_message(&alice, &bob, "are you there?")
+        .await?;
+    db.add_direct_message(&bob, &alice, "yes").await?;
+
+    let undelivered = db.undelivered_direct_messages(&bob).await?;
+    let ids: Vec<i64> = undelivered.iter().map(|message| message.id).collect();
+    assert_eq!(ids, [first.id, second.id]);
+    assert_eq!(undelivered[0].sender, "alice");
+    assert_eq!(undelivered[0].recipient, "bob");
+    assert_eq!(undelivered[0].created_at, first.created_at);
+
+    db.mark_delivered(&bob, first.id).await?;
+    let undelivered = db.undelivered_direct_messages(&bob).await?;
+    assert_eq!(undelivered.len(), 1);
+    db.mark_delivered(&bob, second.id).await?;
+    assert!(db.undelivered_direct_messages(&bob).await?.is_empty());
+    assert_eq!(db.undelivered_direct_messages(&alice).await?.len(), 1);
+    assert_eq!(db.get_user("bob").await?.map(|user| user.id), Some(bob.id));
+    assert!(db.get_user("carol").await?.is_none());
+    Ok(())
+}
+
+pub async fn unread_counts_follow_read_markers(db: &dyn Storage) -> sqlx::Result<()> {
+    let general = db.add_room("general").await?;
+    let random = db.add_room("random").await?;
+    let alice = db.add_user("alice").await?;
+    let bob = db.add_user("bob").await?;
+    db.add_message(general, &bob, "before", None, None).await?;
+    db.join_room(general, &alice).await?;
+    db.add_message(general, &bob, "hello", None, None).await?;
+    db.add_message(general, &alice, "hi", None, None).await?;
+    let deleted = db.add_message(general, &bob, "oops", None, None).await?;
+    db.delete_message(deleted.id).await?;
+    db.join_room(random, &alice).await?;
+    db.join_room(random, &alice).await?;
+    db.add_message(random, &bob, "anyone?", None, None).await?;
+    db.add_direct_message(&bob, &alice, "psst").await?;
+
+    let unread = |room_id, name: &str, count| Unread {
+        room_id,
+        name: name.to_string(),
+        count,
+    };
+    assert_eq!(
+        db.unread(&alice).await?,
+        [
+            unread(Some(general), "general", 1),
+            unread(Some(random), "random", 1),
+            unread(None, "bob", 1)
+        ]
+    );
+    assert!(db.is_member(random, &alice).await?);
+    assert!(!db.is_member(random, &bob).await?);
+    db.join_room(general, &bob).await?;
+    assert_eq!(db.room_members(general).await?, ["alice", "bob"]);
+    assert_eq!(db.room_members(random).await?, ["alice"]);
+
+    db.mark_read(general, &alice).await?;
+    db.mark_direct_read(&alice, &bob).await?;
+    assert_eq!(
+        db.unread(&alice).await?,
+        [
+            unread(Some(general), "general", 0),
+            unread(Some(random), "random", 1)
+        ]
+    );
+    Ok(())
+}
+
+pub async fn stale_sessions_are_closed(db: &dyn Storage) -> sqlx::Result<()> {
+    let alice = db.add_user("alice").await?;
+    let bob = db.add_user("bob").await?;
+    let closed = db.open_session(&alice, "127.0.0.1:1234").await?;
+    db.close_session(closed).await?;
+    db.open_session(&bob, "127.0.0.1:1235").await?;
+
+    assert_eq!(db.close_stale_sessions().await?, 1);
+    assert_eq!(db.close_stale_sessions().await?, 0);
+    Ok(())
+}
@@ -1,35 +1,107 @@
 mod client;
 mod config;
+mod connections;
 mod db;
 mod macros;
+mod queue;
+mod rate_limit;
+mod registry;
 mod server;
+mod state;
+mod uploads;
 
-use log::{error, LevelFilter};
+use config::{Config, StorageKind};
+use db::{MemoryStorage, Storage};
+use log::{error, info, LevelFilter};
 use simple_logger::SimpleLogger;
+use state::ServerState;
+use std::sync::Arc;
 use time::macros::format_description;
 
-pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     SimpleLogger::new()
         .with_level(LevelFilter::Info)
+        .with_module_level("sqlx", LevelFilter::Warn)
         .with_timestamp_format(format_description!(
             "[year]-[month]-[day] [hour]:[minute]:[second]"
         ))
         .init()
         .unwrap();
 
-    let _pool = match db::connect().await {
-        Some(pool) => pool,
-        None => {
-            error!("Could not connect to the database, check your .env file");
-            return Ok(());
-        }
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args.as_slice() {
+        [] => start_server().await,
+        ["migrate", command @ ..] => migrate_command(command).await,
+        _ => Err(
+            format!("Unknown arguments {args:?}, usage: server [migrate [status|up|down]]").into(),
+        ),
     };
 
-    if let Err(e) = server::run().await {
+    if let Err(e) = result {
         error!("{}", e);
     };
     Ok(())
 }
+
+async fn open_storage(config: &Config) -> Result<Box<dyn Storage>> {
+    match config.storage {
+        StorageKind::Memory => {
+            info!("Using in-memory storage, nothing will be kept after the server stops");
+            Ok(Box::<MemoryStorage>::default())
+        }
+        StorageKind::Database => {
+            dotenv::dotenv().ok();
+            let db_url = std::env::var("DATABASE_URL")
+                .map_err(|_| "DATABASE_URL is not set, check your .env file")?;
+            db::connect(&db_url)
+                .await
+                .map_err(|e| format!("Could not connect to the database: {e}").into())
+        }
+    }
+}
+
+async fn start_server() -> Result<()> {
+    let config = Config::load()?;
+    let db = open_storage(&config).await?;
+    db.migrate()
+        .await
+        .map_err(|e| format!("Could not migrate the database: {e}"))?;
+    info!("Database schema is up to date");
+
+    let state = ServerState::new(config, db).await?;
+    server::run(Arc::new(state)).await
+}
+
+async fn migrate_command(command: &[&str]) -> Result<()> {
+    let db = open_storage(&Config::load()?).await?;
+    match command {
+        ["status"] => {
+            for migration in db.migration_status().await? {
+                let state = if migration.applied {
+                    "applied"
+                } else {
+                    "pending"
+                };
+                println!("{} {} ({state})", migration.version, migration.description);
+            }
+        }
+        [] | ["up"] => {
+            db.migrate().await?;
+            println!("All migrations have been applied");
+        }
+        ["down"] => match db.undo_last_migration().await? {
+            Some(version) => println!("Migration {version} has been reverted"),
+            None => println!("There are no applied migrations"),
+        },
+        _ => {
+            return Err(
+                format!("Unknown migrate command {command:?}, expected status, up or down").into(),
+            )
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,220 @@
+use crate::config::SlowConsumerPolicy;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+struct Shared {
+    messages: Mutex<VecDeque<Arc<str>>>,
+    notify: Notify,
+    closed: AtomicBool,
+    capacity: usize,
+    policy: SlowConsumerPolicy,
+}
+
+#[derive(Clone)]
+pub struct Sender {
+    shared: Arc<Shared>,
+}
+
+pub struct Receiver {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+pub enum SendError {
+    DroppedOldest(usize),
+    DroppedNewest(usize),
+    Disconnected(usize),
+    Closed,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DroppedOldest(depth) => {
+                write!(f, "queue is full ({depth}), the oldest message was dropped")
+            }
+            Self::DroppedNewest(depth) => {
+                write!(f, "queue is full ({depth}), the message was dropped")
+            }
+            Self::Disconnected(depth) => {
+                write!(
+                    f,
+                    "queue is full ({depth}), disconnecting the slow consumer"
+                )
+            }
+            Self::Closed => write!(f, "queue is closed"),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+impl SendError {
+    // With `DropOldest` the message is still queued, an older one is lost instead
+    pub fn is_queued(&self) -> bool {
+        matches!(self, Self::DroppedOldest(_))
+    }
+}
+
+pub fn bounded(capacity: usize, policy: SlowConsumerPolicy) -> (Sender, Receiver) {
+    assert!(capacity > 0, "a queue needs room for at least one message");
+    let shared = Arc::new(Shared {
+        messages: Mutex::new(VecDeque::with_capacity(capacity)),
+        notify: Notify::new(),
+        closed: AtomicBool::new(false),
+        capacity,
+        policy,
+    });
+    (
+        Sender {
+            shared: Arc::clone(&shared),
+        },
+        Receiver { shared },
+    )
+}
+
+impl Sender {
+    pub fn send(&self, message: Arc<str>) -> Result<(), SendError> {
+        if self.is_closed() {
+            return Err(SendError::Closed);
+        }
+
+        let mut messages = self.shared.messages.lock().unwrap();
+        let depth = messages.len();
+        let result = if depth < self.shared.capacity {
+            messages.push_back(message);
+            Ok(())
+        } else {
+            match self.shared.policy {
+                SlowConsumerPolicy::DropOldest => {
+                    messages.pop_front();
+                    messages.push_back(message);
+                    Err(SendError::DroppedOldest(depth))
+                }
+                SlowConsumerPolicy::DropNewest => Err(SendError::DroppedNewest(depth)),
+                SlowConsumerPolicy::Disconnect => {
+                    self.shared.closed.store(true, Ordering::Release);
+                    Err(SendError::Disconnected(depth))
+                }
+            }
+        };
+        drop(messages);
+
+        self.shared.notify.notify_one();
+        result
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.messages.lock().unwrap().len()
+    }
+
+    pub fn close(&self) {
+        self.shared.closed.store(true, Ordering::Release);
+        self.shared.notify.notify_one();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Acquire)
+    }
+}
+
+impl Receiver {
+    /// Returns `None` once the queue has been closed, the pending messages of a
+    /// closed queue are discarded.
+    pub async fn recv(&mut self) -> Option<Arc<str>> {
+        loop {
+            if self.shared.closed.load(Ordering::Acquire) {
+                return None;
+            }
+            if let Some(message) = self.shared.messages.lock().unwrap().pop_front() {
+                return Some(message);
+            }
+            self.shared.notify.notified().await;
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Option<Arc<str>> {
+        if self.shared.closed.load(Ordering::Acquire) {
+            return None;
+        }
+        self.shared.messages.lock().unwrap().pop_front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.messages.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send_all(sender: &Sender, messages: &[&str]) -> Vec<Result<(), SendError>> {
+        messages
+            .iter()
+            .map(|message| sender.send((*message).into()))
+            .collect()
+    }
+
+    fn drain(receiver: &mut Receiver) -> Vec<String> {
+        std::iter::from_fn(|| receiver.try_recv())
+            .map(|message| message.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_latest_messages() {
+        let (sender, mut receiver) = bounded(2, SlowConsumerPolicy::DropOldest);
+        let results = send_all(&sender, &["a", "b", "c"]);
+        assert!(matches!(results[2], Err(SendError::DroppedOldest(2))));
+        assert!(results[2].as_ref().unwrap_err().is_queued());
+        assert!(!sender.is_closed());
+        assert_eq!(drain(&mut receiver), ["b", "c"]);
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_earliest_messages() {
+        let (sender, mut receiver) = bounded(2, SlowConsumerPolicy::DropNewest);
+        let results = send_all(&sender, &["a", "b", "c"]);
+        assert!(matches!(results[2], Err(SendError::DroppedNewest(2))));
+        assert!(!results[2].as_ref().unwrap_err().is_queued());
+        assert!(!sender.is_closed());
+        assert_eq!(drain(&mut receiver), ["a", "b"]);
+    }
+
+    #[test]
+    fn disconnect_closes_a_full_queue() {
+        let (sender, mut receiver) = bounded(2, SlowConsumerPolicy::Disconnect);
+        let results = send_all(&sender, &["a", "b", "c", "d"]);
+        assert!(matches!(results[2], Err(SendError::Disconnected(2))));
+        assert!(matches!(results[3], Err(SendError::Closed)));
+        assert!(!results[2].as_ref().unwrap_err().is_queued());
+        assert!(sender.is_closed());
+        assert!(receiver.try_recv().is_none());
+    }
+
+    #[tokio::test]
+    async fn close_wakes_up_the_receiver() {
+        let (sender, mut receiver) = bounded(2, SlowConsumerPolicy::DropOldest);
+        let waiting = tokio::spawn(async move { receiver.recv().await });
+        tokio::task::yield_now().await;
+        sender.close();
+        let received = tokio::time::timeout(std::time::Duration::from_secs(5), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(received.is_none());
+        assert!(matches!(sender.send("a".into()), Err(SendError::Closed)));
+    }
+
+    #[test]
+    fn close_discards_the_pending_messages() {
+        let (sender, mut receiver) = bounded(2, SlowConsumerPolicy::DropOldest);
+        sender.send("a".into()).unwrap();
+        sender.close();
+        assert!(receiver.try_recv().is_none());
+    }
+}
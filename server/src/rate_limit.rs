@@ -0,0 +1,326 @@
+use crate::config::RateLimit;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: f64, rate: f64) -> Self {
+        Self {
+            capacity,
+            rate,
+            tokens: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+
+    // A cost bigger than the bucket itself could never be paid, so it is capped
+    // by the capacity, i.e. such request needs a full bucket.
+    fn wait_time(&self, cost: f64) -> Duration {
+        let cost = cost.min(self.capacity);
+        if self.tokens >= cost {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((cost - self.tokens) / self.rate)
+        }
+    }
+
+    fn consume(&mut self, cost: f64) {
+        self.tokens -= cost.min(self.capacity);
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= self.capacity
+    }
+}
+
+struct Limits {
+    messages: TokenBucket,
+    bytes: TokenBucket,
+}
+
+impl Limits {
+    fn new(limit: &RateLimit) -> Self {
+        Self {
+            messages: TokenBucket::new(limit.messages_burst, limit.messages_per_second),
+            bytes: TokenBucket::new(limit.bytes_burst, limit.bytes_per_second),
+        }
+    }
+
+    fn wait_time(&mut self, now: Instant, bytes: f64) -> Duration {
+        self.messages.refill(now);
+        self.bytes.refill(now);
+        self.messages
+            .wait_time(1.0)
+            .max(self.bytes.wait_time(bytes))
+    }
+
+    fn consume(&mut self, bytes: f64) {
+        self.messages.consume(1.0);
+        self.bytes.consume(bytes);
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.messages.refill(now);
+        self.bytes.refill(now);
+        self.messages.is_full() && self.bytes.is_full()
+    }
+}
+
+// Violations of the rate limit are forgiven over time, so only a client that keeps
+// hitting the limit is disconnected, even if some of its requests get through
+pub struct Violations {
+    allowed: TokenBucket,
+}
+
+impl Violations {
+    pub fn new(max: usize, forgiven_after: Duration) -> Self {
+        Self {
+            allowed: TokenBucket::new(max as f64, 1.0 / forgiven_after.as_secs_f64()),
+        }
+    }
+
+    /// Returns `true` once there have been more than `max` violations that are
+    /// not forgiven yet.
+    pub fn record(&mut self) -> bool {
+        self.record_at(Instant::now())
+    }
+
+    fn record_at(&mut self, now: Instant) -> bool {
+        self.allowed.refill(now);
+        if self.allowed.tokens < 1.0 {
+            return true;
+        }
+        self.allowed.consume(1.0);
+        false
+    }
+
+    // The time until one more violation is allowed, once `max` have been used up
+    fn retry_after_at(&mut self, now: Instant) -> Option<Duration> {
+        self.allowed.refill(now);
+        (self.allowed.tokens < 1.0).then(|| self.allowed.wait_time(1.0))
+    }
+
+    fn is_forgiven(&mut self, now: Instant) -> bool {
+        self.allowed.refill(now);
+        self.allowed.is_full()
+    }
+}
+
+// Failed logins are counted per IP, so guessing a token can't go on by reconnecting
+pub struct FailedLogins {
+    max: usize,
+    forgiven_after: Duration,
+    ips: Mutex<HashMap<IpAddr, Violations>>,
+}
+
+impl FailedLogins {
+    pub fn new(max: usize, forgiven_after: Duration) -> Self {
+        Self {
+            max,
+            forgiven_after,
+            ips: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the time after which the IP may try again, once it has failed
+    /// `max` times that are not forgiven yet.
+    pub async fn retry_after(&self, ip: IpAddr) -> Option<Duration> {
+        let now = Instant::now();
+        self.ips
+            .lock()
+            .await
+            .get_mut(&ip)
+            .and_then(|failed| failed.retry_after_at(now))
+    }
+
+    pub async fn record(&self, ip: IpAddr) {
+        let now = Instant::now();
+        let mut ips = self.ips.lock().await;
+        // Like full buckets, forgiven IPs are the same as new ones
+        ips.retain(|_, failed| !failed.is_forgiven(now));
+        ips.entry(ip)
+            .or_insert_with(|| Violations::new(self.max, self.forgiven_after))
+            .record_at(now);
+    }
+}
+
+pub struct RateLimiter {
+    user_limit: RateLimit,
+    ip_limit: RateLimit,
+    users: Mutex<HashMap<String, Limits>>,
+    ips: Mutex<HashMap<IpAddr, Limits>>,
+}
+
+impl RateLimiter {
+    pub fn new(user_limit: RateLimit, ip_limit: RateLimit) -> Self {
+        Self {
+            user_limit,
+            ip_limit,
+            users: Mutex::new(HashMap::new()),
+            ips: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes one message and `bytes` bytes from both the user and the IP buckets.
+    /// Nothing is taken if any of the buckets is short, the returned error is
+    /// the time after which the request would be accepted.
+    pub async fn check(&self, username: &str, ip: IpAddr, bytes: usize) -> Result<(), Duration> {
+        let now = Instant::now();
+        let bytes = bytes as f64;
+        let mut users = self.users.lock().await;
+        let mut ips = self.ips.lock().await;
+        let user = users
+            .entry(username.to_string())
+            .or_insert_with(|| Limits::new(&self.user_limit));
+        let ip = ips.entry(ip).or_insert_with(|| Limits::new(&self.ip_limit));
+
+        let retry_after = user.wait_time(now, bytes).max(ip.wait_time(now, bytes));
+        if retry_after.is_zero() {
+            user.consume(bytes);
+            ip.consume(bytes);
+            Ok(())
+        } else {
+            Err(retry_after)
+        }
+    }
+
+    /// Forgets the buckets that have been refilled, a full bucket is the same
+    /// as a new one so this never lets anyone bypass the limits.
+    pub async fn prune(&self) {
+        let now = Instant::now();
+        self.users
+            .lock()
+            .await
+            .retain(|_, limits| !limits.is_full(now));
+        self.ips
+            .lock()
+            .await
+            .retain(|_, limits| !limits.is_full(now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(per_second: f64, burst: f64) -> RateLimit {
+        RateLimit {
+            messages_per_second: per_second,
+            messages_burst: burst,
+            bytes_per_second: per_second * 1000.0,
+            bytes_burst: burst * 1000.0,
+        }
+    }
+
+    #[test]
+    fn buckets_refill_at_their_rate_up_to_the_capacity() {
+        let mut bucket = TokenBucket::new(4.0, 2.0);
+        let start = bucket.updated;
+        bucket.consume(4.0);
+        assert_eq!(bucket.wait_time(1.0), Duration::from_millis(500));
+
+        bucket.refill(start + Duration::from_secs(1));
+        assert_eq!(bucket.tokens, 2.0);
+        bucket.refill(start + Duration::from_secs(10));
+        assert_eq!(bucket.tokens, 4.0);
+        assert!(bucket.is_full());
+    }
+
+    #[test]
+    fn costs_larger_than_the_capacity_need_a_full_bucket() {
+        let mut bucket = TokenBucket::new(4.0, 1.0);
+        assert_eq!(bucket.wait_time(100.0), Duration::ZERO);
+        bucket.consume(100.0);
+        assert_eq!(bucket.tokens, 0.0);
+        assert_eq!(bucket.wait_time(100.0), Duration::from_secs(4));
+    }
+
+    #[tokio::test]
+    async fn bursts_are_allowed_up_to_the_limit() {
+        let limiter = RateLimiter::new(limit(0.001, 3.0), limit(0.001, 100.0));
+        let ip = "127.0.0.1".parse().unwrap();
+        for _ in 0..3 {
+            assert!(limiter.check("alice", ip, 10).await.is_ok());
+        }
+        assert!(limiter.check("alice", ip, 10).await.is_err());
+        // Other users behind the same IP have their own bucket
+        assert!(limiter.check("bob", ip, 10).await.is_ok());
+
+        let ip = "127.0.0.2".parse().unwrap();
+        let bytes = limiter.check("carol", ip, 5000).await;
+        assert!(
+            bytes.is_ok(),
+            "a request larger than the burst needs a full bucket"
+        );
+        assert!(limiter.check("carol", ip, 1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn prune_forgets_only_full_buckets() {
+        let limiter = RateLimiter::new(limit(1e9, 3.0), limit(0.001, 100.0));
+        let ip = "127.0.0.1".parse().unwrap();
+        limiter.check("alice", ip, 10).await.unwrap();
+        std::thread::sleep(Duration::from_millis(1));
+
+        limiter.prune().await;
+        assert!(limiter.users.lock().await.is_empty());
+        assert_eq!(limiter.ips.lock().await.len(), 1);
+    }
+
+    #[test]
+    fn violations_are_forgiven_over_time() {
+        let mut violations = Violations::new(2, Duration::from_secs(10));
+        let start = violations.allowed.updated;
+        assert!(!violations.record_at(start));
+        assert!(!violations.record_at(start));
+        assert!(violations.record_at(start));
+
+        // Allowed requests in between don't reset the count, only time does
+        assert!(violations.record_at(start + Duration::from_secs(5)));
+        assert!(!violations.record_at(start + Duration::from_secs(10)));
+        assert!(violations.record_at(start + Duration::from_secs(11)));
+
+        let mut none_allowed = Violations::new(0, Duration::from_secs(10));
+        assert!(none_allowed.record_at(start));
+    }
+
+    #[tokio::test]
+    async fn failed_logins_block_only_their_ip() {
+        let failed_logins = FailedLogins::new(2, Duration::from_secs(60));
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        failed_logins.record(ip).await;
+        assert_eq!(failed_logins.retry_after(ip).await, None);
+        failed_logins.record(ip).await;
+        let retry_after = failed_logins.retry_after(ip).await.unwrap();
+        assert!(retry_after > Duration::from_secs(59));
+        assert_eq!(failed_logins.retry_after(IpAddr::from([127, 0, 0, 2])).await, None);
+    }
+
+    #[test]
+    fn used_up_violations_tell_when_to_retry() {
+        let mut violations = Violations::new(1, Duration::from_secs(10));
+        let start = violations.allowed.updated;
+        assert_eq!(violations.retry_after_at(start), None);
+        violations.record_at(start);
+        assert_eq!(
+            violations.retry_after_at(start + Duration::from_secs(4)),
+            Some(Duration::from_secs(6))
+        );
+        assert!(!violations.is_forgiven(start + Duration::from_secs(9)));
+        assert!(violations.is_forgiven(start + Duration::from_secs(10)));
+    }
+}
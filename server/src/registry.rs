@@ -0,0 +1,202 @@
+use crate::config::DuplicateLoginPolicy;
+use crate::queue;
+use crate::Result;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use log::info;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+// A sharded map: connects, disconnects and sends to different clients only
+// contend when they hit the same shard, and sending never waits for a socket
+#[derive(Default)]
+pub struct Registry {
+    clients: DashMap<SocketAddr, queue::Sender>,
+    // Lowercased usernames of the online users, so "Alice" can't pretend to be "alice"
+    usernames: DashMap<String, SocketAddr>,
+}
+
+impl Registry {
+    pub fn insert(&self, addr: SocketAddr, sender: queue::Sender) {
+        self.clients.insert(addr, sender);
+    }
+
+    pub fn remove(&self, addr: &SocketAddr) {
+        self.clients.remove(addr);
+    }
+
+    /// Returns `false` if the username is already used by someone else and the
+    /// policy doesn't allow to take over their session.
+    pub fn claim_username(
+        &self,
+        username: &str,
+        addr: SocketAddr,
+        policy: DuplicateLoginPolicy,
+    ) -> bool {
+        match self.usernames.entry(username.to_lowercase()) {
+            Entry::Vacant(entry) => {
+                entry.insert(addr);
+                true
+            }
+            Entry::Occupied(mut entry) => match policy {
+                DuplicateLoginPolicy::Reject => false,
+                DuplicateLoginPolicy::TakeOver => {
+                    let previous = entry.insert(addr);
+                    if let Some(client) = self.clients.get(&previous) {
+                        client.close();
+                    }
+                    info!("{username} ({addr}) has taken over the session of {previous}");
+                    true
+                }
+            },
+        }
+    }
+
+    pub fn release_username(&self, username: &str, addr: SocketAddr) {
+        self.usernames
+            .remove_if(&username.to_lowercase(), |_, owner| *owner == addr);
+    }
+
+    pub fn release_usernames_of(&self, addr: SocketAddr) {
+        self.usernames.retain(|_, owner| *owner != addr);
+    }
+
+    pub fn is_online(&self, username: &str) -> bool {
+        self.usernames.contains_key(&username.to_lowercase())
+    }
+
+    pub fn address_of(&self, username: &str) -> Option<SocketAddr> {
+        self.usernames
+            .get(&username.to_lowercase())
+            .map(|owner| *owner)
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn queue_depths(&self) -> Vec<usize> {
+        self.clients
+            .iter()
+            .map(|client| client.value().len())
+            .collect()
+    }
+
+    pub fn broadcast(&self, sender: SocketAddr, request: &str) {
+        let request: Arc<str> = request.into();
+        for client in self.clients.iter() {
+            if *client.key() != sender {
+                if let Err(e) = client.value().send(Arc::clone(&request)) {
+                    info!("Could not send a message to {}: {e}", client.key());
+                }
+            }
+        }
+    }
+
+    // Clients that have disconnected in the meantime are skipped
+    pub fn send_to(&self, targets: &[SocketAddr], request: &str) {
+        let request: Arc<str> = request.into();
+        for target in targets {
+            if let Some(client) = self.clients.get(target) {
+                if let Err(e) = client.send(Arc::clone(&request)) {
+                    info!("Could not send a message to {target}: {e}");
+                }
+            }
+        }
+    }
+
+    pub fn send_targeted(&self, target: SocketAddr, request: &str) -> Result<()> {
+        self.queue_targeted(target, request).map(drop)
+    }
+
+    // Like `send_targeted`, but also tells whether the request made it into the
+    // queue of the target, a full queue drops it unless the policy is `DropOldest`
+    pub fn queue_targeted(&self, target: SocketAddr, request: &str) -> Result<bool> {
+        if let Some(client) = self.clients.get(&target) {
+            match client.send(request.into()) {
+                Ok(()) => Ok(true),
+                Err(e) => {
+                    info!("Could not send a message to {target}: {e}");
+                    Ok(e.is_queued())
+                }
+            }
+        } else {
+            Err(format!("Could not find a user: {}", target).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SlowConsumerPolicy;
+
+    fn connect(registry: &Registry, port: u16) -> (SocketAddr, queue::Receiver) {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let (sender, receiver) = queue::bounded(8, SlowConsumerPolicy::DropOldest);
+        registry.insert(addr, sender);
+        (addr, receiver)
+    }
+
+    #[test]
+    fn reject_keeps_the_first_session() {
+        let registry = Registry::default();
+        let (first, mut first_receiver) = connect(&registry, 1);
+        let (second, _) = connect(&registry, 2);
+
+        assert!(registry.claim_username("Alice", first, DuplicateLoginPolicy::Reject));
+        assert!(!registry.claim_username("alice", second, DuplicateLoginPolicy::Reject));
+        assert_eq!(registry.address_of("ALICE"), Some(first));
+
+        registry.send_targeted(first, "hello").unwrap();
+        assert_eq!(first_receiver.try_recv().as_deref(), Some("hello"));
+    }
+
+    #[test]
+    fn send_to_reaches_only_the_targets() {
+        let registry = Registry::default();
+        let (first, mut first_receiver) = connect(&registry, 1);
+        let (_, mut second_receiver) = connect(&registry, 2);
+        let gone = SocketAddr::from(([127, 0, 0, 1], 3));
+
+        registry.send_to(&[first, gone], "hello");
+        assert_eq!(first_receiver.try_recv().as_deref(), Some("hello"));
+        assert!(second_receiver.try_recv().is_none());
+    }
+
+    #[test]
+    fn dropped_requests_are_not_queued() {
+        let registry = Registry::default();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        let (sender, mut receiver) = queue::bounded(1, SlowConsumerPolicy::DropNewest);
+        registry.insert(addr, sender);
+
+        assert!(registry.queue_targeted(addr, "first").unwrap());
+        assert!(!registry.queue_targeted(addr, "second").unwrap());
+        assert_eq!(receiver.try_recv().as_deref(), Some("first"));
+
+        let (addr, _receiver) = connect(&registry, 2);
+        for _ in 0..8 {
+            registry.send_targeted(addr, "filler").unwrap();
+        }
+        assert!(registry.queue_targeted(addr, "latest").unwrap());
+    }
+
+    #[test]
+    fn take_over_closes_the_previous_session() {
+        let registry = Registry::default();
+        let (first, mut first_receiver) = connect(&registry, 1);
+        let (second, _) = connect(&registry, 2);
+
+        assert!(registry.claim_username("Alice", first, DuplicateLoginPolicy::TakeOver));
+        assert!(registry.claim_username("alice", second, DuplicateLoginPolicy::TakeOver));
+        assert_eq!(registry.address_of("Alice"), Some(second));
+        assert!(first_receiver.try_recv().is_none());
+
+        // The old session must not release the name of the new one
+        registry.release_username("Alice", first);
+        assert!(registry.is_online("alice"));
+        registry.release_usernames_of(second);
+        assert!(!registry.is_online("alice"));
+    }
+}
@@ -1,68 +1,245 @@
 use crate::client::Client;
-use crate::config::Config;
+use crate::connections::Rejection;
+use crate::db::{DirectMessage, Message, Reaction, User};
+use crate::rate_limit::Violations;
+use crate::state::ServerState;
+use crate::uploads::UploadError;
 use crate::{request_to_json, response_to_json, Result};
-use chrono::Utc;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
 use futures::SinkExt;
 use log::info;
 use serde_json::{json, Value};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{mpsc, Mutex};
+use tokio::time::{interval_at, sleep_until, timeout, Instant, MissedTickBehavior};
 use tokio_stream::StreamExt;
-use tokio_util::codec::{Framed, LinesCodec};
+use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
 
-lazy_static::lazy_static! {
-    static ref CONFIG: Config = Config::default();
-}
-
-// TODO: Check how to deal with the private messages if you have only SocketAddr and no username
-type Clients = Arc<Mutex<HashMap<SocketAddr, mpsc::UnboundedSender<String>>>>;
+// Only the checksum of a token is stored, the length just keeps requests small
+const MAX_TOKEN_LEN: usize = 256;
 
-async fn bind_server() -> Result<TcpListener> {
-    match TcpListener::bind(CONFIG.server_address.clone()).await {
+async fn bind_server(address: &str) -> Result<TcpListener> {
+    match TcpListener::bind(address).await {
         Ok(listener) => {
-            info!("Server is listening on {}", CONFIG.server_address);
+            info!("Server is listening on {address}");
             Ok(listener)
         }
         Err(e) => Err(format!("Could not bind the server to this address: {e}").into()),
     }
 }
 
-pub async fn run() -> Result<()> {
-    let listener = bind_server().await?;
-    let clients = Arc::new(Mutex::new(HashMap::new()));
+pub async fn run(state: Arc<ServerState>) -> Result<()> {
+    let listener = bind_server(&state.config.server_address).await?;
+    serve(listener, state).await
+}
+
+// Accepts connections until the listener fails, the tests serve on a random port
+async fn serve(listener: TcpListener, state: Arc<ServerState>) -> Result<()> {
+    tokio::spawn(log_stats(Arc::clone(&state)));
     loop {
         let (stream, addr) = listener.accept().await.unwrap();
-        let clients = Arc::clone(&clients);
+        let guard = match state.connection_limiter.acquire(addr.ip()) {
+            Ok(guard) => guard,
+            Err(rejection) => {
+                info!("Rejected a connection from {addr}: {rejection}");
+                tokio::spawn(reject_connection(stream, rejection));
+                continue;
+            }
+        };
+        let state = Arc::clone(&state);
         tokio::spawn(async move {
-            if let Err(e) = handle_client(stream, addr, &clients).await {
+            let _guard = guard;
+            if let Err(e) = handle_client(stream, addr, &state).await {
                 info!("{e}");
             }
         });
     }
 }
 
-async fn handle_client(stream: TcpStream, addr: SocketAddr, clients: &Clients) -> Result<()> {
+async fn reject_connection(stream: TcpStream, rejection: Rejection) {
     let mut lines = Framed::new(stream, LinesCodec::new());
+    let response = response_to_json!(503, "ServerFull", json!({ "reason": rejection.reason() }));
+    if let Err(e) = lines.send(response).await {
+        info!("Could not send a message to a rejected connection: {e}");
+    }
+}
+
+async fn handle_client(stream: TcpStream, addr: SocketAddr, state: &ServerState) -> Result<()> {
+    let mut lines = Framed::new(stream, LinesCodec::new());
+
+    let authorization = authorize_user(&mut lines, addr, state);
+    let user = match timeout(state.config.login_timeout, authorization).await {
+        Ok(user) => user?,
+        Err(_) => {
+            state.clients.release_usernames_of(addr);
+            if let Err(e) = lines.send(response_to_json!(408, "LoginTimeout")).await {
+                info!("Could not send a message to {addr}: {e}");
+            }
+            return Err(format!("{addr} did not log in in time").into());
+        }
+    };
+    let session_id = match state.db.open_session(&user, &addr.to_string()).await {
+        Ok(session_id) => session_id,
+        Err(e) => {
+            state.clients.release_username(&user.username, addr);
+            return Err(format!("Could not open a session for {addr}: {e}").into());
+        }
+    };
+    let mut client = Client::new(
+        &state.clients,
+        user,
+        addr,
+        state.config.client_queue_capacity,
+        state.config.slow_consumer_policy,
+    );
 
-    let username = authorize_user(&mut lines, addr).await?;
-    let mut client = Client::new(clients, username, addr).await;
+    new_connection_info(state, &client).await?;
+    if let Err(e) = deliver_direct_messages(state, &client).await {
+        info!("Could not deliver direct messages to {addr}: {e}");
+    }
+    // Everyone is in the default room, the unread counts tell the client which
+    // other rooms the user has joined
+    if let Err(e) = state
+        .db
+        .join_room(state.default_room_id, &client.user)
+        .await
+    {
+        info!("Could not add {addr} to the default room: {e}");
+    }
+    if let Err(e) = send_unread(state, &client).await {
+        info!("Could not send the unread counts to {addr}: {e}");
+    }
 
-    new_connection_info(clients, &client).await?;
+    let mut violations = Violations::new(
+        state.config.max_rate_limit_violations,
+        state.config.violation_decay,
+    );
+    let mut missed_heartbeats = 0;
+    let mut heartbeat = interval_at(
+        Instant::now() + state.config.heartbeat_interval,
+        state.config.heartbeat_interval,
+    );
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // A file transfer over its limit waits here, the next requests are read after it
+    let mut throttled: Option<(Value, usize)> = None;
+    let mut retry_at = Instant::now();
 
     loop {
         tokio::select! {
-            Some(msg) = client.rx.recv() => {
-                if let Err(e) = lines.send(&msg).await {
-                    info!("Could not send a message to {}: {e}", client.addr);
+            _ = heartbeat.tick() => {
+                // Its pongs wait behind the throttled request, which shows it is alive
+                if throttled.is_some() {
+                    missed_heartbeats = 0;
+                }
+                if missed_heartbeats >= state.config.max_missed_heartbeats {
+                    info!(
+                        "{} ({}) has missed {missed_heartbeats} heartbeats, disconnecting",
+                        client.user.username, client.addr
+                    );
                     break;
                 }
-            }
-            request = lines.next() => match request {
+                missed_heartbeats += 1;
+                let request = request_to_json!("Ping", json!({ "id": missed_heartbeats }));
+                if let Err(e) = state.clients.send_targeted(client.addr, &request) {
+                    info!("Error with {} occured: {e}", client.addr);
+                    break;
+                }
+            },
+            msg = client.rx.recv() => match msg {
+                Some(msg) => {
+                    if let Err(e) = send_pending(&mut lines, &mut client, msg).await {
+                        info!("Could not send a message to {}: {e}", client.addr);
+                        break;
+                    }
+                }
+                None => {
+                    info!(
+                        "{} ({}) has been disconnected by the server",
+                        client.user.username, client.addr
+                    );
+                    break;
+                }
+            },
+            _ = sleep_until(retry_at), if throttled.is_some() => {
+                let (request, bytes) = throttled.take().unwrap();
+                if let Err(retry_after) = state
+                    .transfer_limiter
+                    .check(&client.user.username, addr.ip(), bytes)
+                    .await
+                {
+                    throttled = Some((request, bytes));
+                    retry_at = Instant::now() + retry_after;
+                } else if let Err(e) = handle_request(state, &client, &request).await {
+                    info!("Error with {} occured: {e}", client.addr);
+                    break;
+                }
+            },
+            request = lines.next(), if throttled.is_none() => match request {
                 Some(Ok(request)) => {
-                    if let Err(e) = handle_request(clients, &client, &request).await {
+                    let json_request: Value = serde_json::from_str(&request).unwrap_or_default();
+                    let method = json_request.get("method").and_then(|v| v.as_str());
+                    // Answers to the heartbeat only show that the client is alive, they
+                    // don't count against the rate limit
+                    if method == Some("Pong") {
+                        missed_heartbeats = 0;
+                        continue;
+                    }
+
+                    // File transfers have their own limits and are slowed down to them,
+                    // so an upload or a download is never broken off
+                    if matches!(method, Some("UploadChunk") | Some("Download")) {
+                        // A download costs about as much as the chunk that is sent back
+                        let bytes = if method == Some("Download") {
+                            state.config.max_chunk_size / 3 * 4
+                        } else {
+                            request.len()
+                        };
+                        throttled = Some((json_request, bytes));
+                        retry_at = Instant::now();
+                        continue;
+                    }
+                    if let Err(retry_after) = state
+                        .rate_limiter
+                        .check(&client.user.username, addr.ip(), request.len())
+                        .await
+                    {
+                        if violations.record() {
+                            info!(
+                                "{} ({}) has exceeded the rate limit too many times",
+                                client.user.username, client.addr
+                            );
+                            break;
+                        }
+                        let response = response_to_json!(
+                            429,
+                            "RateLimited",
+                            json!({ "retry_after_ms": retry_after.as_millis() as u64 })
+                        );
+                        if let Err(e) = state.clients.send_targeted(client.addr, &response) {
+                            info!("Error with {} occured: {e}", client.addr);
+                            break;
+                        }
+                        continue;
+                    }
+
+                    // Only a numeric id is echoed, the body is not reflected back as is
+                    if method == Some("Ping") {
+                        let id = json_request
+                            .get("body")
+                            .and_then(|body| body.get("id"))
+                            .and_then(|id| id.as_u64());
+                        let response = request_to_json!("Pong", json!({ "id": id }));
+                        if let Err(e) = state.clients.send_targeted(client.addr, &response) {
+                            info!("Error with {} occured: {e}", client.addr);
+                            break;
+                        }
+                        continue;
+                    }
+
+                    if let Err(e) = handle_request(state, &client, &json_request).await {
                         info!("Error with {} occured: {e}", client.addr);
                         break;
                     }
@@ -76,51 +253,843 @@ async fn handle_client(stream: TcpStream, addr: SocketAddr, clients: &Clients) -
         }
     }
 
-    clients.lock().await.remove(&addr);
-    disconnection_info(clients, &client).await?;
+    state.clients.remove(&addr);
+    state.clients.release_username(&client.user.username, addr);
+    if client.rx.len() > 0 {
+        info!(
+            "{} messages to {} were not delivered",
+            client.rx.len(),
+            client.addr
+        );
+    }
+    state.rate_limiter.prune().await;
+    state.transfer_limiter.prune().await;
+    state.uploads.cancel_all(addr).await;
+    if let Err(e) = state.db.close_session(session_id).await {
+        info!("Could not close the session of {addr}: {e}");
+    }
+    disconnection_info(state, &client).await?;
+    Ok(())
+}
+
+// Writes everything that has been queued so far with a single flush
+async fn send_pending(
+    lines: &mut Framed<TcpStream, LinesCodec>,
+    client: &mut Client,
+    first: Arc<str>,
+) -> std::result::Result<(), LinesCodecError> {
+    lines.feed(first).await?;
+    while let Some(msg) = client.rx.try_recv() {
+        lines.feed(msg).await?;
+    }
+    SinkExt::<Arc<str>>::flush(lines).await
+}
+
+async fn handle_request(state: &ServerState, client: &Client, json_request: &Value) -> Result<()> {
+    let body = json_request.get("body");
+    match json_request.get("method").and_then(|v| v.as_str()) {
+        Some("SendMessage") => send_message(state, client, body).await,
+        Some("EditMessage") => edit_message(state, client, body).await,
+        Some("DeleteMessage") => delete_message(state, client, body).await,
+        Some("DirectMessage") => send_direct_message(state, client, body).await,
+        Some("JoinRoom") => join_room(state, client, body).await,
+        Some("MarkRead") => mark_read(state, client, body).await,
+        Some("GetMembers") => get_members(state, client, body).await,
+        Some("GetThread") => get_thread(state, client, body).await,
+        Some("AddReaction") => change_reaction(state, client, body, true).await,
+        Some("RemoveReaction") => change_reaction(state, client, body, false).await,
+        Some("GetProfile") => get_profile(state, client, body).await,
+        Some("UploadStart") => start_upload(state, client, body).await,
+        Some("UploadChunk") => upload_chunk(state, client, body).await,
+        Some("UploadFinish") => finish_upload(state, client, body).await,
+        Some("Download") => download(state, client, body).await,
+        _ => {
+            let response = response_to_json!(400, "BadRequest");
+            state.clients.send_targeted(client.addr, &response)
+        }
+    }
+}
+
+// The body is either the text of the message or `{ "body": text, "parent_id": id, "room_id": id }`,
+// where both ids are optional
+async fn send_message(state: &ServerState, client: &Client, body: Option<&Value>) -> Result<()> {
+    let (message, parent_id) = match body {
+        Some(Value::Object(body)) => (
+            body.get("body").and_then(|v| v.as_str()),
+            body.get("parent_id").and_then(|v| v.as_i64()),
+        ),
+        body => (body.and_then(|v| v.as_str()), None),
+    };
+
+    if !state.config.is_valid_message(message, client.addr)? {
+        let response = response_to_json!(400, "InvalidMessage");
+        return state.clients.send_targeted(client.addr, &response);
+    }
+    let room_id = match target_room(state, client, body).await? {
+        Some(room_id) => room_id,
+        None => return Ok(()),
+    };
+    // Replies can only be sent to the room of the parent message
+    let parent = match parent_id {
+        Some(parent_id) => match state.db.get_message(parent_id).await? {
+            Some(parent) if parent.room_id == room_id => Some(parent),
+            _ => {
+                let response = response_to_json!(400, "InvalidParent");
+                return state.clients.send_targeted(client.addr, &response);
+            }
+        },
+        None => None,
+    };
+
+    info!("{} sent a message to the server", client.user.username);
+    let message = state
+        .db
+        .add_message(
+            room_id,
+            &client.user,
+            message.unwrap().trim(),
+            parent_id,
+            None,
+        )
+        .await?;
+    let mut body = message_to_json(&message);
+    if let Some(parent) = parent {
+        body["parent"] = json!({
+            "id": parent.id,
+            "sender": parent.sender,
+            "data": if parent.deleted_at.is_some() { "" } else { &parent.body },
+        });
+    }
+    let request = request_to_json!("SendMessage", body);
+    send_to_room(state, room_id, Some(client.addr), &request).await?;
+    notify_mentioned(state, client, &message, &body).await?;
+    // The sender needs the id to edit or delete the message later
+    let response = response_to_json!(200, "OK", json!({ "id": message.id }));
+    state.clients.send_targeted(client.addr, &response)
+}
+
+// `@alice` in a message notifies alice even if she has not joined the room, but
+// then she only learns who mentioned her and in which room. Every online user is
+// notified once, and only about messages of others
+async fn notify_mentioned(
+    state: &ServerState,
+    client: &Client,
+    message: &Message,
+    body: &Value,
+) -> Result<()> {
+    if !message.body.contains('@') {
+        return Ok(());
+    }
+    let members: Vec<SocketAddr> = state
+        .db
+        .room_members(message.room_id)
+        .await?
+        .iter()
+        .filter_map(|username| state.clients.address_of(username))
+        .collect();
+    let request = request_to_json!("Mentioned", body);
+    let mut outside_request = None;
+    let mut notified = vec![client.addr];
+    for word in message
+        .body
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+    {
+        // "@bob," mentions bob, unless someone is called "bob,"
+        let trimmed = word.trim_end_matches(|c: char| c.is_ascii_punctuation());
+        let addr = state
+            .clients
+            .address_of(word)
+            .or_else(|| state.clients.address_of(trimmed));
+        let Some(addr) = addr.filter(|addr| !notified.contains(addr)) else {
+            continue;
+        };
+        notified.push(addr);
+        let request = if members.contains(&addr) {
+            &request
+        } else {
+            if outside_request.is_none() {
+                let room = state.db.room_name(message.room_id).await?;
+                let body = json!({ "sender": message.sender, "room": room });
+                outside_request = Some(request_to_json!("Mentioned", body));
+            }
+            outside_request.as_ref().unwrap()
+        };
+        if let Err(e) = state.clients.send_targeted(addr, request) {
+            info!("Could not notify {addr} about a mention: {e}");
+        }
+    }
     Ok(())
 }
 
-async fn handle_request(clients: &Clients, client: &Client, request: &str) -> Result<()> {
-    let json_request: Value = serde_json::from_str(request).unwrap();
-    let message = json_request.get("body").and_then(|v| v.as_str());
+// Sends the request to the members of the room who are online, except `skip`
+async fn send_to_room(
+    state: &ServerState,
+    room_id: i64,
+    skip: Option<SocketAddr>,
+    request: &str,
+) -> Result<()> {
+    let targets: Vec<SocketAddr> = state
+        .db
+        .room_members(room_id)
+        .await?
+        .iter()
+        .filter_map(|username| state.clients.address_of(username))
+        .filter(|addr| Some(*addr) != skip)
+        .collect();
+    state.clients.send_to(&targets, request);
+    Ok(())
+}
 
-    if CONFIG.is_valid_message(message, client.addr)? {
-        info!("{} sent a message to the server", client.username);
-        let now = Utc::now().format("%Y-%m-%d %H:%M:%S %z").to_string();
-        let request = request_to_json!(
-            "SendMessage",
-            json!({ "data": message.unwrap().to_string().trim(), "sender": client.username, "date": now })
-        );
-        broadcast(clients, client.addr, &request).await?;
+// Messages go to the default room unless the body has a `room_id`. Returns `None`
+// after answering the client when the user is not in the room
+async fn target_room(
+    state: &ServerState,
+    client: &Client,
+    body: Option<&Value>,
+) -> Result<Option<i64>> {
+    let room_id = match body
+        .and_then(|body| body.get("room_id"))
+        .and_then(|v| v.as_i64())
+    {
+        Some(room_id) => room_id,
+        None => return Ok(Some(state.default_room_id)),
+    };
+    if state.db.is_member(room_id, &client.user).await? {
+        Ok(Some(room_id))
     } else {
+        let response = response_to_json!(404, "RoomNotFound");
+        state.clients.send_targeted(client.addr, &response)?;
+        Ok(None)
+    }
+}
+
+// The body is the name of the room, it is created by the first user who joins it
+async fn join_room(state: &ServerState, client: &Client, body: Option<&Value>) -> Result<()> {
+    let name = body
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| format!("Invalid request from {}", client.addr))?;
+    if !is_valid_room_name(name) {
+        let response = response_to_json!(400, "InvalidRoomName");
+        return state.clients.send_targeted(client.addr, &response);
+    }
+    let room_id = state.db.add_room(name).await?;
+    state.db.join_room(room_id, &client.user).await?;
+    info!("{} joined #{name}", client.user.username);
+    send_unread(state, client).await
+}
+
+// Room names are shown after a `#`, so they are kept short and simple
+fn is_valid_room_name(name: &str) -> bool {
+    (1..=32).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+// The body is `{ "room_id": id }`, or `{ "username": sender }` for direct messages.
+// Everything in the conversation up to now is marked as read
+async fn mark_read(state: &ServerState, client: &Client, body: Option<&Value>) -> Result<()> {
+    let room_id = body
+        .and_then(|body| body.get("room_id"))
+        .and_then(|v| v.as_i64());
+    let username = body
+        .and_then(|body| body.get("username"))
+        .and_then(|v| v.as_str());
+    match (room_id, username) {
+        (Some(room_id), _) => state.db.mark_read(room_id, &client.user).await?,
+        (None, Some(username)) => match state.db.get_user(username).await? {
+            Some(peer) => state.db.mark_direct_read(&client.user, &peer).await?,
+            None => {
+                let response = response_to_json!(404, "UserNotFound");
+                return state.clients.send_targeted(client.addr, &response);
+            }
+        },
+        (None, None) => return Err(format!("Invalid request from {}", client.addr).into()),
+    }
+    send_unread(state, client).await
+}
+
+// The body is `{ "room_id": id }`, only members of the room can list the others
+async fn get_members(state: &ServerState, client: &Client, body: Option<&Value>) -> Result<()> {
+    let room_id = match target_room(state, client, body).await? {
+        Some(room_id) => room_id,
+        None => return Ok(()),
+    };
+    let members: Vec<Value> = state
+        .db
+        .room_members(room_id)
+        .await?
+        .into_iter()
+        .map(|username| {
+            let online = state.clients.is_online(&username);
+            json!({ "username": username, "online": online })
+        })
+        .collect();
+    let request = request_to_json!("Members", json!({ "room_id": room_id, "members": members }));
+    state.clients.send_targeted(client.addr, &request)
+}
+
+// The rooms the user has joined and the senders of unread direct messages
+async fn send_unread(state: &ServerState, client: &Client) -> Result<()> {
+    let unread = state.db.unread(&client.user).await?;
+    let (rooms, direct): (Vec<_>, Vec<_>) =
+        unread.iter().partition(|unread| unread.room_id.is_some());
+    let rooms: Vec<Value> = rooms
+        .iter()
+        .map(|unread| json!({ "id": unread.room_id, "name": unread.name, "unread": unread.count }))
+        .collect();
+    let direct: Vec<Value> = direct
+        .iter()
+        .map(|unread| json!({ "username": unread.name, "unread": unread.count }))
+        .collect();
+    let request = request_to_json!("UnreadCounts", json!({ "rooms": rooms, "direct": direct }));
+    state.clients.send_targeted(client.addr, &request)
+}
+
+// The body is `{ "to": username, "body": text }`, messages to offline users are
+// kept until they log in. Both users must have logged in with a token
+async fn send_direct_message(
+    state: &ServerState,
+    client: &Client,
+    body: Option<&Value>,
+) -> Result<()> {
+    let to = body
+        .and_then(|body| body.get("to"))
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| format!("Invalid request from {}", client.addr))?;
+    let message = body
+        .and_then(|body| body.get("body"))
+        .and_then(|v| v.as_str());
+
+    if !state.config.is_valid_message(message, client.addr)? {
         let response = response_to_json!(400, "InvalidMessage");
-        send_targeted(clients, client.addr, &response).await?;
+        return state.clients.send_targeted(client.addr, &response);
+    }
+    let recipient = match state.db.get_user(to).await? {
+        Some(recipient) => recipient,
+        None => {
+            let response = response_to_json!(404, "UserNotFound");
+            return state.clients.send_targeted(client.addr, &response);
+        }
+    };
+    // Anyone could log in as a user without a token and read the messages
+    // that were kept for them, or send some in their name
+    if !client.user.claimed || !recipient.claimed {
+        let response = response_to_json!(403, "TokenRequired");
+        return state.clients.send_targeted(client.addr, &response);
+    }
+
+    let message = state
+        .db
+        .add_direct_message(&client.user, &recipient, message.unwrap().trim())
+        .await?;
+    let request = request_to_json!("DirectMessage", direct_message_to_json(&message));
+    let recipient_addr = state.clients.address_of(&recipient.username);
+    if let Some(addr) = recipient_addr {
+        if let Ok(true) = state.clients.queue_targeted(addr, &request) {
+            state.db.mark_delivered(&recipient, message.id).await?;
+        }
+    }
+    // The sender sees the message in the conversation as well
+    if recipient_addr != Some(client.addr) {
+        state.clients.send_targeted(client.addr, &request)?;
+    }
+    Ok(())
+}
+
+// Direct messages that were sent while the user was offline, with their original dates
+async fn deliver_direct_messages(state: &ServerState, client: &Client) -> Result<()> {
+    if !client.user.claimed {
+        return Ok(());
+    }
+    let messages = state.db.undelivered_direct_messages(&client.user).await?;
+    if messages.is_empty() {
+        return Ok(());
+    }
+    let request = request_to_json!("UnreadDirectMessages", json!({ "count": messages.len() }));
+    state.clients.send_targeted(client.addr, &request)?;
+    // The messages after one that did not fit into the queue are kept for the next log in
+    let mut last_id = None;
+    for message in &messages {
+        let request = request_to_json!("DirectMessage", direct_message_to_json(message));
+        if !state.clients.queue_targeted(client.addr, &request)? {
+            break;
+        }
+        last_id = Some(message.id);
+    }
+    if let Some(last_id) = last_id {
+        state.db.mark_delivered(&client.user, last_id).await?;
+    }
+    Ok(())
+}
+
+fn direct_message_to_json(message: &DirectMessage) -> Value {
+    json!({
+        "id": message.id,
+        "sender": message.sender,
+        "recipient": message.recipient,
+        "data": message.body,
+        "date": message.created_at.format("%Y-%m-%d %H:%M:%S %z").to_string(),
+    })
+}
+
+async fn edit_message(state: &ServerState, client: &Client, body: Option<&Value>) -> Result<()> {
+    let id = message_id(body, client.addr)?;
+    let message = body
+        .and_then(|body| body.get("body"))
+        .and_then(|v| v.as_str());
+
+    if !state.config.is_valid_message(message, client.addr)? {
+        let response = response_to_json!(400, "InvalidMessage");
+        return state.clients.send_targeted(client.addr, &response);
+    }
+    if let Some(original) = can_change_message(state, client, id).await? {
+        info!("{} edited the message {id}", client.user.username);
+        let message = state
+            .db
+            .edit_message(id, &client.user, message.unwrap().trim())
+            .await?;
+        let date = message
+            .edited_at
+            .unwrap_or(message.created_at)
+            .format("%Y-%m-%d %H:%M:%S %z")
+            .to_string();
+        let request = request_to_json!(
+            "MessageEdited",
+            json!({ "id": message.id, "data": message.body, "date": date })
+        );
+        send_to_room(state, original.room_id, None, &request).await?;
     }
 
     Ok(())
 }
 
+async fn delete_message(state: &ServerState, client: &Client, body: Option<&Value>) -> Result<()> {
+    let id = message_id(body, client.addr)?;
+
+    if let Some(message) = can_change_message(state, client, id).await? {
+        info!("{} deleted the message {id}", client.user.username);
+        state.db.delete_message(id).await?;
+        let request = request_to_json!("MessageDeleted", json!({ "id": id }));
+        send_to_room(state, message.room_id, None, &request).await?;
+    }
+
+    Ok(())
+}
+
+async fn get_thread(state: &ServerState, client: &Client, body: Option<&Value>) -> Result<()> {
+    let id = message_id(body, client.addr)?;
+
+    // Threads of rooms the user has not joined look like they don't exist
+    let parent = match state.db.get_message(id).await? {
+        Some(parent) if state.db.is_member(parent.room_id, &client.user).await? => parent,
+        _ => {
+            let response = response_to_json!(404, "MessageNotFound");
+            return state.clients.send_targeted(client.addr, &response);
+        }
+    };
+    let mut replies = Vec::new();
+    for reply in state.db.replies(id).await? {
+        replies.push(message_with_reactions(state, &reply).await?);
+    }
+    let parent = message_with_reactions(state, &parent).await?;
+    let request = request_to_json!("Thread", json!({ "parent": parent, "replies": replies }));
+    state.clients.send_targeted(client.addr, &request)
+}
+
+// Both requests have the body `{ "message_id": id, "emoji": emoji }`
+async fn change_reaction(
+    state: &ServerState,
+    client: &Client,
+    body: Option<&Value>,
+    add: bool,
+) -> Result<()> {
+    let message_id = body
+        .and_then(|body| body.get("message_id"))
+        .and_then(|v| v.as_i64())
+        .ok_or_else(|| format!("Invalid request from {}", client.addr))?;
+    let emoji = body
+        .and_then(|body| body.get("emoji"))
+        .and_then(|v| v.as_str());
+
+    if !state.config.is_valid_reaction(emoji, client.addr)? {
+        let response = response_to_json!(400, "InvalidReaction");
+        return state.clients.send_targeted(client.addr, &response);
+    }
+    let room_id = match state.db.get_message(message_id).await? {
+        Some(message)
+            if message.deleted_at.is_none()
+                && state.db.is_member(message.room_id, &client.user).await? =>
+        {
+            message.room_id
+        }
+        _ => {
+            let response = response_to_json!(404, "MessageNotFound");
+            return state.clients.send_targeted(client.addr, &response);
+        }
+    };
+
+    if add {
+        state
+            .db
+            .add_reaction(message_id, &client.user, emoji.unwrap())
+            .await?;
+    } else {
+        state
+            .db
+            .remove_reaction(message_id, &client.user, emoji.unwrap())
+            .await?;
+    }
+    let reactions = state.db.reactions(message_id).await?;
+    let request = request_to_json!(
+        "Reactions",
+        json!({ "message_id": message_id, "reactions": reactions_to_json(&reactions) })
+    );
+    send_to_room(state, room_id, None, &request).await
+}
+
+// The body is the username
+async fn get_profile(state: &ServerState, client: &Client, body: Option<&Value>) -> Result<()> {
+    let username = body
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| format!("Invalid request from {}", client.addr))?;
+    let profile = match state.db.profile(username).await? {
+        Some(profile) => profile,
+        None => {
+            let response = response_to_json!(404, "UserNotFound");
+            return state.clients.send_targeted(client.addr, &response);
+        }
+    };
+    let date = |date: Option<DateTime<Utc>>| {
+        date.map(|date| date.format("%Y-%m-%d %H:%M:%S %z").to_string())
+    };
+    let request = request_to_json!(
+        "Profile",
+        json!({
+            "username": profile.username,
+            "online": state.clients.is_online(&profile.username),
+            "messages": profile.messages,
+            "first_seen": date(profile.first_seen),
+            "last_seen": date(profile.last_seen),
+        })
+    );
+    state.clients.send_targeted(client.addr, &request)
+}
+
+// The body is `{ "name": name, "size": bytes, "sha256": checksum }`, the client sends
+// the chunks after `UploadStarted` and waits for `UploadProgress` after each of them
+async fn start_upload(state: &ServerState, client: &Client, body: Option<&Value>) -> Result<()> {
+    let invalid_request = || format!("Invalid request from {}", client.addr);
+    let body = body.ok_or_else(invalid_request)?;
+    let name = body["name"].as_str().ok_or_else(invalid_request)?;
+    let size = body["size"].as_u64().ok_or_else(invalid_request)?;
+    let sha256 = body["sha256"].as_str().ok_or_else(invalid_request)?;
+
+    match state.uploads.start(client.addr, name, size, sha256).await {
+        Ok(upload_id) => {
+            let request = request_to_json!(
+                "UploadStarted",
+                json!({ "upload_id": upload_id, "name": name })
+            );
+            state.clients.send_targeted(client.addr, &request)
+        }
+        Err(e) => upload_error(state, client, e),
+    }
+}
+
+// The body is `{ "upload_id": id, "data": base64 }`
+async fn upload_chunk(state: &ServerState, client: &Client, body: Option<&Value>) -> Result<()> {
+    let upload_id = upload_id(body, client.addr)?;
+    let data = body
+        .and_then(|body| body["data"].as_str())
+        .and_then(|data| STANDARD.decode(data).ok());
+    let data = match data {
+        Some(data) => data,
+        None => {
+            state.uploads.cancel(client.addr, upload_id).await;
+            let response = response_to_json!(400, "InvalidChunk");
+            return state.clients.send_targeted(client.addr, &response);
+        }
+    };
+
+    let written = state.uploads.write_chunk(client.addr, upload_id, &data);
+    match written.await {
+        Ok(received) => {
+            let request = request_to_json!(
+                "UploadProgress",
+                json!({ "upload_id": upload_id, "received": received })
+            );
+            state.clients.send_targeted(client.addr, &request)
+        }
+        Err(e) => upload_error(state, client, e),
+    }
+}
+
+// The body is `{ "upload_id": id, "body": caption, "room_id": id }`, the caption and
+// the room are optional. The file is sent to the room as a message with an attachment
+async fn finish_upload(state: &ServerState, client: &Client, body: Option<&Value>) -> Result<()> {
+    let upload_id = upload_id(body, client.addr)?;
+    let caption = body.and_then(|body| body.get("body")).map(|v| v.as_str());
+    if let Some(caption) = caption {
+        if !state.config.is_valid_message(caption, client.addr)? {
+            state.uploads.cancel(client.addr, upload_id).await;
+            let response = response_to_json!(400, "InvalidMessage");
+            return state.clients.send_targeted(client.addr, &response);
+        }
+    }
+    let room_id = match target_room(state, client, body).await? {
+        Some(room_id) => room_id,
+        None => {
+            state.uploads.cancel(client.addr, upload_id).await;
+            return Ok(());
+        }
+    };
+    let blob = match state.uploads.finish(client.addr, upload_id).await {
+        Ok(blob) => blob,
+        Err(e) => return upload_error(state, client, e),
+    };
+
+    info!(
+        "{} uploaded {} ({} bytes)",
+        client.user.username, blob.name, blob.size
+    );
+    let attachment = state
+        .db
+        .add_attachment(&client.user, &blob.name, blob.size as i64, &blob.sha256)
+        .await?;
+    let caption = caption.flatten().unwrap_or_default();
+    let message = state
+        .db
+        .add_message(
+            room_id,
+            &client.user,
+            caption.trim(),
+            None,
+            Some(&attachment),
+        )
+        .await?;
+    let request = request_to_json!("SendMessage", message_to_json(&message));
+    send_to_room(state, room_id, None, &request).await
+}
+
+fn upload_error(state: &ServerState, client: &Client, error: UploadError) -> Result<()> {
+    info!("Upload from {} failed: {error}", client.addr);
+    let response = response_to_json!(error.status_code(), error.reason());
+    state.clients.send_targeted(client.addr, &response)
+}
+
+fn upload_id(body: Option<&Value>, client_addr: SocketAddr) -> Result<i64> {
+    body.and_then(|body| body.get("upload_id"))
+        .and_then(|v| v.as_i64())
+        .ok_or_else(|| format!("Invalid request from {}", client_addr).into())
+}
+
+// The body is `{ "attachment_id": id, "offset": bytes }`, the client asks for the
+// next chunk after writing the previous one
+async fn download(state: &ServerState, client: &Client, body: Option<&Value>) -> Result<()> {
+    let invalid_request = || format!("Invalid request from {}", client.addr);
+    let body = body.ok_or_else(invalid_request)?;
+    let attachment_id = body["attachment_id"].as_i64().ok_or_else(invalid_request)?;
+    let offset = body["offset"].as_u64().unwrap_or_default();
+
+    // Only the members of the room the file was sent to can download it
+    let is_member = match state.db.attachment_room(attachment_id).await? {
+        Some(room_id) => state.db.is_member(room_id, &client.user).await?,
+        None => false,
+    };
+    let attachment = match state.db.get_attachment(attachment_id).await? {
+        Some(attachment) if is_member && offset <= attachment.size as u64 => attachment,
+        _ => {
+            let response = response_to_json!(404, "AttachmentNotFound");
+            return state.clients.send_targeted(client.addr, &response);
+        }
+    };
+    let data = state
+        .uploads
+        .read_chunk(&attachment.sha256, offset)
+        .await
+        .map_err(|e| format!("Could not read attachment {attachment_id}: {e}"))?;
+    let request = request_to_json!(
+        "DownloadChunk",
+        json!({
+            "attachment_id": attachment.id,
+            "name": attachment.name,
+            "size": attachment.size,
+            "sha256": attachment.sha256,
+            "offset": offset,
+            "data": STANDARD.encode(data),
+        })
+    );
+    state.clients.send_targeted(client.addr, &request)
+}
+
+async fn message_with_reactions(state: &ServerState, message: &Message) -> Result<Value> {
+    let mut json = message_to_json(message);
+    json["reactions"] = reactions_to_json(&state.db.reactions(message.id).await?);
+    Ok(json)
+}
+
+fn reactions_to_json(reactions: &[Reaction]) -> Value {
+    reactions
+        .iter()
+        .map(|reaction| {
+            json!({
+                "emoji": reaction.emoji,
+                "count": reaction.usernames.len(),
+                "users": reaction.usernames,
+            })
+        })
+        .collect()
+}
+
+// The text of deleted messages is never sent to the clients
+fn message_to_json(message: &Message) -> Value {
+    let date = message
+        .created_at
+        .format("%Y-%m-%d %H:%M:%S %z")
+        .to_string();
+    let deleted = message.deleted_at.is_some();
+    let mut json = json!({
+        "id": message.id,
+        "room_id": message.room_id,
+        "data": if deleted { "" } else { &message.body },
+        "sender": message.sender,
+        "date": date,
+        "parent_id": message.parent_id,
+        "edited": message.edited_at.is_some(),
+        "deleted": deleted,
+    });
+    if let (Some(id), false) = (message.attachment_id, deleted) {
+        json["attachment"] = json!({
+            "id": id,
+            "name": message.attachment_name,
+            "size": message.attachment_size,
+        });
+    }
+    json
+}
+
+fn message_id(body: Option<&Value>, client_addr: SocketAddr) -> Result<i64> {
+    body.and_then(|body| body.get("id"))
+        .and_then(|v| v.as_i64())
+        .ok_or_else(|| format!("Invalid request from {}", client_addr).into())
+}
+
+// Only the sender of a message or a moderator can change it, the message is
+// returned to them and everyone else gets an error response
+async fn can_change_message(
+    state: &ServerState,
+    client: &Client,
+    id: i64,
+) -> Result<Option<Message>> {
+    let response = match state.db.get_message(id).await? {
+        Some(message)
+            if message.deleted_at.is_none()
+                && state.db.is_member(message.room_id, &client.user).await? =>
+        {
+            if message.sender == client.user.username
+                || state.config.is_moderator(&client.user.username)
+            {
+                return Ok(Some(message));
+            }
+            response_to_json!(403, "Forbidden")
+        }
+        _ => response_to_json!(404, "MessageNotFound"),
+    };
+    state.clients.send_targeted(client.addr, &response)?;
+    Ok(None)
+}
+
 async fn authorize_user(
     lines: &mut Framed<TcpStream, LinesCodec>,
     client_addr: SocketAddr,
-) -> Result<String> {
+    state: &ServerState,
+) -> Result<User> {
     loop {
-        let mut username = None;
+        let mut user = None;
         let request = match lines.next().await {
             Some(Ok(request)) => request,
             Some(Err(e)) => return Err(format!("Invalid request from {client_addr}: {e}").into()),
             None => return Err(format!("{client_addr} disconnected before entering username").into()),
         };
 
-        let json_request: Value = serde_json::from_str(&request).unwrap();
+        if let Some(retry_after) = state.failed_logins.retry_after(client_addr.ip()).await {
+            let response = response_to_json!(
+                429,
+                "TooManyFailedLogins",
+                json!({ "retry_after_ms": retry_after.as_millis() as u64 })
+            );
+            if let Err(e) = lines.send(&response).await {
+                info!("Could not send a message to {client_addr}: {e}");
+            }
+            return Err(format!("{client_addr} has failed to log in too many times").into());
+        }
+
+        let json_request: Value = serde_json::from_str(&request).unwrap_or_default();
         let (response, status_code) = match json_request.get("method").and_then(|v| v.as_str()) {
+            // The body is the username, or `{ "username": name, "token": secret }`.
+            // The first log in with a token claims the name, afterwards only that
+            // token can log in as the user
             Some("LogInUsername") => {
-                username = json_request.get("body").and_then(|v| v.as_str());
-                if CONFIG.is_valid_username(username, client_addr)? {
-                    (response_to_json!(200, "OK"), 200)
-                } else {
+                let (username, token) = match json_request.get("body") {
+                    Some(Value::Object(body)) => (
+                        body.get("username").and_then(|v| v.as_str()),
+                        body.get("token").and_then(|v| v.as_str()),
+                    ),
+                    body => (body.and_then(|v| v.as_str()), None),
+                };
+                let token_sha256 = token.map(|token| format!("{:x}", Sha256::digest(token)));
+                if !state.config.is_valid_username(username, client_addr)? {
                     (response_to_json!(400, "InvalidUsername"), 400)
+                } else if token.is_some_and(|token| token.is_empty() || token.len() > MAX_TOKEN_LEN) {
+                    (response_to_json!(400, "InvalidToken"), 400)
+                } else if state
+                    .config
+                    .is_reserved_username(username.unwrap(), token_sha256.as_deref())
+                {
+                    (response_to_json!(400, "ReservedUsername"), 400)
+                } else {
+                    let username = username.unwrap();
+                    // Checked before anything is stored or the username is claimed, so
+                    // a wrong token can't take over the session of the owner
+                    let verified = match (state.db.get_user(username).await?, &token_sha256) {
+                        // Moderators were checked against the config already, it wins
+                        // over a token that someone claimed the name with before
+                        (_, Some(_)) if state.config.is_moderator(username) => true,
+                        (Some(existing), Some(token_sha256)) if existing.claimed => {
+                            state.db.token_matches(&existing, token_sha256).await?
+                        }
+                        (Some(existing), None) => !existing.claimed,
+                        _ => true,
+                    };
+                    let registered = if verified {
+                        register_user(state, username, token_sha256.as_deref()).await?
+                    } else {
+                        None
+                    };
+                    match registered {
+                        None => {
+                            state.failed_logins.record(client_addr.ip()).await;
+                            match token {
+                                Some(_) => (response_to_json!(401, "InvalidToken"), 401),
+                                None => (response_to_json!(401, "TokenRequired"), 401),
+                            }
+                        }
+                        Some(_)
+                            if !state.clients.claim_username(
+                                username,
+                                client_addr,
+                                state.config.duplicate_login_policy,
+                            ) =>
+                        {
+                            (response_to_json!(409, "UsernameTaken"), 409)
+                        }
+                        Some(registered) => {
+                            user = Some(registered);
+                            (response_to_json!(200, "OK"), 200)
+                        }
+                    }
                 }
             }
             _ => (response_to_json!(400, "BadRequest"), 400),
@@ -131,57 +1100,966 @@ async fn authorize_user(
         }
 
         if status_code == 200 {
-            return Ok(username.unwrap().to_string());
+            return Ok(user.unwrap());
         }
     }
 }
 
-async fn new_connection_info(clients: &Clients, client: &Client) -> Result<()> {
-    let info = format!("{} has been connected to the server", &client.username);
+// Stores the user once the token has been checked, the token claims the name if
+// nobody has yet. Returns `None` if someone else claimed it in the meantime
+async fn register_user(
+    state: &ServerState,
+    username: &str,
+    token_sha256: Option<&str>,
+) -> Result<Option<User>> {
+    let mut user = match state.db.add_user(username).await {
+        Ok(user) => user,
+        Err(e) => return Err(format!("Could not register {username}: {e}").into()),
+    };
+    let verified = match token_sha256 {
+        Some(token_sha256) => {
+            state.db.claim_user(&user, token_sha256).await? || state.config.is_moderator(username)
+        }
+        None => !user.claimed,
+    };
+    // Only users who logged in with a token send and receive direct messages
+    user.claimed = token_sha256.is_some();
+    Ok(verified.then_some(user))
+}
+
+async fn new_connection_info(state: &ServerState, client: &Client) -> Result<()> {
+    let info = format!("{} has been connected to the server", &client.user.username);
     info!(
         "{} ({}) has been connected to the server",
-        client.username, client.addr
+        client.user.username, client.addr
     );
     let now = Utc::now().format("%Y-%m-%d %H:%M:%S %z").to_string();
     let request = request_to_json!("Connection", json!({ "data": info, "date": now }));
 
-    broadcast(clients, client.addr, &request).await?;
+    state.clients.broadcast(client.addr, &request);
     Ok(())
 }
 
-async fn disconnection_info(clients: &Clients, client: &Client) -> Result<()> {
-    let info = format!("{} has been disconnected from the server", &client.username);
+async fn disconnection_info(state: &ServerState, client: &Client) -> Result<()> {
+    let info = format!(
+        "{} has been disconnected from the server",
+        &client.user.username
+    );
     info!(
         "{} ({}) has been disconnected from the server",
-        client.username, client.addr
+        client.user.username, client.addr
     );
     let now = Utc::now().format("%Y-%m-%d %H:%M:%S %z").to_string();
     let request = request_to_json!("Connection", json!({ "data": info, "date": now }));
 
-    broadcast(clients, client.addr, &request).await?;
+    state.clients.broadcast(client.addr, &request);
     Ok(())
 }
 
-async fn broadcast(clients: &Clients, sender: SocketAddr, request: &str) -> Result<()> {
-    let mut clients = clients.lock().await;
-    for client in clients.iter_mut() {
-        if *client.0 != sender {
-            if let Err(e) = client.1.send(request.into()) {
-                info!("Could not send a message to {}: {e}", client.0);
+async fn log_stats(state: Arc<ServerState>) {
+    let mut interval = tokio::time::interval(state.config.stats_interval);
+    loop {
+        interval.tick().await;
+        let depths = state.clients.queue_depths();
+        let queued: usize = depths.iter().sum();
+        let max_depth = depths.iter().max().copied().unwrap_or_default();
+        info!(
+            "{} clients connected, {queued} messages queued (max queue depth {max_depth})",
+            state.clients.len()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, DuplicateLoginPolicy, Moderator, RateLimit};
+    use crate::db::MemoryStorage;
+    use std::time::Duration;
+
+    type Connection = Framed<TcpStream, LinesCodec>;
+
+    async fn start_server() -> SocketAddr {
+        start_server_with(Config::default()).await
+    }
+
+    async fn start_server_with(config: Config) -> SocketAddr {
+        let state = ServerState::new(config, Box::<MemoryStorage>::default())
+            .await
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, Arc::new(state)));
+        addr
+    }
+
+    async fn send(connection: &mut Connection, method: &str, body: impl Into<Value>) {
+        let body = body.into();
+        let request = json!({ "type": "request_c2s", "method": method, "body": body });
+        connection.send(request.to_string()).await.unwrap();
+    }
+
+    async fn receive(connection: &mut Connection) -> Value {
+        let line = timeout(Duration::from_secs(5), connection.next())
+            .await
+            .expect("the server did not answer in time")
+            .unwrap()
+            .unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    // The login is a username, or a username with a token
+    async fn connect(addr: SocketAddr, login: impl Into<Value>) -> Connection {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut connection = Framed::new(stream, LinesCodec::new());
+        send(&mut connection, "LogInUsername", login).await;
+        connection
+    }
+
+    // A successful log in ends with the unread counts, everything before them is skipped
+    async fn log_in(addr: SocketAddr, login: impl Into<Value>) -> (Connection, Value) {
+        let mut connection = connect(addr, login).await;
+        let response = receive(&mut connection).await;
+        if response["status_code"] == 200 {
+            while receive(&mut connection).await["method"] != "UnreadCounts" {}
+        }
+        (connection, response)
+    }
+
+    #[tokio::test]
+    async fn messages_are_broadcast_to_other_clients() {
+        let addr = start_server().await;
+        let (mut alice, response) = log_in(addr, "alice").await;
+        assert_eq!(response["status_code"], 200);
+        let (mut bob, response) = log_in(addr, "bob").await;
+        assert_eq!(response["status_code"], 200);
+        let connection = receive(&mut alice).await;
+        assert_eq!(connection["method"], "Connection");
+        assert_eq!(
+            connection["body"]["data"],
+            "bob has been connected to the server"
+        );
+
+        send(&mut alice, "SendMessage", " hello ").await;
+        let message = receive(&mut bob).await;
+        assert_eq!(message["method"], "SendMessage");
+        assert_eq!(message["body"]["id"], 1);
+        assert_eq!(message["body"]["room_id"], 1);
+        assert_eq!(message["body"]["data"], "hello");
+        assert_eq!(message["body"]["sender"], "alice");
+    }
+
+    #[tokio::test]
+    async fn connections_over_the_limit_are_rejected() {
+        let addr = start_server_with(Config {
+            max_connections: 1,
+            ..Config::default()
+        })
+        .await;
+        let (_alice, _) = log_in(addr, "alice").await;
+
+        let (_, response) = log_in(addr, "bob").await;
+        assert_eq!(response["status_code"], 503);
+        assert_eq!(response["message"], "ServerFull");
+        assert_eq!(response["body"]["reason"], "MaxConnections");
+    }
+
+    #[tokio::test]
+    async fn disconnections_are_announced() {
+        let addr = start_server().await;
+        let (mut alice, _) = log_in(addr, "alice").await;
+        let (bob, _) = log_in(addr, "bob").await;
+        receive(&mut alice).await;
+
+        drop(bob);
+        let connection = receive(&mut alice).await;
+        assert_eq!(connection["method"], "Connection");
+        assert_eq!(
+            connection["body"]["data"],
+            "bob has been disconnected from the server"
+        );
+    }
+
+    #[tokio::test]
+    async fn usernames_of_online_users_are_taken() {
+        let addr = start_server().await;
+        let (_alice, _) = log_in(addr, "alice").await;
+
+        let (_, response) = log_in(addr, "Alice").await;
+        assert_eq!(response["status_code"], 409);
+        assert_eq!(response["message"], "UsernameTaken");
+    }
+
+    #[tokio::test]
+    async fn invalid_messages_are_rejected() {
+        let addr = start_server().await;
+        let (mut alice, _) = log_in(addr, "alice").await;
+
+        send(&mut alice, "SendMessage", "   ").await;
+        let response = receive(&mut alice).await;
+        assert_eq!(response["status_code"], 400);
+        assert_eq!(response["message"], "InvalidMessage");
+    }
+
+    #[tokio::test]
+    async fn pings_echo_only_their_id_and_are_rate_limited() {
+        let addr = start_server().await;
+        let (mut alice, _) = log_in(addr, "alice").await;
+
+        send(&mut alice, "Ping", json!({ "id": 7, "data": "<script>" })).await;
+        let pong = receive(&mut alice).await;
+        assert_eq!(pong["method"], "Pong");
+        assert_eq!(pong["body"], json!({ "id": 7 }));
+
+        for _ in 0..10 {
+            send(&mut alice, "Ping", json!({ "id": 8 })).await;
+        }
+        let mut rate_limited = false;
+        for _ in 0..10 {
+            if receive(&mut alice).await["status_code"] == 429 {
+                rate_limited = true;
             }
         }
+        assert!(rate_limited);
     }
-    Ok(())
-}
 
-async fn send_targeted(clients: &Clients, target: SocketAddr, request: &str) -> Result<()> {
-    let mut clients = clients.lock().await;
-    if let Some(client) = clients.get_mut(&target) {
-        if let Err(e) = client.send(request.into()) {
-            info!("Could not send a message to {target}: {e}");
+    #[tokio::test]
+    async fn clients_that_do_not_log_in_in_time_are_disconnected() {
+        let addr = start_server_with(Config {
+            login_timeout: Duration::from_millis(100),
+            ..Config::default()
+        })
+        .await;
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut connection = Framed::new(stream, LinesCodec::new());
+
+        let response = receive(&mut connection).await;
+        assert_eq!(response["status_code"], 408);
+        assert_eq!(response["message"], "LoginTimeout");
+        assert!(connection.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn clients_that_miss_heartbeats_are_disconnected() {
+        let addr = start_server_with(Config {
+            heartbeat_interval: Duration::from_millis(50),
+            max_missed_heartbeats: 2,
+            ..Config::default()
+        })
+        .await;
+        let (mut alice, _) = log_in(addr, "alice").await;
+
+        // Pongs keep the client alive and don't count against the rate limit
+        for _ in 0..10 {
+            let ping = receive(&mut alice).await;
+            assert_eq!(ping["method"], "Ping");
+            send(&mut alice, "Pong", ping["body"].clone()).await;
         }
-    } else {
-        return Err(format!("Could not find a user: {}", target).into());
+        send(&mut alice, "SendMessage", "still here").await;
+        loop {
+            let response = receive(&mut alice).await;
+            if response["method"] != "Ping" {
+                assert_eq!(response["status_code"], 200);
+                break;
+            }
+        }
+
+        // Without pongs the connection is closed after the missed heartbeats
+        let mut pings = 0;
+        while let Some(ping) = timeout(Duration::from_secs(5), alice.next()).await.unwrap() {
+            assert!(ping.unwrap().contains("Ping"));
+            pings += 1;
+        }
+        assert!(pings >= 2);
+    }
+
+    #[tokio::test]
+    async fn senders_can_edit_and_delete_their_messages() {
+        let addr = start_server().await;
+        let (mut alice, _) = log_in(addr, "alice").await;
+        let (mut bob, _) = log_in(addr, "bob").await;
+        receive(&mut alice).await;
+
+        send(&mut alice, "SendMessage", "helo").await;
+        let response = receive(&mut alice).await;
+        assert_eq!(response["status_code"], 200);
+        let id = response["body"]["id"].as_i64().unwrap();
+        receive(&mut bob).await;
+
+        let edit = json!({ "id": id, "body": "hello" });
+        send(&mut alice, "EditMessage", edit.clone()).await;
+        for connection in [&mut alice, &mut bob] {
+            let edited = receive(connection).await;
+            assert_eq!(edited["method"], "MessageEdited");
+            assert_eq!(edited["body"]["id"], id);
+            assert_eq!(edited["body"]["data"], "hello");
+        }
+
+        send(&mut alice, "DeleteMessage", json!({ "id": id })).await;
+        for connection in [&mut alice, &mut bob] {
+            let deleted = receive(connection).await;
+            assert_eq!(deleted["method"], "MessageDeleted");
+            assert_eq!(deleted["body"]["id"], id);
+        }
+
+        send(&mut alice, "EditMessage", edit).await;
+        assert_eq!(receive(&mut alice).await["status_code"], 404);
+    }
+
+    #[tokio::test]
+    async fn only_moderators_can_change_messages_of_others() {
+        let config = Config {
+            moderators: vec![Moderator {
+                username: "Carol".to_string(),
+                token_sha256: format!("{:x}", Sha256::digest("carol's secret")),
+            }],
+            ..Config::default()
+        };
+        let addr = start_server_with(config).await;
+        let (mut alice, _) = log_in(addr, "alice").await;
+        send(&mut alice, "SendMessage", "hello").await;
+        let id = receive(&mut alice).await["body"]["id"].as_i64().unwrap();
+
+        let (mut bob, _) = log_in(addr, "bob").await;
+        send(&mut bob, "DeleteMessage", json!({ "id": id })).await;
+        let response = receive(&mut bob).await;
+        assert_eq!(response["status_code"], 403);
+        assert_eq!(response["message"], "Forbidden");
+
+        let (_, response) = log_in(addr, "carol").await;
+        assert_eq!(response["message"], "ReservedUsername");
+        let login = json!({ "username": "carol", "token": "guess" });
+        let (_, response) = log_in(addr, login).await;
+        assert_eq!(response["message"], "ReservedUsername");
+
+        let login = json!({ "username": "carol", "token": "carol's secret" });
+        let (mut carol, response) = log_in(addr, login).await;
+        assert_eq!(response["status_code"], 200);
+        send(&mut carol, "DeleteMessage", json!({ "id": id })).await;
+        let deleted = receive(&mut carol).await;
+        assert_eq!(deleted["method"], "MessageDeleted");
+        assert_eq!(deleted["body"]["id"], id);
+    }
+
+    #[tokio::test]
+    async fn replies_quote_their_parent_and_form_a_thread() {
+        let addr = start_server().await;
+        let (mut alice, _) = log_in(addr, "alice").await;
+        let (mut bob, _) = log_in(addr, "bob").await;
+        receive(&mut alice).await;
+        send(&mut alice, "SendMessage", "question").await;
+        let parent_id = receive(&mut alice).await["body"]["id"].as_i64().unwrap();
+        receive(&mut bob).await;
+
+        let reply = json!({ "body": "answer", "parent_id": parent_id });
+        send(&mut bob, "SendMessage", reply).await;
+        let message = receive(&mut alice).await;
+        assert_eq!(message["body"]["data"], "answer");
+        assert_eq!(message["body"]["parent_id"], parent_id);
+        assert_eq!(message["body"]["parent"]["sender"], "alice");
+        assert_eq!(message["body"]["parent"]["data"], "question");
+        receive(&mut bob).await;
+
+        send(&mut bob, "GetThread", json!({ "id": parent_id })).await;
+        let thread = receive(&mut bob).await;
+        assert_eq!(thread["method"], "Thread");
+        assert_eq!(thread["body"]["parent"]["data"], "question");
+        assert_eq!(thread["body"]["replies"][0]["data"], "answer");
+        assert_eq!(thread["body"]["replies"][0]["sender"], "bob");
+    }
+
+    #[tokio::test]
+    async fn replies_to_unknown_messages_are_rejected() {
+        let addr = start_server().await;
+        let (mut alice, _) = log_in(addr, "alice").await;
+
+        let reply = json!({ "body": "answer", "parent_id": 42 });
+        send(&mut alice, "SendMessage", reply).await;
+        let response = receive(&mut alice).await;
+        assert_eq!(response["status_code"], 400);
+        assert_eq!(response["message"], "InvalidParent");
+    }
+
+    #[tokio::test]
+    async fn reaction_counts_are_broadcast() {
+        let addr = start_server().await;
+        let (mut alice, _) = log_in(addr, "alice").await;
+        let (mut bob, _) = log_in(addr, "bob").await;
+        receive(&mut alice).await;
+        send(&mut alice, "SendMessage", "hello").await;
+        let id = receive(&mut alice).await["body"]["id"].as_i64().unwrap();
+        receive(&mut bob).await;
+
+        let thumbs_up = json!({ "message_id": id, "emoji": "👍" });
+        let mut counts = Vec::new();
+        for (sender, method) in [
+            ("bob", "AddReaction"),
+            ("bob", "AddReaction"),
+            ("alice", "AddReaction"),
+            ("bob", "RemoveReaction"),
+        ] {
+            let connection = if sender == "bob" {
+                &mut bob
+            } else {
+                &mut alice
+            };
+            send(connection, method, thumbs_up.clone()).await;
+            let reactions = receive(&mut alice).await;
+            assert_eq!(reactions["method"], "Reactions");
+            assert_eq!(reactions["body"]["message_id"], id);
+            counts.push(reactions["body"]["reactions"][0]["count"].clone());
+            assert_eq!(receive(&mut bob).await, reactions);
+        }
+        assert_eq!(counts, [1, 1, 2, 1]);
+
+        send(&mut bob, "GetThread", json!({ "id": id })).await;
+        let thread = loop {
+            let request = receive(&mut bob).await;
+            if request["method"] == "Thread" {
+                break request;
+            }
+        };
+        assert_eq!(thread["body"]["parent"]["reactions"][0]["emoji"], "👍");
+        assert_eq!(
+            thread["body"]["parent"]["reactions"][0]["users"],
+            json!(["alice"])
+        );
+
+        let letter = json!({ "message_id": id, "emoji": "a" });
+        send(&mut bob, "AddReaction", letter).await;
+        assert_eq!(receive(&mut bob).await["message"], "InvalidReaction");
+    }
+
+    #[tokio::test]
+    async fn profiles_show_activity_of_users() {
+        let addr = start_server().await;
+        let (mut alice, _) = log_in(addr, "alice").await;
+        send(&mut alice, "SendMessage", "hello").await;
+        receive(&mut alice).await;
+
+        send(&mut alice, "GetProfile", "alice").await;
+        let profile = receive(&mut alice).await;
+        assert_eq!(profile["method"], "Profile");
+        assert_eq!(profile["body"]["online"], true);
+        assert_eq!(profile["body"]["messages"], 1);
+        assert!(profile["body"]["first_seen"].is_string());
+
+        send(&mut alice, "GetProfile", "bob").await;
+        assert_eq!(receive(&mut alice).await["message"], "UserNotFound");
+    }
+
+    #[tokio::test]
+    async fn files_are_uploaded_in_chunks_and_downloaded() {
+        let upload_dir = std::env::temp_dir().join(format!("uploads-{}", std::process::id()));
+        let addr = start_server_with(Config {
+            upload_dir: upload_dir.clone(),
+            max_upload_size: 10,
+            max_chunk_size: 4,
+            ..Config::default()
+        })
+        .await;
+        let (mut alice, _) = log_in(addr, "alice").await;
+        let (mut bob, _) = log_in(addr, "bob").await;
+        receive(&mut alice).await;
+
+        let file = b"hello file";
+        let sha256 = format!("{:x}", sha2::Sha256::digest(file));
+        let start = json!({ "name": "hello.txt", "size": file.len(), "sha256": sha256 });
+        send(&mut alice, "UploadStart", start).await;
+        let started = receive(&mut alice).await;
+        assert_eq!(started["method"], "UploadStarted");
+        let upload_id = started["body"]["upload_id"].clone();
+        for chunk in file.chunks(4) {
+            let chunk = json!({ "upload_id": upload_id, "data": STANDARD.encode(chunk) });
+            send(&mut alice, "UploadChunk", chunk).await;
+            assert_eq!(receive(&mut alice).await["method"], "UploadProgress");
+        }
+        let finish = json!({ "upload_id": upload_id, "body": "a file" });
+        send(&mut alice, "UploadFinish", finish).await;
+        let message = receive(&mut bob).await;
+        assert_eq!(receive(&mut alice).await, message);
+        assert_eq!(message["body"]["data"], "a file");
+        assert_eq!(message["body"]["attachment"]["name"], "hello.txt");
+        assert_eq!(message["body"]["attachment"]["size"], 10);
+
+        let attachment_id = message["body"]["attachment"]["id"].clone();
+        let mut downloaded = Vec::new();
+        while downloaded.len() < file.len() {
+            let download = json!({ "attachment_id": attachment_id, "offset": downloaded.len() });
+            send(&mut bob, "Download", download).await;
+            let chunk = receive(&mut bob).await;
+            assert_eq!(chunk["body"]["sha256"], sha256);
+            let data = chunk["body"]["data"].as_str().unwrap();
+            downloaded.extend(STANDARD.decode(data).unwrap());
+        }
+        assert_eq!(downloaded, file);
+
+        // The checksum is checked after the last chunk
+        let start = json!({ "name": "hello.txt", "size": 4, "sha256": sha256 });
+        send(&mut alice, "UploadStart", start).await;
+        let upload_id = receive(&mut alice).await["body"]["upload_id"].clone();
+        let chunk = json!({ "upload_id": upload_id, "data": STANDARD.encode("evil") });
+        send(&mut alice, "UploadChunk", chunk).await;
+        receive(&mut alice).await;
+        let finish = json!({ "upload_id": upload_id });
+        send(&mut alice, "UploadFinish", finish).await;
+        assert_eq!(receive(&mut alice).await["message"], "ChecksumMismatch");
+
+        let start = json!({ "name": "large.bin", "size": 11, "sha256": sha256 });
+        send(&mut alice, "UploadStart", start).await;
+        assert_eq!(receive(&mut alice).await["message"], "FileTooLarge");
+        std::fs::remove_dir_all(upload_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn uploads_are_throttled_and_limited_by_the_stored_size() {
+        let upload_dir = std::env::temp_dir().join(format!("uploads-full-{}", std::process::id()));
+        let addr = start_server_with(Config {
+            upload_dir: upload_dir.clone(),
+            max_upload_size: 10,
+            max_upload_dir_size: 15,
+            max_chunk_size: 4,
+            user_transfer_limit: RateLimit {
+                messages_per_second: 20.0,
+                messages_burst: 1.0,
+                bytes_per_second: 1e6,
+                bytes_burst: 1e6,
+            },
+            ..Config::default()
+        })
+        .await;
+        let (mut alice, _) = log_in(addr, "alice").await;
+
+        let file = b"hello file";
+        let sha256 = format!("{:x}", sha2::Sha256::digest(file));
+        let start = json!({ "name": "hello.txt", "size": file.len(), "sha256": sha256 });
+        send(&mut alice, "UploadStart", start.clone()).await;
+        let upload_id = receive(&mut alice).await["body"]["upload_id"].clone();
+        let started = std::time::Instant::now();
+        for chunk in file.chunks(4) {
+            let chunk = json!({ "upload_id": upload_id, "data": STANDARD.encode(chunk) });
+            send(&mut alice, "UploadChunk", chunk).await;
+        }
+        for _ in file.chunks(4) {
+            assert_eq!(receive(&mut alice).await["method"], "UploadProgress");
+        }
+        // Chunks over the limit wait instead of being rejected
+        assert!(started.elapsed() >= Duration::from_millis(90));
+        send(&mut alice, "UploadFinish", json!({ "upload_id": upload_id })).await;
+        assert_eq!(receive(&mut alice).await["method"], "SendMessage");
+
+        send(&mut alice, "UploadStart", start).await;
+        let response = receive(&mut alice).await;
+        assert_eq!(response["status_code"], 507);
+        assert_eq!(response["message"], "StorageFull");
+
+        // A cancelled upload gives its space back
+        let start = json!({ "name": "small.txt", "size": 5, "sha256": sha256 });
+        send(&mut alice, "UploadStart", start.clone()).await;
+        let upload_id = receive(&mut alice).await["body"]["upload_id"].clone();
+        let chunk = json!({ "upload_id": upload_id, "data": "not base64!" });
+        send(&mut alice, "UploadChunk", chunk).await;
+        assert_eq!(receive(&mut alice).await["message"], "InvalidChunk");
+        send(&mut alice, "UploadStart", start).await;
+        assert_eq!(receive(&mut alice).await["method"], "UploadStarted");
+        std::fs::remove_dir_all(upload_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn throttled_uploads_keep_the_connection_alive() {
+        let upload_dir =
+            std::env::temp_dir().join(format!("uploads-throttled-{}", std::process::id()));
+        let addr = start_server_with(Config {
+            upload_dir: upload_dir.clone(),
+            max_chunk_size: 2,
+            heartbeat_interval: Duration::from_millis(100),
+            max_missed_heartbeats: 1,
+            user_transfer_limit: RateLimit {
+                messages_per_second: 5.0,
+                messages_burst: 1.0,
+                bytes_per_second: 1e6,
+                bytes_burst: 1e6,
+            },
+            ..Config::default()
+        })
+        .await;
+        let (mut alice, _) = log_in(addr, "alice").await;
+
+        let file = b"hello file";
+        let sha256 = format!("{:x}", sha2::Sha256::digest(file));
+        let start = json!({ "name": "hello.txt", "size": file.len(), "sha256": sha256 });
+        send(&mut alice, "UploadStart", start).await;
+        let upload_id = receive(&mut alice).await["body"]["upload_id"].clone();
+        for chunk in file.chunks(2) {
+            let chunk = json!({ "upload_id": upload_id, "data": STANDARD.encode(chunk) });
+            send(&mut alice, "UploadChunk", chunk).await;
+        }
+        // The pings are sent while the chunks wait for the limit, and the pongs are
+        // only read after them
+        let (mut progress, mut pings) = (0, 0);
+        while progress < file.chunks(2).count() {
+            let message = receive(&mut alice).await;
+            match message["method"].as_str() {
+                Some("UploadProgress") => progress += 1,
+                Some("Ping") => {
+                    pings += 1;
+                    send(&mut alice, "Pong", message["body"].clone()).await;
+                }
+                _ => panic!("unexpected {message}"),
+            }
+        }
+        assert!(pings >= 2);
+        send(&mut alice, "UploadFinish", json!({ "upload_id": upload_id })).await;
+        while receive(&mut alice).await["method"] == "Ping" {}
+        std::fs::remove_dir_all(upload_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn direct_messages_wait_for_offline_users() {
+        let addr = start_server().await;
+        let bob_login = json!({ "username": "bob", "token": "bob's secret" });
+        let (mut bob, _) = log_in(addr, bob_login.clone()).await;
+        drop(bob);
+        let (mut alice, _) = log_in(addr, json!({ "username": "alice", "token": "1234" })).await;
+
+        let dm = json!({ "to": "bob", "body": "are you there?" });
+        send(&mut alice, "DirectMessage", dm).await;
+        let sent = receive(&mut alice).await;
+        assert_eq!(sent["method"], "DirectMessage");
+        assert_eq!(sent["body"]["recipient"], "bob");
+
+        bob = connect(addr, bob_login.clone()).await;
+        assert_eq!(receive(&mut bob).await["status_code"], 200);
+        let unread = receive(&mut bob).await;
+        assert_eq!(unread["method"], "UnreadDirectMessages");
+        assert_eq!(unread["body"]["count"], 1);
+        let delivered = receive(&mut bob).await;
+        assert_eq!(delivered, sent);
+        let counts = receive(&mut bob).await;
+        assert_eq!(counts["method"], "UnreadCounts");
+        assert_eq!(
+            counts["body"]["direct"],
+            json!([{ "username": "alice", "unread": 1 }])
+        );
+
+        // Online users get them right away, and only once
+        let dm = json!({ "to": "alice", "body": "yes" });
+        send(&mut bob, "DirectMessage", dm).await;
+        let reply = receive(&mut alice).await;
+        assert_eq!(reply["method"], "Connection");
+        let reply = receive(&mut alice).await;
+        assert_eq!(reply["body"]["data"], "yes");
+        assert_eq!(receive(&mut bob).await, reply);
+
+        drop(bob);
+        receive(&mut alice).await;
+        (bob, _) = log_in(addr, bob_login).await;
+        send(
+            &mut bob,
+            "DirectMessage",
+            json!({ "to": "carol", "body": "hi" }),
+        )
+        .await;
+        assert_eq!(receive(&mut bob).await["message"], "UserNotFound");
+    }
+
+    #[tokio::test]
+    async fn direct_messages_need_users_with_tokens() {
+        let addr = start_server().await;
+        let (mut alice, _) = log_in(addr, json!({ "username": "alice", "token": "1234" })).await;
+        let (carol, _) = log_in(addr, "carol").await;
+        drop(carol);
+        receive(&mut alice).await;
+        receive(&mut alice).await;
+
+        let dm = json!({ "to": "carol", "body": "are you there?" });
+        send(&mut alice, "DirectMessage", dm).await;
+        let response = receive(&mut alice).await;
+        assert_eq!(response["status_code"], 403);
+        assert_eq!(response["message"], "TokenRequired");
+
+        // Nothing was kept for whoever logs in as carol next
+        let (mut carol, _) = log_in(addr, "carol").await;
+        let dm = json!({ "to": "alice", "body": "it's me, carol" });
+        send(&mut carol, "DirectMessage", dm).await;
+        assert_eq!(receive(&mut carol).await["message"], "TokenRequired");
+    }
+
+    #[tokio::test]
+    async fn tokens_protect_claimed_usernames() {
+        let config = Config {
+            duplicate_login_policy: DuplicateLoginPolicy::TakeOver,
+            ..Config::default()
+        };
+        let addr = start_server_with(config).await;
+        let (mut alice, response) =
+            log_in(addr, json!({ "username": "Alice", "token": "secret" })).await;
+        assert_eq!(response["status_code"], 200);
+
+        let (_, response) = log_in(addr, "alice").await;
+        assert_eq!(response["status_code"], 401);
+        assert_eq!(response["message"], "TokenRequired");
+        let (_, response) = log_in(addr, json!({ "username": "ALICE", "token": "guess" })).await;
+        assert_eq!(response["status_code"], 401);
+        assert_eq!(response["message"], "InvalidToken");
+        let (_, response) = log_in(addr, json!({ "username": "bob", "token": "" })).await;
+        assert_eq!(response["message"], "InvalidToken");
+
+        // A wrong token doesn't take over the session
+        send(&mut alice, "SendMessage", "still here").await;
+        assert_eq!(receive(&mut alice).await["status_code"], 200);
+
+        let (mut alice, response) =
+            log_in(addr, json!({ "username": "alice", "token": "secret" })).await;
+        assert_eq!(response["status_code"], 200);
+        send(&mut alice, "GetProfile", "ALICE").await;
+        let mut profile = receive(&mut alice).await;
+        // The end of the previous session may be announced first
+        while profile["method"] == "Connection" {
+            profile = receive(&mut alice).await;
+        }
+        assert_eq!(profile["body"]["username"], "Alice");
+        assert_eq!(profile["body"]["messages"], 1);
+    }
+
+    #[tokio::test]
+    async fn failed_logins_are_limited_per_ip() {
+        let addr = start_server_with(Config {
+            max_failed_logins: 2,
+            ..Config::default()
+        })
+        .await;
+        let (bob, _) = log_in(addr, json!({ "username": "bob", "token": "bob's secret" })).await;
+        drop(bob);
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut mallory = Framed::new(stream, LinesCodec::new());
+        mallory.send("not json").await.unwrap();
+        assert_eq!(receive(&mut mallory).await["message"], "BadRequest");
+        for guess in ["1234", "abcd"] {
+            send(&mut mallory, "LogInUsername", json!({ "username": "bob", "token": guess })).await;
+            assert_eq!(receive(&mut mallory).await["message"], "InvalidToken");
+        }
+        send(&mut mallory, "LogInUsername", json!({ "username": "bob", "token": "?" })).await;
+        let response = receive(&mut mallory).await;
+        assert_eq!(response["status_code"], 429);
+        assert_eq!(response["message"], "TooManyFailedLogins");
+        assert!(mallory.next().await.is_none());
+
+        // Reconnecting doesn't help, not even with the right token
+        let (_, response) = log_in(addr, json!({ "username": "bob", "token": "bob's secret" })).await;
+        assert_eq!(response["message"], "TooManyFailedLogins");
+    }
+
+    #[tokio::test]
+    async fn unread_counts_follow_read_markers() {
+        let addr = start_server().await;
+        let alice_login = json!({ "username": "alice", "token": "1234" });
+        let (mut bob, _) = log_in(addr, json!({ "username": "bob", "token": "5678" })).await;
+        let (mut alice, _) = log_in(addr, alice_login.clone()).await;
+        receive(&mut bob).await;
+        send(&mut alice, "JoinRoom", "Random!").await;
+        assert_eq!(receive(&mut alice).await["message"], "InvalidRoomName");
+        send(&mut alice, "JoinRoom", "random").await;
+        let counts = receive(&mut alice).await;
+        assert_eq!(counts["method"], "UnreadCounts");
+        let rooms = counts["body"]["rooms"].as_array().unwrap();
+        let names: Vec<&str> = rooms
+            .iter()
+            .map(|room| room["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["general", "random"]);
+        let random_id = rooms[1]["id"].as_i64().unwrap();
+        drop(alice);
+        receive(&mut bob).await;
+
+        let in_random = json!({ "body": "anyone?", "room_id": random_id });
+        send(&mut bob, "SendMessage", in_random.clone()).await;
+        assert_eq!(receive(&mut bob).await["message"], "RoomNotFound");
+        send(&mut bob, "JoinRoom", "random").await;
+        receive(&mut bob).await;
+        send(&mut bob, "SendMessage", in_random).await;
+        assert_eq!(receive(&mut bob).await["status_code"], 200);
+        send(&mut bob, "SendMessage", "hello").await;
+        assert_eq!(receive(&mut bob).await["status_code"], 200);
+        send(
+            &mut bob,
+            "DirectMessage",
+            json!({ "to": "alice", "body": "hi" }),
+        )
+        .await;
+        receive(&mut bob).await;
+
+        (alice, _) = log_in(addr, alice_login).await;
+        send(&mut alice, "MarkRead", json!({ "room_id": random_id })).await;
+        let counts = receive(&mut alice).await;
+        assert_eq!(
+            counts["body"]["rooms"],
+            json!([
+                { "id": rooms[0]["id"], "name": "general", "unread": 1 },
+                { "id": random_id, "name": "random", "unread": 0 },
+            ])
+        );
+        assert_eq!(
+            counts["body"]["direct"],
+            json!([{ "username": "bob", "unread": 1 }])
+        );
+        send(&mut alice, "MarkRead", json!({ "username": "bob" })).await;
+        let counts = receive(&mut alice).await;
+        assert_eq!(counts["body"]["direct"], json!([]));
+        assert_eq!(counts["body"]["rooms"][0]["unread"], 1);
+    }
+
+    #[tokio::test]
+    async fn members_of_joined_rooms_can_be_listed() {
+        let addr = start_server().await;
+        let (mut alice, _) = log_in(addr, "alice").await;
+        let (mut bob, _) = log_in(addr, "bob").await;
+        receive(&mut alice).await;
+        send(&mut alice, "JoinRoom", "random").await;
+        let counts = receive(&mut alice).await;
+        let random_id = counts["body"]["rooms"][1]["id"].clone();
+
+        send(&mut alice, "GetMembers", json!({ "room_id": random_id })).await;
+        let members = receive(&mut alice).await;
+        assert_eq!(members["method"], "Members");
+        assert_eq!(
+            members["body"],
+            json!({ "room_id": random_id, "members": [{ "username": "alice", "online": true }] })
+        );
+        send(&mut bob, "GetMembers", json!({ "room_id": random_id })).await;
+        assert_eq!(receive(&mut bob).await["message"], "RoomNotFound");
+
+        drop(bob);
+        receive(&mut alice).await;
+        send(&mut alice, "GetMembers", json!({})).await;
+        assert_eq!(
+            receive(&mut alice).await["body"]["members"],
+            json!([
+                { "username": "alice", "online": true },
+                { "username": "bob", "online": false },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn room_messages_reach_only_members() {
+        let addr = start_server().await;
+        let (mut alice, _) = log_in(addr, "alice").await;
+        let (mut bob, _) = log_in(addr, "bob").await;
+        receive(&mut alice).await;
+        let (mut carol, _) = log_in(addr, "carol").await;
+        receive(&mut alice).await;
+        receive(&mut bob).await;
+        send(&mut alice, "JoinRoom", "random").await;
+        let random_id = receive(&mut alice).await["body"]["rooms"][1]["id"].clone();
+        send(&mut bob, "JoinRoom", "random").await;
+        receive(&mut bob).await;
+
+        let in_random = json!({ "body": "secret", "room_id": random_id });
+        send(&mut alice, "SendMessage", in_random).await;
+        assert_eq!(receive(&mut bob).await["body"]["data"], "secret");
+        let id = receive(&mut alice).await["body"]["id"].clone();
+        let reaction = json!({ "message_id": id, "emoji": "👍" });
+        send(&mut alice, "AddReaction", reaction).await;
+        assert_eq!(receive(&mut alice).await["method"], "Reactions");
+        assert_eq!(receive(&mut bob).await["method"], "Reactions");
+        send(&mut alice, "EditMessage", json!({ "id": id, "body": "edited" })).await;
+        assert_eq!(receive(&mut alice).await["method"], "MessageEdited");
+        send(&mut alice, "DeleteMessage", json!({ "id": id })).await;
+        assert_eq!(receive(&mut alice).await["method"], "MessageDeleted");
+
+        // Carol only gets the messages of the default room
+        send(&mut bob, "SendMessage", "hello").await;
+        let message = receive(&mut carol).await;
+        assert_eq!(message["method"], "SendMessage");
+        assert_eq!(message["body"]["data"], "hello");
+    }
+
+    #[tokio::test]
+    async fn messages_of_other_rooms_look_missing() {
+        let upload_dir = std::env::temp_dir().join(format!("uploads-rooms-{}", std::process::id()));
+        let addr = start_server_with(Config {
+            upload_dir: upload_dir.clone(),
+            ..Config::default()
+        })
+        .await;
+        let (mut alice, _) = log_in(addr, "alice").await;
+        let (mut bob, _) = log_in(addr, "bob").await;
+        receive(&mut alice).await;
+        send(&mut alice, "JoinRoom", "random").await;
+        let random_id = receive(&mut alice).await["body"]["rooms"][1]["id"].clone();
+
+        let file = b"hello file";
+        let sha256 = format!("{:x}", sha2::Sha256::digest(file));
+        let start = json!({ "name": "hello.txt", "size": file.len(), "sha256": sha256 });
+        send(&mut alice, "UploadStart", start).await;
+        let upload_id = receive(&mut alice).await["body"]["upload_id"].clone();
+        let chunk = json!({ "upload_id": upload_id, "data": STANDARD.encode(file) });
+        send(&mut alice, "UploadChunk", chunk).await;
+        receive(&mut alice).await;
+        let finish = json!({ "upload_id": upload_id, "room_id": random_id });
+        send(&mut alice, "UploadFinish", finish).await;
+        let message = receive(&mut alice).await;
+        let id = message["body"]["id"].clone();
+        let attachment_id = message["body"]["attachment"]["id"].clone();
+
+        for (method, body) in [
+            ("GetThread", json!({ "id": id })),
+            ("AddReaction", json!({ "message_id": id, "emoji": "👍" })),
+            ("EditMessage", json!({ "id": id, "body": "mine now" })),
+            ("DeleteMessage", json!({ "id": id })),
+        ] {
+            send(&mut bob, method, body).await;
+            let response = receive(&mut bob).await;
+            assert_eq!(response["status_code"], 404);
+            assert_eq!(response["message"], "MessageNotFound");
+        }
+        let download = json!({ "attachment_id": attachment_id, "offset": 0 });
+        send(&mut bob, "Download", download.clone()).await;
+        assert_eq!(receive(&mut bob).await["message"], "AttachmentNotFound");
+
+        send(&mut bob, "JoinRoom", "random").await;
+        receive(&mut bob).await;
+        send(&mut bob, "Download", download).await;
+        assert_eq!(receive(&mut bob).await["method"], "DownloadChunk");
+        std::fs::remove_dir_all(upload_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn mentioned_users_are_notified_once() {
+        let addr = start_server().await;
+        let (mut alice, _) = log_in(addr, "alice").await;
+        let (mut bob, _) = log_in(addr, "bob").await;
+        receive(&mut alice).await;
+        let (mut carol, _) = log_in(addr, "carol").await;
+        receive(&mut alice).await;
+        receive(&mut bob).await;
+
+        let text = "@bob, @Carol and @dave: ask @bob and @alice";
+        send(&mut alice, "SendMessage", text).await;
+        for connection in [&mut bob, &mut carol] {
+            let message = receive(connection).await;
+            assert_eq!(message["method"], "SendMessage");
+            let mention = receive(connection).await;
+            assert_eq!(mention["method"], "Mentioned");
+            assert_eq!(mention["body"], message["body"]);
+        }
+        assert_eq!(receive(&mut alice).await["status_code"], 200);
+
+        send(&mut alice, "SendMessage", "no mentions").await;
+        for connection in [&mut bob, &mut carol] {
+            assert_eq!(receive(connection).await["body"]["data"], "no mentions");
+        }
+        assert_eq!(receive(&mut alice).await["status_code"], 200);
+
+        // Users outside the room don't see the message
+        send(&mut alice, "JoinRoom", "random").await;
+        let random_id = receive(&mut alice).await["body"]["rooms"][1]["id"].clone();
+        let in_random = json!({ "body": "a secret for @carol", "room_id": random_id });
+        send(&mut alice, "SendMessage", in_random).await;
+        let mention = receive(&mut carol).await;
+        assert_eq!(mention["method"], "Mentioned");
+        assert_eq!(mention["body"], json!({ "sender": "alice", "room": "random" }));
     }
-    Ok(())
 }
@@ -0,0 +1,55 @@
+use crate::config::Config;
+use crate::connections::ConnectionLimiter;
+use crate::db::Storage;
+use crate::rate_limit::{FailedLogins, RateLimiter};
+use crate::registry::Registry;
+use crate::uploads::Uploads;
+use crate::Result;
+use std::sync::Arc;
+
+const DEFAULT_ROOM: &str = "general";
+
+pub struct ServerState {
+    pub config: Config,
+    pub db: Box<dyn Storage>,
+    pub clients: Registry,
+    pub rate_limiter: RateLimiter,
+    pub transfer_limiter: RateLimiter,
+    pub failed_logins: FailedLogins,
+    pub connection_limiter: Arc<ConnectionLimiter>,
+    pub uploads: Uploads,
+    pub default_room_id: i64,
+}
+
+impl ServerState {
+    pub async fn new(config: Config, db: Box<dyn Storage>) -> Result<Self> {
+        let default_room_id = db.add_room(DEFAULT_ROOM).await?;
+        db.close_stale_sessions().await?;
+        Ok(Self {
+            rate_limiter: RateLimiter::new(
+                config.user_rate_limit.clone(),
+                config.ip_rate_limit.clone(),
+            ),
+            transfer_limiter: RateLimiter::new(
+                config.user_transfer_limit.clone(),
+                config.ip_transfer_limit.clone(),
+            ),
+            failed_logins: FailedLogins::new(config.max_failed_logins, config.failed_login_decay),
+            connection_limiter: Arc::new(ConnectionLimiter::new(
+                config.max_connections,
+                config.max_connections_per_ip,
+            )),
+            uploads: Uploads::new(
+                config.upload_dir.clone(),
+                config.max_upload_size,
+                config.max_upload_dir_size,
+                config.max_chunk_size,
+                config.max_uploads_per_client,
+            ),
+            clients: Registry::default(),
+            default_room_id,
+            config,
+            db,
+        })
+    }
+}
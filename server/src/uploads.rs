@@ -0,0 +1,437 @@
+use dashmap::DashMap;
+use log::info;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+// A file that is still being received, chunks are written to `<id>.part`
+struct Upload {
+    owner: SocketAddr,
+    name: String,
+    size: u64,
+    sha256: String,
+    received: u64,
+    hasher: Sha256,
+    file: File,
+}
+
+// A file that has been received completely and matches its checksum
+pub struct Blob {
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+#[derive(Debug)]
+pub enum UploadError {
+    TooLarge(u64),
+    ChunkTooLarge(usize),
+    TooManyUploads(usize),
+    InvalidFile,
+    NotFound,
+    Incomplete { size: u64, received: u64 },
+    ChecksumMismatch,
+    StorageFull(u64),
+    Io(io::Error),
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLarge(max) => write!(f, "the file is larger than {max} bytes"),
+            Self::ChunkTooLarge(max) => write!(f, "the chunk is larger than {max} bytes"),
+            Self::TooManyUploads(max) => write!(f, "more than {max} uploads at once"),
+            Self::InvalidFile => write!(f, "invalid file name or checksum"),
+            Self::NotFound => write!(f, "unknown upload"),
+            Self::Incomplete { size, received } => {
+                write!(f, "received {received} of {size} bytes")
+            }
+            Self::ChecksumMismatch => write!(f, "the checksum does not match"),
+            Self::StorageFull(max) => write!(f, "the stored files would exceed {max} bytes"),
+            Self::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<io::Error> for UploadError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl UploadError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            Self::TooLarge(_) | Self::ChunkTooLarge(_) => 413,
+            Self::TooManyUploads(_) => 429,
+            Self::InvalidFile | Self::Incomplete { .. } | Self::ChecksumMismatch => 400,
+            Self::NotFound => 404,
+            Self::StorageFull(_) => 507,
+            Self::Io(_) => 500,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::TooLarge(_) => "FileTooLarge",
+            Self::ChunkTooLarge(_) => "ChunkTooLarge",
+            Self::TooManyUploads(_) => "TooManyUploads",
+            Self::InvalidFile => "InvalidFile",
+            Self::NotFound => "UploadNotFound",
+            Self::Incomplete { .. } => "UploadIncomplete",
+            Self::ChecksumMismatch => "ChecksumMismatch",
+            Self::StorageFull(_) => "StorageFull",
+            Self::Io(_) => "UploadFailed",
+        }
+    }
+}
+
+// Files are received in chunks, so a large upload never has to fit into one line
+// of the protocol. Every upload belongs to the connection that started it
+pub struct Uploads {
+    dir: PathBuf,
+    max_size: u64,
+    max_total_size: u64,
+    max_chunk_size: usize,
+    max_per_client: usize,
+    next_id: AtomicI64,
+    in_progress: DashMap<i64, Upload>,
+    // Bytes of the stored files, and the full size of every upload in progress
+    used: AtomicU64,
+    // Held while a finished upload is moved to the stored files, so two uploads
+    // of the same file can't both count it as new
+    storing: Mutex<()>,
+}
+
+impl Uploads {
+    pub fn new(
+        dir: PathBuf,
+        max_size: u64,
+        max_total_size: u64,
+        max_chunk_size: usize,
+        max_per_client: usize,
+    ) -> Self {
+        let used = stored_size(&dir);
+        let next_id = next_part_id(&dir);
+        Self {
+            dir,
+            max_size,
+            max_total_size,
+            max_chunk_size,
+            max_per_client,
+            next_id: AtomicI64::new(next_id),
+            in_progress: DashMap::new(),
+            used: AtomicU64::new(used),
+            storing: Mutex::new(()),
+        }
+    }
+
+    pub async fn start(
+        &self,
+        owner: SocketAddr,
+        name: &str,
+        size: u64,
+        sha256: &str,
+    ) -> Result<i64, UploadError> {
+        if size > self.max_size {
+            return Err(UploadError::TooLarge(self.max_size));
+        }
+        // The name is only shown to other users, but it must not look like a path
+        let valid_name = !name.is_empty()
+            && name.len() <= 255
+            && !name.contains(['/', '\\'])
+            && !name.chars().any(char::is_control)
+            && name != "."
+            && name != "..";
+        let valid_checksum =
+            sha256.len() == 64 && sha256.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'));
+        if !valid_name || !valid_checksum {
+            return Err(UploadError::InvalidFile);
+        }
+        let uploads = self
+            .in_progress
+            .iter()
+            .filter(|upload| upload.owner == owner)
+            .count();
+        if uploads >= self.max_per_client {
+            return Err(UploadError::TooManyUploads(self.max_per_client));
+        }
+        self.reserve(size)?;
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let created = async {
+            fs::create_dir_all(&self.dir).await?;
+            File::create(self.part_path(id)).await
+        };
+        let file = match created.await {
+            Ok(file) => file,
+            Err(e) => {
+                self.release(size);
+                return Err(e.into());
+            }
+        };
+        self.in_progress.insert(
+            id,
+            Upload {
+                owner,
+                name: name.to_string(),
+                size,
+                sha256: sha256.to_string(),
+                received: 0,
+                hasher: Sha256::new(),
+                file,
+            },
+        );
+        Ok(id)
+    }
+
+    // Returns the number of bytes received so far, a chunk that doesn't fit
+    // into the announced size cancels the upload
+    pub async fn write_chunk(
+        &self,
+        owner: SocketAddr,
+        id: i64,
+        data: &[u8],
+    ) -> Result<u64, UploadError> {
+        if data.len() > self.max_chunk_size {
+            self.cancel(owner, id).await;
+            return Err(UploadError::ChunkTooLarge(self.max_chunk_size));
+        }
+        // The upload is taken out of the map, so no lock is held while writing.
+        // Any error ends it, it is not put back then
+        let (_, mut upload) = self
+            .in_progress
+            .remove_if(&id, |_, upload| upload.owner == owner)
+            .ok_or(UploadError::NotFound)?;
+        let written = async {
+            if upload.received + data.len() as u64 > upload.size {
+                return Err(UploadError::TooLarge(upload.size));
+            }
+            upload.file.write_all(data).await?;
+            Ok(())
+        };
+        if let Err(e) = written.await {
+            self.discard(id, upload.size).await;
+            return Err(e);
+        }
+        upload.hasher.update(data);
+        upload.received += data.len() as u64;
+        let received = upload.received;
+        self.in_progress.insert(id, upload);
+        Ok(received)
+    }
+
+    pub async fn finish(&self, owner: SocketAddr, id: i64) -> Result<Blob, UploadError> {
+        let (_, upload) = self
+            .in_progress
+            .remove_if(&id, |_, upload| upload.owner == owner)
+            .ok_or(UploadError::NotFound)?;
+        let size = upload.size;
+        let blob = self.store(id, upload).await;
+        if blob.is_err() {
+            self.discard(id, size).await;
+        }
+        blob
+    }
+
+    // Moves a complete upload that matches its checksum to the stored files
+    async fn store(&self, id: i64, mut upload: Upload) -> Result<Blob, UploadError> {
+        upload.file.flush().await?;
+        drop(upload.file);
+        if upload.received != upload.size {
+            return Err(UploadError::Incomplete {
+                size: upload.size,
+                received: upload.received,
+            });
+        }
+        if format!("{:x}", upload.hasher.finalize()) != upload.sha256 {
+            return Err(UploadError::ChecksumMismatch);
+        }
+        // Files with the same content are stored once, and counted once
+        let path = self.dir.join(&upload.sha256);
+        let storing = self.storing.lock().await;
+        let stored_before = fs::try_exists(&path).await?;
+        fs::rename(self.part_path(id), path).await?;
+        drop(storing);
+        if stored_before {
+            self.release(upload.size);
+        }
+        Ok(Blob {
+            name: upload.name,
+            size: upload.size,
+            sha256: upload.sha256,
+        })
+    }
+
+    pub async fn cancel(&self, owner: SocketAddr, id: i64) {
+        if let Some((_, upload)) = self
+            .in_progress
+            .remove_if(&id, |_, upload| upload.owner == owner)
+        {
+            self.discard(id, upload.size).await;
+        }
+    }
+
+    // Removes the `.part` file of an upload that has been taken out of the map
+    async fn discard(&self, id: i64, size: u64) {
+        self.release(size);
+        if let Err(e) = fs::remove_file(self.part_path(id)).await {
+            info!("Could not remove {}: {e}", self.part_path(id).display());
+        }
+    }
+
+    // Uploads that were not finished before the client disconnected
+    pub async fn cancel_all(&self, owner: SocketAddr) {
+        let ids: Vec<i64> = self
+            .in_progress
+            .iter()
+            .filter(|upload| upload.owner == owner)
+            .map(|upload| *upload.key())
+            .collect();
+        for id in ids {
+            self.cancel(owner, id).await;
+        }
+    }
+
+    // Reads at most one chunk of a stored file starting at `offset`
+    pub async fn read_chunk(&self, sha256: &str, offset: u64) -> io::Result<Vec<u8>> {
+        let mut file = File::open(self.dir.join(sha256)).await?;
+        file.seek(io::SeekFrom::Start(offset)).await?;
+        let mut data = Vec::with_capacity(self.max_chunk_size);
+        file.take(self.max_chunk_size as u64)
+            .read_to_end(&mut data)
+            .await?;
+        Ok(data)
+    }
+
+    fn part_path(&self, id: i64) -> PathBuf {
+        self.dir.join(format!("{id}.part"))
+    }
+
+    // The whole file is reserved when the upload starts, so uploads at the same
+    // time can't exceed the limit together
+    fn reserve(&self, size: u64) -> Result<(), UploadError> {
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(size)
+                    .filter(|total| *total <= self.max_total_size)
+            })
+            .map(|_| ())
+            .map_err(|_| UploadError::StorageFull(self.max_total_size))
+    }
+
+    fn release(&self, size: u64) {
+        self.used.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+// Leftover `.part` files of uploads that were interrupted by a restart are not counted
+fn stored_size(dir: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension().is_none())
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+// Ids after the `.part` files left over from a restart, so they are not reused
+fn next_part_id(dir: &Path) -> i64 {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return 1;
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "part")
+        })
+        .filter_map(|path| path.file_stem()?.to_str()?.parse::<i64>().ok())
+        .max()
+        .map_or(1, |id| id + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn part_files(dir: &Path) -> usize {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension().is_some())
+            .count()
+    }
+
+    #[tokio::test]
+    async fn failed_uploads_leave_no_part_files() {
+        let dir = std::env::temp_dir().join(format!("uploads-parts-{}", std::process::id()));
+        let uploads = Uploads::new(dir.clone(), 10, 20, 4, 2);
+        let owner = "127.0.0.1:1".parse().unwrap();
+        let sha256 = format!("{:x}", Sha256::digest("abcd"));
+
+        let id = uploads.start(owner, "a.txt", 2, &sha256).await.unwrap();
+        let result = uploads.write_chunk(owner, id, b"abc").await;
+        assert!(matches!(result, Err(UploadError::TooLarge(2))));
+        assert_eq!(part_files(&dir), 0);
+
+        let id = uploads.start(owner, "a.txt", 4, &sha256).await.unwrap();
+        uploads.write_chunk(owner, id, b"abce").await.unwrap();
+        let result = uploads.finish(owner, id).await;
+        assert!(matches!(result, Err(UploadError::ChecksumMismatch)));
+        assert_eq!(part_files(&dir), 0);
+
+        // Only the stored file still counts against the limit
+        let id = uploads.start(owner, "a.txt", 4, &sha256).await.unwrap();
+        uploads.write_chunk(owner, id, b"abcd").await.unwrap();
+        uploads.finish(owner, id).await.unwrap();
+        assert_eq!(uploads.used.load(Ordering::Relaxed), 4);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn the_same_file_is_counted_once() {
+        let dir = std::env::temp_dir().join(format!("uploads-same-{}", std::process::id()));
+        let uploads = Uploads::new(dir.clone(), 10, 20, 4, 2);
+        let sha256 = format!("{:x}", Sha256::digest("abcd"));
+        let owner = "127.0.0.1:1".parse().unwrap();
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let id = uploads.start(owner, "a.txt", 4, &sha256).await.unwrap();
+            uploads.write_chunk(owner, id, b"abcd").await.unwrap();
+            ids.push(id);
+        }
+
+        let (first, second) =
+            tokio::join!(uploads.finish(owner, ids[0]), uploads.finish(owner, ids[1]));
+        first.unwrap();
+        second.unwrap();
+        assert_eq!(uploads.used.load(Ordering::Relaxed), 4);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn ids_continue_after_leftover_part_files() {
+        let dir = std::env::temp_dir().join(format!("uploads-ids-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("7.part"), "ab").unwrap();
+        std::fs::write(dir.join("3.part"), "ab").unwrap();
+        let uploads = Uploads::new(dir.clone(), 10, 20, 4, 2);
+        let owner = "127.0.0.1:1".parse().unwrap();
+        let sha256 = format!("{:x}", Sha256::digest("abcd"));
+
+        assert_eq!(uploads.start(owner, "a.txt", 4, &sha256).await.unwrap(), 8);
+        assert_eq!(std::fs::read(dir.join("7.part")).unwrap(), b"ab");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}